@@ -0,0 +1,89 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use zf::Candidate;
+use zf::filter::{rank_candidates_ref, rank_candidates_with_threshold, score_candidates, CaseMode, Tiebreak};
+
+// counts every allocation so the cost of cloning candidates is visible
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+// a list of paths shaped roughly like a `git ls-files` of a large repo. `camel` names the
+// files like `FilterState` instead, which is where lowercasing while matching costs the most
+fn generate_paths(count: usize, camel: bool) -> Vec<Candidate> {
+    let dirs = ["src", "lib", "tests", "docs", "tools", "vendor"];
+    let names = if camel {
+        ["Main", "Filter", "Ui", "Config", "Parser", "Render", "State", "Index"]
+    } else {
+        ["main", "filter", "ui", "config", "parser", "render", "state", "index"]
+    };
+    let exts = ["rs", "md", "toml", "zig", "c"];
+
+    let mut content = String::new();
+    for i in 0..count {
+        content.push_str(&format!(
+            "{}/{}_{}/{}{}.{}\n",
+            dirs[i % dirs.len()], names[(i / 7) % names.len()], i % 101,
+            names[i % names.len()], i, exts[i % exts.len()],
+        ));
+    }
+    Candidate::collect(content.as_bytes(), b'\n', false, false).unwrap()
+}
+
+fn bench<F: FnMut()>(name: &str, iterations: u32, mut f: F) {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    let elapsed: Duration = start.elapsed();
+    let allocations = (ALLOCATIONS.load(Ordering::Relaxed) - allocations) / iterations as usize;
+    println!("{:<40} {:>10.3?}/iter {:>8} allocs/iter", name, elapsed / iterations, allocations);
+}
+
+fn main() {
+    for count in [1_000, 10_000, 100_000] {
+        let candidates = generate_paths(count, false);
+        for query in ["main", "src ui", "fltr rs"] {
+            bench(&format!("sequential {:>6} {:?}", count, query), 10, || {
+                rank_candidates_with_threshold(candidates.clone(), query, false, Tiebreak::Length, CaseMode::Smart, None, usize::MAX);
+            });
+            bench(&format!("parallel   {:>6} {:?}", count, query), 10, || {
+                rank_candidates_with_threshold(candidates.clone(), query, false, Tiebreak::Length, CaseMode::Smart, None, 0);
+            });
+            bench(&format!("borrowed   {:>6} {:?}", count, query), 10, || {
+                score_candidates(&candidates, query, false, Tiebreak::Length, CaseMode::Smart, None);
+            });
+            bench(&format!("indices    {:>6} {:?}", count, query), 10, || {
+                rank_candidates_ref(&candidates, query, false);
+            });
+        }
+
+        // every prefix of a query typed into the interactive search
+        let query = "filter rs";
+        let camel = generate_paths(count, true);
+        for (kind, candidates) in [("keystrokes", &candidates), ("camelcase ", &camel)] {
+            bench(&format!("{} {:>6} {:?}", kind, count, query), 10, || {
+                for end in 1..=query.len() {
+                    score_candidates(candidates, &query[..end], false, Tiebreak::Length, CaseMode::Smart, None);
+                }
+            });
+        }
+    }
+}
@@ -1,135 +1,872 @@
-use std::{path::{PathBuf, Path}, io::BufRead, ascii::AsciiExt};
+use std::{path::Path, io::{self, BufRead}};
 use std::cmp::Ordering;
+use std::ops;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Candidate {
     pub path: String,
     pub name: Option<String>,
+    /// the byte of `text()` where `name` starts. the name doesn't always end the text, like
+    /// for `src/` or `docs/.`, when `None` it is expected to end the matched field
+    pub name_start: Option<usize>,
+    /// the text shown in place of `path`, which is still what gets printed
+    pub display: Option<String>,
+    /// the bytes of `text()` that are matched against, all of it when `None`
+    pub field: Option<ops::Range<usize>>,
     pub rank: f64,
     pub ranges: Vec<Range>,
+    /// the bytes of each field of `path`, only recorded by `collect_fields`
+    pub fields: Vec<Range>,
+    /// the colors `path` came with before `with_ansi` took them out, in order
+    pub colors: Vec<Sgr>,
+    // `text()` lowercased once up front for the tokens that ignore case. only kept for ascii
+    // text with uppercase letters, and set again whenever the text changes
+    pub(crate) lower: Option<String>,
 }
 
+/// the fields of each record that are matched, counting from 1. the rest of the
+/// record is still shown and printed
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Nth {
+    pub first: usize,
+    /// the last field, inclusive. `None` goes on to the end of the record
+    pub last: Option<usize>,
+}
+
+impl Nth {
+    /// the bytes of `record` from the start of the first field up to the end of the last one.
+    /// fields are split at `delimiter`, or at runs of whitespace when `None`
+    pub fn span(&self, record: &str, delimiter: Option<char>) -> Option<ops::Range<usize>> {
+        self.span_in(&field_spans(record, delimiter))
+    }
+
+    /// same as `span`, with the record already split into `fields`
+    pub fn span_in(&self, fields: &[Range]) -> Option<ops::Range<usize>> {
+        let first = fields.get(self.first.checked_sub(1)?)?;
+        let last = match self.last {
+            Some(last) => fields.get(last.checked_sub(1)?.min(fields.len() - 1))?,
+            None => fields.last()?,
+        };
+        if last.end < first.start { return None; }
+        Some(first.start..last.end)
+    }
+}
+
+/// the bytes of each field of `record` split at `delimiter`, or at runs of whitespace
+/// when `None`. whitespace around the fields is left out
+pub fn field_spans(record: &str, delimiter: Option<char>) -> Vec<Range> {
+    match delimiter {
+        Some(delimiter) => {
+            let mut start = 0;
+            let mut fields = Vec::from_iter(record.match_indices(delimiter).map(|(index, _)| {
+                let field = Range { start, end: index };
+                start = index + delimiter.len_utf8();
+                field
+            }));
+            fields.push(Range { start, end: record.len() });
+            fields
+        },
+        None => Vec::from_iter(record.split_whitespace().map(|field| {
+            let start = field.as_ptr() as usize - record.as_ptr() as usize;
+            Range { start, end: start + field.len() }
+        })),
+    }
+}
+
+/// the bytes `start..end` of a highlighted match. like `ops::Range` the end is exclusive,
+/// so an empty range has `start == end`
 #[derive(Debug, Clone, PartialEq, Default)]
 pub struct Range {
     pub start: usize,
     pub end: usize
 }
 
+impl Range {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+}
+
+impl From<ops::Range<usize>> for Range {
+    fn from(range: ops::Range<usize>) -> Self {
+        Self { start: range.start, end: range.end }
+    }
+}
+
+impl From<Range> for ops::Range<usize> {
+    fn from(range: Range) -> Self {
+        range.start..range.end
+    }
+}
+
+/// an SGR escape sequence taken out of a text, applying from the byte `at` of what is left
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sgr {
+    pub at: usize,
+    /// the parameters between `ESC [` and `m`, like `1;31`
+    pub params: String,
+}
+
+impl Sgr {
+    /// whether this turns every attribute off, like `ESC [ m` and `ESC [ 0 m` do
+    pub fn is_reset(&self) -> bool {
+        self.params.is_empty() || self.params == "0"
+    }
+}
+
+/// `text` without its CSI escape sequences, and the colors among them. the other
+/// sequences, like erasing the line, are dropped
+pub fn strip_ansi(text: &str) -> (String, Vec<Sgr>) {
+    let mut stripped = String::with_capacity(text.len());
+    let mut colors = vec![];
+    let mut rest = text;
+    while let Some(start) = rest.find("\x1b[") {
+        stripped.push_str(&rest[..start]);
+        let sequence = &rest[start + 2..];
+        // parameter and intermediate bytes, then the final byte
+        let Some(end) = sequence.find(|c: char| ('\x40'..='\x7e').contains(&c)) else {
+            // cut off at the end of the record
+            rest = "";
+            break;
+        };
+        if sequence[end..].starts_with('m') {
+            colors.push(Sgr { at: stripped.len(), params: sequence[..end].to_owned() });
+        }
+        rest = &sequence[end + 1..];
+    }
+    stripped.push_str(rest);
+    (stripped, colors)
+}
+
+/// sort `ranges` and join the ones that overlap or touch, leaving ascending ranges
+/// with gaps between them
+pub fn merge_ranges(ranges: &mut Vec<Range>) {
+    ranges.sort_by_key(|r| r.start);
+    ranges.dedup_by(|next, prev| {
+        if next.start > prev.end { return false; }
+        prev.end = prev.end.max(next.end);
+        true
+    });
+}
+
 impl Candidate {
-    pub fn collect<S: BufRead>(content: S, delimiter: u8, plain: bool) -> Vec<Self> {
-        Vec::from_iter(content.split(delimiter).filter_map(|item| {
-            if item.as_ref().unwrap().len() == 0 { return None; }
-            let path = String::from_utf8(item.unwrap()).expect("not utf8");
-            let name = if !plain {
-                Path::new(&path).file_name().map(|s| s.to_string_lossy().into_owned())
-            } else {
-                None
+    /// split `content` by `delimiter` into candidates. records that are not valid utf8 are
+    /// decoded lossily when `lossy` is set, otherwise they are skipped. split at newlines,
+    /// a `\r` ending a record is left out.
+    pub fn collect<S: BufRead>(content: S, delimiter: u8, plain: bool, lossy: bool) -> io::Result<Vec<Self>> {
+        Self::stream(content, delimiter, plain, lossy).collect()
+    }
+
+    /// same as `collect`, also recording where each field of a record starts and ends,
+    /// see `field_spans`
+    pub fn collect_fields<S: BufRead>(content: S, delimiter: u8, field_delimiter: Option<char>, plain: bool, lossy: bool) -> io::Result<Vec<Self>> {
+        Self::stream(content, delimiter, plain, lossy).map(|candidate| {
+            let mut candidate = candidate?;
+            candidate.fields = field_spans(&candidate.path, field_delimiter);
+            Ok(candidate)
+        }).collect()
+    }
+
+    /// same as `collect`, but yields each candidate as soon as its record is read
+    pub fn stream<S: BufRead>(content: S, delimiter: u8, plain: bool, lossy: bool) -> impl Iterator<Item = io::Result<Self>> {
+        content.split(delimiter).filter_map(move |item| {
+            let mut item = match item {
+                Ok(item) => item,
+                Err(e) => return Some(Err(e)),
             };
-            Some(Self {
-                path,
-                name,
-                rank: 0.0,
-                ranges: vec![],
-            })
+            // lines ended by \r\n, only the one right before the \n
+            if delimiter == b'\n' && item.last() == Some(&b'\r') {
+                item.pop();
+            }
+            if item.is_empty() { return None; }
+            let path = match String::from_utf8(item) {
+                Ok(path) => path,
+                Err(e) if lossy => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+                Err(_) => return None,
+            };
+            Some(Ok(Self::from_path(path, plain)))
+        })
+    }
+
+    /// same as `collect`, for lines already in memory. empty lines are skipped as well
+    pub fn from_lines<I: IntoIterator<Item = String>>(lines: I, plain: bool) -> Vec<Self> {
+        Vec::from_iter(lines.into_iter().filter(|line| !line.is_empty()).map(|line| Self::from_path(line, plain)))
+    }
+
+    // a candidate for one record, matching its file name first unless `plain` is set
+    fn from_path(path: String, plain: bool) -> Self {
+        let (name, name_start) = file_name(&path, plain);
+        let lower = lowercase_copy(&path);
+        Self {
+            path,
+            name,
+            name_start,
+            display: None,
+            field: None,
+            rank: 0.0,
+            ranges: vec![],
+            fields: vec![],
+            colors: vec![],
+            lower,
+        }
+    }
 
-        }))
+    /// the text that is shown, matched and highlighted
+    pub fn text(&self) -> &str {
+        self.display.as_deref().unwrap_or(&self.path)
     }
+
+    /// match only the `nth` fields split at `delimiter`, see `Nth::span`. a record without
+    /// those fields matches nothing but the empty query
+    pub fn with_fields(self, nth: Nth, delimiter: Option<char>, plain: bool) -> Self {
+        let text = self.text();
+        let field = nth.span(text, delimiter).unwrap_or(text.len()..text.len());
+        let (name, name_start) = file_name(&text[field.clone()], plain);
+        let name_start = name_start.map(|start| field.start + start);
+        Self { name, name_start, field: Some(field), ..self }
+    }
+
+    /// match only the file name, so directories never match or get highlighted. nothing
+    /// changes without a `name`, with `plain` or for paths without a file name
+    pub fn with_filename_only(self) -> Self {
+        let Some(span) = self.name_span() else { return self };
+        Self { field: Some(span), ..self }
+    }
+
+    // the bytes of `text()` holding `name`, inside the matched field
+    fn name_span(&self) -> Option<ops::Range<usize>> {
+        let name = self.name.as_deref()?;
+        let text = self.text();
+        let field = self.field.clone().unwrap_or(0..text.len());
+        let start = match self.name_start {
+            Some(start) => start,
+            None => field.end.checked_sub(name.len())?,
+        };
+        let span = start..start + name.len();
+        (span.start >= field.start && span.end <= field.end && text.get(span.clone()) == Some(name)).then_some(span)
+    }
+
+    /// take the escape sequences out of `path` so they are neither matched nor counted when
+    /// drawing, keeping its colors to draw it with. `with_display` drops them
+    pub fn with_ansi(self, plain: bool) -> Self {
+        let (path, colors) = strip_ansi(&self.path);
+        let (name, name_start) = file_name(&path, plain);
+        let lower = lowercase_copy(&path);
+        Self { path, name, name_start, colors, lower, ..self }
+    }
+
+    /// show the `fields` of the record in the given order instead, joined by `delimiter` or a
+    /// space. they are also what gets matched, `with_fields` then picks among the shown fields
+    pub fn with_display(self, fields: &[Nth], delimiter: Option<char>, plain: bool) -> Self {
+        let separator = delimiter.unwrap_or(' ').to_string();
+        let display = Vec::from_iter(fields.iter().filter_map(|nth| Some(&self.path[nth.span(&self.path, delimiter)?])))
+            .join(&separator);
+        let (name, name_start) = file_name(&display, plain);
+        let lower = lowercase_copy(&display);
+        // they apply to `path`, not to the fields picked out of it
+        Self { name, name_start, display: Some(display), lower, colors: vec![], ..self }
+    }
+}
+
+// the file name of `text` unless `plain` is set, and where it starts. as the last component
+// only slashes and `.` components can follow it
+fn file_name(text: &str, plain: bool) -> (Option<String>, Option<usize>) {
+    if plain { return (None, None); }
+    let Some(name) = Path::new(text).file_name() else { return (None, None); };
+    let name = name.to_string_lossy().into_owned();
+    let start = text.rfind(&name);
+    (Some(name), start)
+}
+
+// the ascii `text` lowercased, `None` when that changes nothing or the text isn't ascii
+fn lowercase_copy(text: &str) -> Option<String> {
+    (text.is_ascii() && text.bytes().any(|b| b.is_ascii_uppercase())).then(|| text.to_ascii_lowercase())
 }
 
 #[inline]
 fn has_upper(query: &str) -> bool {
-    query.chars().any(|c| c.is_ascii_uppercase())
+    query.chars().any(char::is_uppercase)
 }
 
+/// tokens starting with this are matched as an exact substring instead of fuzzily
+pub const EXACT_PREFIX: char = '\'';
+
+/// tokens starting with this reject every candidate containing the rest of the token
+pub const NEGATE_PREFIX: char = '!';
+
+/// the space separated tokens of each line of `query`, all of which have to match. runs of
+/// spaces and empty lines don't produce empty tokens
 #[inline]
 pub fn split_query(query: &str) -> Vec<&str> {
-    query.split(" ").collect()
+    query.split([' ', '\n', '\r']).filter(|token| !token.is_empty()).collect()
+}
+
+/// inputs with at least this many candidates are ranked across threads
+pub const PARALLEL_THRESHOLD: usize = 2000;
+
+/// how candidates with an equal rank are ordered
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum Tiebreak {
+    /// shorter paths first
+    #[default]
+    Length,
+    /// earlier matches first
+    Begin,
+    /// the order the candidates were read in
+    Index,
+}
+
+/// how the case of the query tokens is matched
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum CaseMode {
+    /// case insensitive, unless the token has an uppercase character
+    #[default]
+    Smart,
+    /// always case insensitive
+    Ignore,
+    /// always case sensitive
+    Respect,
+}
+
+impl CaseMode {
+    // smart case is decided for each token on its own, into one of the other modes
+    fn resolve(self, token: &str) -> CaseMode {
+        match self {
+            CaseMode::Smart if has_upper(token) => CaseMode::Respect,
+            CaseMode::Smart => CaseMode::Ignore,
+            case => case,
+        }
+    }
+}
+
+/// the rank and highlight ranges of the candidate at `index` in the ranked slice
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scored {
+    pub index: usize,
+    pub rank: f64,
+    pub ranges: Vec<Range>,
 }
 
+/// the candidates that match `query`, best first unless `keep_order` is set. with
+/// `max_results` only that many are returned, without sorting the rest
 pub fn rank_candidates(
     candidates: Vec<Candidate>,
     query: &str,
     keep_order: bool,
+    tiebreak: Tiebreak,
+    case: CaseMode,
+    max_results: Option<usize>,
 ) -> Vec<Candidate> {
-    let smart_case = !has_upper(query);
-    let mut ranked = vec![];
-    if query.len() > 0 {
-        let query_tokens = split_query(query);
-        for mut candidate in candidates.into_iter() {
-            candidate.ranges = vec![Default::default(); query_tokens.len()];
-            if rank_candidate(&mut candidate, &query_tokens, smart_case) {
-                ranked.push(candidate);
-            }
-        }
+    rank_candidates_with_threshold(candidates, query, keep_order, tiebreak, case, max_results, PARALLEL_THRESHOLD)
+}
+
+/// same as `rank_candidates`, but the candidates are scored in parallel once there are
+/// `parallel_threshold` or more of them. without the `rayon` feature this is always sequential
+pub fn rank_candidates_with_threshold(
+    candidates: Vec<Candidate>,
+    query: &str,
+    keep_order: bool,
+    tiebreak: Tiebreak,
+    case: CaseMode,
+    max_results: Option<usize>,
+    parallel_threshold: usize,
+) -> Vec<Candidate> {
+    let matcher = Matcher::with_case(query, case);
+    rank_with_threshold(candidates, &matcher, keep_order, tiebreak, max_results, parallel_threshold)
+}
+
+/// same as `rank_candidates`, against a query already parsed into `matcher`
+pub fn rank_candidates_with(
+    candidates: Vec<Candidate>,
+    matcher: &Matcher,
+    keep_order: bool,
+    tiebreak: Tiebreak,
+    max_results: Option<usize>,
+) -> Vec<Candidate> {
+    rank_with_threshold(candidates, matcher, keep_order, tiebreak, max_results, PARALLEL_THRESHOLD)
+}
+
+fn rank_with_threshold(
+    candidates: Vec<Candidate>,
+    matcher: &Matcher,
+    keep_order: bool,
+    tiebreak: Tiebreak,
+    max_results: Option<usize>,
+    parallel_threshold: usize,
+) -> Vec<Candidate> {
+    let scored = score_with_threshold(&candidates, matcher, keep_order, tiebreak, max_results, parallel_threshold);
+
+    let mut candidates = Vec::from_iter(candidates.into_iter().map(Some));
+    Vec::from_iter(scored.into_iter().map(|scored| {
+        let mut candidate = candidates[scored.index].take().expect("candidate ranked twice");
+        candidate.rank = scored.rank;
+        candidate.ranges = scored.ranges;
+        candidate
+    }))
+}
+
+/// same as `rank_candidates`, but borrows the candidates and returns the matches by index
+/// instead of cloning them
+pub fn score_candidates(
+    candidates: &[Candidate],
+    query: &str,
+    keep_order: bool,
+    tiebreak: Tiebreak,
+    case: CaseMode,
+    max_results: Option<usize>,
+) -> Vec<Scored> {
+    score_candidates_with(candidates, &Matcher::with_case(query, case), keep_order, tiebreak, max_results)
+}
+
+/// the indices of the `candidates` that match `query`, best first unless `keep_order` is
+/// set, with the default tiebreak and smart case. nothing is cloned, which suits benchmarks
+pub fn rank_candidates_ref(candidates: &[Candidate], query: &str, keep_order: bool) -> Vec<usize> {
+    let scored = score_candidates(candidates, query, keep_order, Tiebreak::default(), CaseMode::default(), None);
+    Vec::from_iter(scored.into_iter().map(|scored| scored.index))
+}
+
+/// same as `score_candidates`, against a query already parsed into `matcher`
+pub fn score_candidates_with(
+    candidates: &[Candidate],
+    matcher: &Matcher,
+    keep_order: bool,
+    tiebreak: Tiebreak,
+    max_results: Option<usize>,
+) -> Vec<Scored> {
+    score_with_threshold(candidates, matcher, keep_order, tiebreak, max_results, PARALLEL_THRESHOLD)
+}
+
+fn score_with_threshold(
+    candidates: &[Candidate],
+    matcher: &Matcher,
+    keep_order: bool,
+    tiebreak: Tiebreak,
+    max_results: Option<usize>,
+    parallel_threshold: usize,
+) -> Vec<Scored> {
+    // without a query nothing is filtered out, and there is no rank to sort by
+    if matcher.is_empty() {
+        let len = max_results.map_or(candidates.len(), |max| max.min(candidates.len()));
+        return Vec::from_iter((0..len).map(|index| Scored { index, rank: 0.0, ranges: vec![] }));
     }
 
-    if !keep_order {
-        ranked.sort_by(|a, b| {
-            let o = a.rank.partial_cmp(&b.rank).unwrap_or(Ordering::Equal);
-            if !o.is_eq() { return o; }
+    let rank = |(index, candidate): (usize, &Candidate)| {
+        let mut ranges = vec![];
+        let rank = matcher.score_candidate(candidate, &mut ranges)?;
+        Some(Scored { index, rank, ranges })
+    };
 
-            let o = a.path.len().cmp(&b.path.len());
-            if !o.is_eq() { return o; }
+    // collecting in parallel still keeps the input order, which `keep_order` relies on
+    #[cfg(feature = "rayon")]
+    let mut ranked: Vec<Scored> = if candidates.len() >= parallel_threshold {
+        use rayon::prelude::*;
+        candidates.par_iter().enumerate().filter_map(rank).collect()
+    } else {
+        candidates.iter().enumerate().filter_map(rank).collect()
+    };
 
-            let o = a.path.cmp(&b.path);
-            o
-        });
+    #[cfg(not(feature = "rayon"))]
+    let mut ranked: Vec<Scored> = {
+        let _ = parallel_threshold;
+        candidates.iter().enumerate().filter_map(rank).collect()
+    };
+
+    if !keep_order {
+        sort_scored(&mut ranked, candidates, tiebreak, max_results);
+    } else if let Some(max) = max_results {
+        ranked.truncate(max);
     }
 
     ranked
 }
 
-fn rank_candidate(candidate: &mut Candidate, query_tokens: &[&str], smart_case: bool) -> bool {
-    candidate.rank = 0.0;
-    for (token, range) in query_tokens.into_iter().zip(candidate.ranges.iter_mut()) {
-        if let Some(r) = rank_token(
-            candidate.path.as_bytes(),
-            candidate.name.as_ref().map(|n| n.as_bytes()),
-            range,
-            token.as_bytes(),
-            smart_case
-        ) {
-            candidate.rank += r;
+/// score `candidates[start..]` against `query` and merge them into `scored`, which holds
+/// the scores of `candidates[..start]` for the same query. for candidates that keep coming in
+pub fn score_more(
+    scored: &mut Vec<Scored>,
+    candidates: &[Candidate],
+    start: usize,
+    query: &str,
+    keep_order: bool,
+    tiebreak: Tiebreak,
+    case: CaseMode,
+) {
+    score_more_with(scored, candidates, start, &Matcher::with_case(query, case), keep_order, tiebreak);
+}
+
+/// same as `score_more`, against a query already parsed into `matcher`
+pub fn score_more_with(
+    scored: &mut Vec<Scored>,
+    candidates: &[Candidate],
+    start: usize,
+    matcher: &Matcher,
+    keep_order: bool,
+    tiebreak: Tiebreak,
+) {
+    let mut more = score_candidates_with(&candidates[start..], matcher, true, tiebreak, None);
+    for scored in more.iter_mut() {
+        scored.index += start;
+    }
+    scored.append(&mut more);
+
+    // an empty query keeps the input order
+    if !keep_order && !matcher.is_empty() {
+        sort_scored(scored, candidates, tiebreak, None);
+    }
+}
+
+/// whether every candidate matching `query` also matches `old`, so ranking for `query` only
+/// has to look at the matches of `old`. true when `query` adds to the end of `old`, unless it
+/// turns a lone `'` into an exact token or has negated tokens, which match more as they grow
+pub fn narrows(old: &str, query: &str) -> bool {
+    query.starts_with(old)
+        && !query.contains(NEGATE_PREFIX)
+        && split_query(old).last().is_none_or(|token| *token != EXACT_PREFIX.to_string())
+}
+
+/// rank again the candidates in `scored` against `matcher`, which `narrows` the query they
+/// were scored for. the same as scoring all the candidates, as long as none were left out
+pub fn rescore(scored: &[Scored], candidates: &[Candidate], matcher: &Matcher, keep_order: bool, tiebreak: Tiebreak) -> Vec<Scored> {
+    let mut rescored = Vec::from_iter(scored.iter().filter_map(|scored| {
+        let mut ranges = vec![];
+        let rank = matcher.score_candidate(&candidates[scored.index], &mut ranges)?;
+        Some(Scored { index: scored.index, rank, ranges })
+    }));
+    if keep_order || matcher.is_empty() {
+        // already in the input order
+        return rescored;
+    }
+    sort_scored(&mut rescored, candidates, tiebreak, None);
+    rescored
+}
+
+// with `max_results` only the best ones are kept, and only those get sorted
+fn sort_scored(scored: &mut Vec<Scored>, candidates: &[Candidate], tiebreak: Tiebreak, max_results: Option<usize>) {
+    // where the earliest highlighted match starts
+    let begin = |scored: &Scored| scored.ranges.iter().map(|r| r.start).min();
+
+    let compare = |a: &Scored, b: &Scored| {
+        let o = a.rank.partial_cmp(&b.rank).unwrap_or(Ordering::Equal);
+        if !o.is_eq() { return o; }
+
+        let o = match tiebreak {
+            Tiebreak::Length => Ordering::Equal,
+            Tiebreak::Begin => begin(a).cmp(&begin(b)),
+            Tiebreak::Index => a.index.cmp(&b.index),
+        };
+        if !o.is_eq() { return o; }
+
+        let (ca, cb) = (&candidates[a.index], &candidates[b.index]);
+        let o = ca.text().len().cmp(&cb.text().len());
+        if !o.is_eq() { return o; }
+
+        // the input order last, so picking the best few agrees with sorting them all
+        ca.text().cmp(cb.text()).then(a.index.cmp(&b.index))
+    };
+
+    match max_results {
+        Some(max) if max < scored.len() => {
+            if max > 0 {
+                scored.select_nth_unstable_by(max - 1, compare);
+            }
+            scored.truncate(max);
+            scored.sort_unstable_by(compare);
+        },
+        _ => scored.sort_by(compare),
+    }
+}
+
+/// score a single path against `query` without building or sorting a candidate list.
+/// `name` is the filename part of `path` that gets matched with precedence, if any. it has
+/// to end `path`, a `name` that doesn't is ignored.
+///
+/// returns the rank and the highlight ranges of the matched tokens, or `None` if the
+/// path doesn't match. lower ranks are better matches. `case` decides how the case of
+/// each token is matched
+pub fn score(path: &str, name: Option<&str>, query: &str, case: CaseMode) -> Option<(f64, Vec<Range>)> {
+    let mut ranges = vec![];
+    let name = name.filter(|name| path.ends_with(name)).map(|name| path.len() - name.len()..path.len());
+    let rank = Matcher::with_case(query, case).score(path, name, None, &mut ranges)?;
+    Some((rank, ranges))
+}
+
+/// a query parsed once up front, to rank any number of candidates against it
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    tokens: Vec<Token>,
+    ascii: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TokenKind {
+    Fuzzy,
+    Exact,
+    Negated,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    text: String,
+    // the text decoded ahead of time for matching non-ascii paths
+    chars: Vec<char>,
+    // never `Smart`, that is resolved when parsing. ignored case has the text lowercased
+    case: CaseMode,
+}
+
+impl Token {
+    fn new(kind: TokenKind, text: &str, case: CaseMode) -> Self {
+        let case = case.resolve(text);
+        let text = match case {
+            CaseMode::Ignore => String::from_iter(text.chars().map(Unit::to_lower)),
+            _ => text.to_string(),
+        };
+        Token { kind, chars: text.chars().collect(), text, case }
+    }
+}
+
+impl Matcher {
+    pub fn new(query: &str) -> Self {
+        Self::with_case(query, CaseMode::Smart)
+    }
+
+    pub fn with_case(query: &str, case: CaseMode) -> Self {
+        // a query of whitespace alone has no tokens, even for whitespace `split_query` keeps
+        if query.trim().is_empty() { return Self::from_tokens(vec![]); }
+
+        Self::from_tokens(Vec::from_iter(split_query(query).into_iter().map(|token| {
+            if let Some(negated) = token.strip_prefix(NEGATE_PREFIX).filter(|t| !t.is_empty()) {
+                Token::new(TokenKind::Negated, negated, case)
+            } else if let Some(exact) = token.strip_prefix(EXACT_PREFIX).filter(|t| !t.is_empty()) {
+                Token::new(TokenKind::Exact, exact, case)
+            } else {
+                Token::new(TokenKind::Fuzzy, token, case)
+            }
+        })))
+    }
+
+    /// match the whole `query` as one fuzzy token, spaces and prefixes included. a query of
+    /// whitespace alone still matches everything
+    pub fn literal(query: &str, case: CaseMode) -> Self {
+        if query.trim().is_empty() { return Self::from_tokens(vec![]); }
+        Self::from_tokens(vec![Token::new(TokenKind::Fuzzy, query, case)])
+    }
+
+    fn from_tokens(tokens: Vec<Token>) -> Self {
+        let ascii = tokens.iter().all(|token| token.text.is_ascii());
+        Self { tokens, ascii }
+    }
+
+    /// whether there is nothing to match, so every candidate matches in the input order
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// rank `candidate` and set its rank and highlight ranges. returns false and leaves
+    /// the candidate untouched if it doesn't match
+    pub fn rank(&self, candidate: &mut Candidate) -> bool {
+        let mut ranges = vec![];
+        match self.score_candidate(candidate, &mut ranges) {
+            Some(rank) => {
+                candidate.rank = rank;
+                candidate.ranges = ranges;
+                true
+            },
+            None => false,
+        }
+    }
+
+    // ranks the field of `candidate` that is matched, with the ranges still indexing the whole text
+    fn score_candidate(&self, candidate: &Candidate, ranges: &mut Vec<Range>) -> Option<f64> {
+        let lower = candidate.lower.as_deref();
+        let name = candidate.name_span();
+        let Some(field) = candidate.field.clone() else {
+            return self.score(candidate.text(), name, lower, ranges);
+        };
+
+        let lower = lower.map(|lower| &lower[field.clone()]);
+        let name = name.map(|name| name.start - field.start..name.end - field.start);
+        let rank = self.score(&candidate.text()[field.clone()], name, lower, ranges)?;
+        for range in ranges.iter_mut() {
+            range.start += field.start;
+            range.end += field.start;
+        }
+        Some(rank)
+    }
+
+    // ranks `path` in bytes when it and the query are ascii, and in chars otherwise so
+    // case folding and match distances work on whole characters. `lower` is the ascii `path`
+    // lowercased ahead of time, it is lowercased here when missing. `name` is the bytes of
+    // `path` holding the file name
+    fn score(&self, path: &str, name: Option<ops::Range<usize>>, lower: Option<&str>, ranges: &mut Vec<Range>) -> Option<f64> {
+        let ignore_case = self.tokens.iter().any(|token| token.case == CaseMode::Ignore);
+
+        if self.ascii && path.is_ascii() {
+            let lowered;
+            let lower = match lower {
+                Some(lower) => lower,
+                None if ignore_case && path.bytes().any(|b| b.is_ascii_uppercase()) => {
+                    lowered = path.to_ascii_lowercase();
+                    &lowered
+                },
+                None => path,
+            };
+            return rank_tokens(path.as_bytes(), lower.as_bytes(), name, &self.tokens, ranges);
+        }
+
+        let chars = Vec::from_iter(path.chars());
+        let lowered;
+        let lower = if ignore_case {
+            lowered = Vec::from_iter(chars.iter().map(|&c| c.to_lower()));
+            &lowered
         } else {
-            return false;
+            &chars
+        };
+        let name = name.map(|name| {
+            let start = path[..name.start].chars().count();
+            start..start + path[name].chars().count()
+        });
+        let rank = rank_tokens(&chars, lower, name, &self.tokens, ranges)?;
+
+        // the ranges index chars, highlighting needs them in bytes
+        let offsets = Vec::from_iter(path.char_indices().map(|(offset, _)| offset));
+        for range in ranges.iter_mut() {
+            range.start = offsets[range.start];
+            range.end = offsets.get(range.end).copied().unwrap_or(path.len());
         }
+
+        Some(rank)
     }
+}
 
-    true
+/// the units a path is matched in: bytes for ascii input and chars otherwise
+trait Unit: Copy + Eq {
+    fn token(token: &Token) -> &[Self];
+    fn to_lower(self) -> Self;
+    fn is_start_of_word(self) -> bool;
+    fn is_lower(self) -> bool;
+    fn is_upper(self) -> bool;
 }
 
-fn index_of(slice: &[u8], start_index: usize, value: u8) -> Option<usize> {
-    let shift = slice.into_iter().skip(start_index).position(|&ch| (ch as char).to_ascii_lowercase() == (value as char))?;
-    Some(start_index + shift)
+impl Unit for u8 {
+    fn token(token: &Token) -> &[Self] {
+        token.text.as_bytes()
+    }
+
+    fn to_lower(self) -> Self {
+        self.to_ascii_lowercase()
+    }
+
+    fn is_start_of_word(self) -> bool {
+        is_start_of_word(self)
+    }
+
+    fn is_lower(self) -> bool {
+        self.is_ascii_lowercase()
+    }
+
+    fn is_upper(self) -> bool {
+        self.is_ascii_uppercase()
+    }
+}
+
+impl Unit for char {
+    fn token(token: &Token) -> &[Self] {
+        &token.chars
+    }
+
+    // characters lowercasing to several chars are compared by the first one
+    fn to_lower(self) -> Self {
+        self.to_lowercase().next().unwrap_or(self)
+    }
+
+    fn is_start_of_word(self) -> bool {
+        self.is_ascii() && is_start_of_word(self as u8)
+    }
+
+    fn is_lower(self) -> bool {
+        self.is_ascii_lowercase()
+    }
+
+    fn is_upper(self) -> bool {
+        self.is_ascii_uppercase()
+    }
+}
+
+/// a path or filename, along with the same units lowercased for the tokens that ignore case
+#[derive(Clone, Copy)]
+struct Haystack<'a, T> {
+    units: &'a [T],
+    lower: &'a [T],
+    // where the units start in the path
+    start: usize,
+}
+
+impl<'a, T: Unit> Haystack<'a, T> {
+    // the units a token matched with `case` is found in. tokens ignoring case are lowercased too
+    fn search(&self, case: CaseMode) -> &'a [T] {
+        if case == CaseMode::Ignore { self.lower } else { self.units }
+    }
+
+    fn len(&self) -> usize {
+        self.units.len()
+    }
 }
 
-fn index_of_case_sensitive(slice: &[u8], start_index: usize, value: u8) -> Option<usize> {
-    let shift = slice.into_iter().skip(start_index).position(|&ch| ch == value)?;
+// `lower` is `path` with every unit lowercased. `name` is the units of `path` holding the file name
+fn rank_tokens<T: Unit>(path: &[T], lower: &[T], name: Option<ops::Range<usize>>, tokens: &[Token], ranges: &mut Vec<Range>) -> Option<f64> {
+    let name = name.map(|name| Haystack { units: &path[name.clone()], lower: &lower[name.clone()], start: name.start });
+    let path = Haystack { units: path, lower, start: 0 };
+
+    let mut rank = 0.0;
+    ranges.clear();
+    for token in tokens.iter() {
+        let text = T::token(token);
+        let mut range = Range::default();
+        rank += match token.kind {
+            // negated tokens only filter, they are neither ranked nor highlighted
+            TokenKind::Negated => {
+                if scan_exact(path, text, token.case).is_some() {
+                    return None;
+                }
+                continue;
+            },
+            TokenKind::Exact => rank_exact(path, name, &mut range, text, token.case),
+            TokenKind::Fuzzy => rank_token(path, name, &mut range, text, token.case),
+        }?;
+        if !text.is_empty() {
+            ranges.push(range);
+        }
+    }
+
+    // the tokens may have matched in any order, and over each other
+    merge_ranges(ranges);
+    Some(rank)
+}
+
+// searched in the lowercase copy for tokens that ignore case, so units compare as they are
+fn index_of<T: Unit>(slice: &[T], start_index: usize, value: T) -> Option<usize> {
+    let shift = slice.iter().skip(start_index).position(|&ch| ch == value)?;
     Some(start_index + shift)
 }
 
-struct IndexIterator<'a> {
-    str: &'a [u8],
-    chr: u8,
+struct IndexIterator<'a, T> {
+    str: &'a [T],
+    chr: T,
     index: usize,
-    smart_case: bool
 }
 
-impl<'a> IndexIterator<'a> {
-    pub fn new(str: &'a [u8], chr: u8, smart_case: bool) -> Self {
-        Self { str, chr, index: 0, smart_case }
+impl<'a, T: Unit> IndexIterator<'a, T> {
+    pub fn new(str: &'a [T], chr: T) -> Self {
+        Self { str, chr, index: 0 }
     }
 }
 
-impl<'a> Iterator for IndexIterator<'a> {
+impl<'a, T: Unit> Iterator for IndexIterator<'a, T> {
     type Item = usize;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let out = if self.smart_case {
-            index_of(self.str, self.index, self.chr)
-        } else {
-            index_of_case_sensitive(self.str, self.index, self.chr)
-        };
+        let out = index_of(self.str, self.index, self.chr);
         if let Some(i) = out {
             self.index = i + 1;
         }
@@ -148,63 +885,74 @@ pub fn is_start_of_word(byte: u8) -> bool {
     matches!(byte as char, std::path::MAIN_SEPARATOR | '_' | '-' | '.' | ' ')
 }
 
+// a word starts at the beginning, after a separator, or where camelCase turns upper
+fn is_word_start<T: Unit>(str: &[T], index: usize) -> bool {
+    index == 0
+        || str[index - 1].is_start_of_word()
+        || (str[index - 1].is_lower() && str[index].is_upper())
+}
+
 /// this is the core of the ranking algorithm. special precedence is given to
 /// filenames. if a match is found on a filename the candidate is ranked higher
-fn scan_to_end(name: &[u8], token: &[u8], start_index: usize, smart_case: bool) -> Option<Match> {
+fn scan_to_end<T: Unit>(name: Haystack<T>, token: &[T], start_index: usize, case: CaseMode) -> Option<Match> {
+    let (search, name) = (name.search(case), name.units);
     let mut matched = Match { rank: 1.0, start: start_index, end: 0};
     let mut last_index = start_index;
     let mut last_sequential = false;
 
     // penalty for not starting on a word boundary
-    if start_index > 0 && !is_start_of_word(name[start_index-1]) {
+    if !is_word_start(name, start_index) {
         matched.rank += 2.0;
     }
 
-    for &chr in token.into_iter() {
-        if let Some(index) = if smart_case {
-            index_of(name, last_index+1, chr)
-        } else {
-            index_of_case_sensitive(name, last_index+1, chr)
-        } {
-            if index == last_index + 1 {
-                // sequential matches only count the first character
-                if !last_sequential {
-                    last_sequential = true;
-                    matched.rank += 1.0;
-                }
-            } else {
-                // penalty for not starting on a word boundary
-                if !is_start_of_word(name[index - 1]) {
-                    matched.rank += 2.0;
-                }
+    for &chr in token.iter() {
+        let index = index_of(search, last_index+1, chr)?;
 
-                last_sequential = false;
-                matched.rank += (index - last_index) as f64;
+        if index == last_index + 1 {
+            // sequential matches only count the first character
+            if !last_sequential {
+                last_sequential = true;
+                matched.rank += 1.0;
             }
-
-            last_index = index;
         } else {
-            return None;
+            // penalty for not starting on a word boundary
+            if !is_word_start(name, index) {
+                matched.rank += 2.0;
+            }
+
+            last_sequential = false;
+            matched.rank += (index - last_index) as f64;
         }
+
+        last_index = index;
     }
 
-    matched.end = last_index;
+    matched.end = last_index + 1;
     Some(matched)
 }
 
-fn rank_token(path: &[u8], name: Option<&[u8]>, range: &mut Range, token: &[u8], smart_case: bool) -> Option<f64> {
+/// subtracted from the rank of a fuzzy match starting at the first char of the filename
+const FILENAME_PREFIX_BONUS: f64 = 0.5;
+
+fn rank_token<T: Unit>(path: Haystack<T>, name: Option<Haystack<T>>, range: &mut Range, token: &[T], case: CaseMode) -> Option<f64> {
     // iterate over the indexes where the first char of the token matches
-    use std::f64::MAX;
+    const MAX: f64 = f64::MAX;
+    // an empty token matches anything without adding to the rank
+    let Some((&first, rest)) = token.split_first() else { return Some(0.0); };
     let mut best_rank: f64 = MAX;
     if let Some(name) = name {
-        let offs = path.len() - name.len();
-        for start_index in IndexIterator::new(path, token[0], smart_case) {
-            if let Some(matched) = scan_to_end(name, &token[1..], start_index, smart_case) {
+        for start_index in IndexIterator::new(name.search(case), first) {
+            if let Some(mut matched) = scan_to_end(name, rest, start_index, case) {
+                // bonus for matching from the start of the filename
+                if matched.start == 0 {
+                    matched.rank -= FILENAME_PREFIX_BONUS;
+                }
+
                 if best_rank == MAX || matched.rank < best_rank {
                     best_rank = matched.rank;
                     *range = Range {
-                        start: matched.start + offs,
-                        end: matched.end + offs,
+                        start: matched.start + name.start,
+                        end: matched.end + name.start,
                     };
                 }
             } else {
@@ -214,20 +962,11 @@ fn rank_token(path: &[u8], name: Option<&[u8]>, range: &mut Range, token: &[u8],
     }
 
     if best_rank < MAX {
-        best_rank = best_rank / 2.0;
-        // how much of the token matched the filename?
-        let token_len = token.len();
-        let name_len = name.unwrap().len();
-        if token_len == name_len {
-            best_rank = best_rank / 2.0;
-        } else {
-            let coverage = 1.0 - (token_len as f64) / (name_len as f64);
-            best_rank *= coverage;
-        }
+        best_rank = rank_filename(best_rank, token.len(), name.unwrap().len());
     } else {
         // retry on the full string
-        for start_index in IndexIterator::new(path, token[0], smart_case) {
-            if let Some(matched) = scan_to_end(path, &token[1..], start_index, smart_case) {
+        for start_index in IndexIterator::new(path.search(case), first) {
+            if let Some(matched) = scan_to_end(path, rest, start_index, case) {
                 if best_rank == MAX || matched.rank < best_rank {
                     best_rank = matched.rank;
                     *range = Range {
@@ -245,6 +984,52 @@ fn rank_token(path: &[u8], name: Option<&[u8]>, range: &mut Range, token: &[u8],
     if best_rank == MAX { None } else { Some(best_rank) }
 }
 
+// matches on the filename are ranked higher
+fn rank_filename(rank: f64, token_len: usize, name_len: usize) -> f64 {
+    let rank = rank / 2.0;
+    // how much of the token matched the filename? an empty filename has nothing to cover
+    if token_len == name_len {
+        rank / 2.0
+    } else if name_len == 0 {
+        rank
+    } else {
+        let coverage = 1.0 - (token_len as f64) / (name_len as f64);
+        rank * coverage
+    }
+}
+
+/// find the best contiguous occurrence of `token` in `str`. an exact match always
+/// ranks better than the best possible fuzzy match of the same token
+fn scan_exact<T: Unit>(str: Haystack<T>, token: &[T], case: CaseMode) -> Option<Match> {
+    let (search, str) = (str.search(case), str.units);
+    let mut best: Option<Match> = None;
+    for start_index in IndexIterator::new(search, token[0]) {
+        let Some(window) = search.get(start_index..start_index + token.len()) else { break; };
+        if window != token { continue; }
+
+        // penalty for not starting on a word boundary
+        let rank = if is_word_start(str, start_index) { 1.0 } else { 3.0 };
+        if best.as_ref().is_none_or(|b| rank < b.rank) {
+            best = Some(Match { rank, start: start_index, end: start_index + token.len() });
+        }
+    }
+
+    best
+}
+
+fn rank_exact<T: Unit>(path: Haystack<T>, name: Option<Haystack<T>>, range: &mut Range, token: &[T], case: CaseMode) -> Option<f64> {
+    if let Some(name) = name {
+        if let Some(matched) = scan_exact(name, token, case) {
+            *range = Range { start: matched.start + name.start, end: matched.end + name.start };
+            return Some(rank_filename(matched.rank, token.len(), name.len()));
+        }
+    }
+
+    let matched = scan_exact(path, token, case)?;
+    *range = Range { start: matched.start, end: matched.end };
+    Some(matched.rank)
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -252,34 +1037,761 @@ mod tests {
 
     #[test]
     fn collect_candidates_whitespace() {
-        let out = Candidate::collect("first second third fourth".as_bytes(), b' ', false);
+        let out = Candidate::collect("first second third fourth".as_bytes(), b' ', false, false).unwrap();
 
         assert_eq!(out.len(), 4);
-        assert_eq!(out[0].path.to_str().unwrap(), "first");
-        assert_eq!(out[1].path.to_str().unwrap(), "second");
-        assert_eq!(out[2].path.to_str().unwrap(), "third");
-        assert_eq!(out[3].path.to_str().unwrap(), "fourth");
+        assert_eq!(out[0].path, "first");
+        assert_eq!(out[1].path, "second");
+        assert_eq!(out[2].path, "third");
+        assert_eq!(out[3].path, "fourth");
+    }
+
+    #[test]
+    fn collect_candidates_crlf() {
+        let out = Candidate::collect("src/lib.rs\r\nREADME\r.md\r\n\r\nlast\r\r".as_bytes(), b'\n', false, false).unwrap();
+        assert_eq!(Vec::from_iter(out.iter().map(|c| c.path.as_str())), ["src/lib.rs", "README\r.md", "last\r"]);
+        assert_eq!(out[0].name.as_deref(), Some("lib.rs"));
+
+        // anything but newlines keeps them
+        let out = Candidate::collect("a\r\0b\r\n".as_bytes(), b'\0', false, false).unwrap();
+        assert_eq!(Vec::from_iter(out.iter().map(|c| c.path.as_str())), ["a\r", "b\r\n"]);
     }
 
     #[test]
     fn collect_candidates_newline() {
-        let out = Candidate::collect("first\nsecond\nthird\nfourth".as_bytes(), b'\n', false);
+        let out = Candidate::collect("first\nsecond\nthird\nfourth".as_bytes(), b'\n', false, false).unwrap();
 
         assert_eq!(out.len(), 4);
-        assert_eq!(out[0].path.to_str().unwrap(), "first");
-        assert_eq!(out[1].path.to_str().unwrap(), "second");
-        assert_eq!(out[2].path.to_str().unwrap(), "third");
-        assert_eq!(out[3].path.to_str().unwrap(), "fourth");
+        assert_eq!(out[0].path, "first");
+        assert_eq!(out[1].path, "second");
+        assert_eq!(out[2].path, "third");
+        assert_eq!(out[3].path, "fourth");
     }
 
     #[test]
     fn collect_candidates_excess_newline() {
-        let out = Candidate::collect("first   second   third    fourth".as_bytes(), b' ', false);
+        let out = Candidate::collect("first   second   third    fourth".as_bytes(), b' ', false, false).unwrap();
 
         assert_eq!(out.len(), 4);
-        assert_eq!(out[0].path.to_str().unwrap(), "first");
-        assert_eq!(out[1].path.to_str().unwrap(), "second");
-        assert_eq!(out[2].path.to_str().unwrap(), "third");
-        assert_eq!(out[3].path.to_str().unwrap(), "fourth");
+        assert_eq!(out[0].path, "first");
+        assert_eq!(out[1].path, "second");
+        assert_eq!(out[2].path, "third");
+        assert_eq!(out[3].path, "fourth");
+    }
+
+    struct FailingReader {
+        data: &'static [u8],
+    }
+
+    impl io::Read for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.data.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe"));
+            }
+            let len = self.data.len().min(buf.len());
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn strip_colors() {
+        let sgr = |at, params: &str| Sgr { at, params: params.to_owned() };
+        assert_eq!(strip_ansi("src/lib.rs"), ("src/lib.rs".to_owned(), vec![]));
+        assert_eq!(
+            strip_ansi("\x1b[33mabc1234\x1b[m fix \x1b[1;31mmain\x1b[0m.rs"),
+            ("abc1234 fix main.rs".to_owned(), vec![sgr(0, "33"), sgr(7, ""), sgr(12, "1;31"), sgr(16, "0")]),
+        );
+        // other sequences go too, and so does one cut off at the end
+        assert_eq!(strip_ansi("a\x1b[Kb\x1b[38;5;208"), ("ab".to_owned(), vec![]));
+        assert!(sgr(0, "").is_reset() && sgr(0, "0").is_reset() && !sgr(0, "01").is_reset());
+
+        let candidates = Candidate::collect(&b"\x1b[34msrc/\x1b[0mMain.rs\n"[..], b'\n', false, false).unwrap();
+        let candidate = candidates[0].clone().with_ansi(false);
+        assert_eq!(candidate.path, "src/Main.rs");
+        assert_eq!(candidate.name.as_deref(), Some("Main.rs"));
+        assert_eq!(candidate.lower.as_deref(), Some("src/main.rs"));
+        assert_eq!(candidate.colors, [sgr(0, "34"), sgr(4, "0")]);
+        assert!(score(&candidate.path, candidate.name.as_deref(), "0m", CaseMode::Smart).is_none());
+        assert!(candidate.with_display(&[Nth { first: 1, last: None }], None, false).colors.is_empty());
+    }
+
+    #[test]
+    fn candidates_from_lines() {
+        let lines = vec!["src/Main.rs".to_string(), "".to_string(), "README.md".to_string()];
+        let candidates = Candidate::from_lines(lines.clone(), false);
+        let read = Candidate::collect(&b"src/Main.rs\n\nREADME.md\n"[..], b'\n', false, false).unwrap();
+        assert_eq!(candidates, read);
+        assert_eq!(candidates[0].name.as_deref(), Some("Main.rs"));
+        assert_eq!(candidates[0].lower.as_deref(), Some("src/main.rs"));
+
+        let plain = Candidate::from_lines(lines, true);
+        assert_eq!(Vec::from_iter(plain.iter().map(|c| c.path.as_str())), ["src/Main.rs", "README.md"]);
+        assert!(plain.iter().all(|c| c.name.is_none()));
+
+        let text = "src/lib.rs\nsrc/ui.rs";
+        let ranked = rank_candidates(Candidate::from_lines(text.lines().map(String::from), false), "ui", false, Tiebreak::Length, CaseMode::Smart, None);
+        assert_eq!(Vec::from_iter(ranked.iter().map(|c| c.path.as_str())), ["src/ui.rs"]);
+    }
+
+    #[test]
+    fn range_conversions() {
+        assert_eq!(Range::from(2..5), Range { start: 2, end: 5 });
+        assert_eq!(ops::Range::from(Range::new(2, 5)), 2..5);
+        let ranges = Vec::from_iter([0..3, 4..4].map(Range::from));
+        assert_eq!(ranges, [Range::new(0, 3), Range::new(4, 4)]);
+        let range: ops::Range<usize> = ranges[0].clone().into();
+        assert_eq!(&"src/lib.rs"[range], "src");
+    }
+
+    #[test]
+    fn collect_candidates_read_error() {
+        let reader = io::BufReader::new(FailingReader { data: b"first\nsecond\nthi" });
+        let out = Candidate::collect(reader, b'\n', false, false);
+
+        assert_eq!(out.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn collect_candidates_invalid_utf8() {
+        let input: &[u8] = b"first\nsec\xffond\nthird";
+
+        let out = Candidate::collect(input, b'\n', false, false).unwrap();
+        assert_eq!(out.len(), 2);
+        assert_eq!(out[0].path, "first");
+        assert_eq!(out[1].path, "third");
+
+        let out = Candidate::collect(input, b'\n', false, true).unwrap();
+        assert_eq!(out.len(), 3);
+        assert_eq!(out[1].path, "sec\u{FFFD}ond");
+        assert_eq!(out[1].name.as_deref(), Some("sec\u{FFFD}ond"));
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn rank_parallel_matches_sequential() {
+        let paths = Vec::from_iter((0..5000).map(|i| format!("src/module_{}/file_{}.rs\n", i % 37, i)));
+        let candidates = Candidate::collect(paths.concat().as_bytes(), b'\n', false, false).unwrap();
+
+        for query in ["file", "m3 f1", "src rs", "zzz"] {
+            for keep_order in [false, true] {
+                let sequential = rank_candidates_with_threshold(candidates.clone(), query, keep_order, Tiebreak::Length, CaseMode::Smart, None, usize::MAX);
+                let parallel = rank_candidates_with_threshold(candidates.clone(), query, keep_order, Tiebreak::Length, CaseMode::Smart, None, 0);
+                assert_eq!(sequential, parallel);
+            }
+        }
+    }
+
+    #[test]
+    fn keep_order_is_input_order() {
+        // the better matches come last
+        let lines = Vec::from_iter((0..3000).map(|i| match i % 3 {
+            0 => format!("a/very/long/path/x_{}/f_i_l_e.txt\n", i),
+            1 => format!("none_{}\n", i),
+            _ => format!("file_{}\n", i),
+        }));
+        let candidates = Candidate::collect(lines.concat().as_bytes(), b'\n', false, false).unwrap();
+        let expected = Vec::from_iter(candidates.iter().filter(|c| !c.path.starts_with("none")).map(|c| c.path.as_str()));
+
+        for threshold in [usize::MAX, 0] {
+            let ranked = rank_candidates_with_threshold(candidates.clone(), "file", true, Tiebreak::Length, CaseMode::Smart, None, threshold);
+            assert_eq!(paths(&ranked), expected);
+
+            let ranked = rank_candidates_with_threshold(candidates.clone(), "file", true, Tiebreak::Begin, CaseMode::Smart, Some(5), threshold);
+            assert_eq!(paths(&ranked), expected[..5]);
+        }
+    }
+
+    fn paths(candidates: &[Candidate]) -> Vec<&str> {
+        Vec::from_iter(candidates.iter().map(|c| c.path.as_str()))
+    }
+
+    #[test]
+    fn rank_exact_token() {
+        let candidates = Candidate::collect("src/lib.rs\ns-r-c\nSRC/main.rs\nresource".as_bytes(), b'\n', false, false).unwrap();
+
+        let ranked = rank_candidates(candidates.clone(), "src", false, Tiebreak::Length, CaseMode::Smart, None);
+        assert_eq!(paths(&ranked).len(), 4);
+
+        let ranked = rank_candidates(candidates.clone(), "'src", false, Tiebreak::Length, CaseMode::Smart, None);
+        assert_eq!(paths(&ranked), ["src/lib.rs", "SRC/main.rs"]);
+        assert_eq!(ranked[0].ranges, [Range::new(0, 3)]);
+
+        // exact matches are case sensitive as soon as the query has an uppercase letter
+        let ranked = rank_candidates(candidates.clone(), "'SRC", false, Tiebreak::Length, CaseMode::Smart, None);
+        assert_eq!(paths(&ranked), ["SRC/main.rs"]);
+
+        // an exact match on the filename
+        let ranked = rank_candidates(candidates.clone(), "'main", false, Tiebreak::Length, CaseMode::Smart, None);
+        assert_eq!(paths(&ranked), ["SRC/main.rs"]);
+        assert_eq!(ranked[0].ranges, [Range::new(4, 8)]);
+
+        // an exact token ranks better than the equivalent fuzzy one
+        let exact = rank_candidates(candidates.clone(), "'lib", false, Tiebreak::Length, CaseMode::Smart, None);
+        let fuzzy = rank_candidates(candidates.clone(), "lib", false, Tiebreak::Length, CaseMode::Smart, None);
+        assert!(exact[0].rank < fuzzy[0].rank);
+
+        // a lone sigil is matched literally
+        assert!(rank_candidates(candidates, "'", false, Tiebreak::Length, CaseMode::Smart, None).is_empty());
+    }
+
+    #[test]
+    fn rank_negated_token() {
+        let candidates = Candidate::collect("foo/bar\nfoo/test\nfoo/Testing.rs\nbaz".as_bytes(), b'\n', false, false).unwrap();
+
+        let ranked = rank_candidates(candidates.clone(), "foo !test", false, Tiebreak::Length, CaseMode::Smart, None);
+        assert_eq!(paths(&ranked), ["foo/bar"]);
+        assert_eq!(ranked[0].ranges, [Range::new(0, 3)]);
+
+        let ranked = rank_candidates(candidates.clone(), "foo !Test", false, Tiebreak::Length, CaseMode::Smart, None);
+        assert_eq!(paths(&ranked), ["foo/bar", "foo/test"]);
+
+        // a negated token on its own keeps everything else in input order
+        let ranked = rank_candidates(candidates.clone(), "!foo", true, Tiebreak::Length, CaseMode::Smart, None);
+        assert_eq!(paths(&ranked), ["baz"]);
+        assert!(ranked[0].ranges.is_empty());
+        assert_eq!(ranked[0].rank, 0.0);
+
+        // a lone sigil is matched literally
+        assert!(rank_candidates(candidates, "!", false, Tiebreak::Length, CaseMode::Smart, None).is_empty());
+    }
+
+    #[test]
+    fn score_known_inputs() {
+        assert_eq!(score("src/lib.rs", Some("lib.rs"), "lib", CaseMode::Smart), Some((0.375, vec![Range::new(4, 7)])));
+        assert_eq!(score("src/lib.rs", None, "sl", CaseMode::Smart), Some((5.0, vec![Range::new(0, 5)])));
+        assert_eq!(
+            score("src/lib.rs", Some("lib.rs"), "src lib", CaseMode::Smart),
+            Some((2.375, vec![Range::new(0, 3), Range::new(4, 7)]))
+        );
+        assert_eq!(score("src/lib.rs", Some("lib.rs"), "LIB", CaseMode::Respect), None);
+        assert_eq!(score("src/lib.rs", Some("lib.rs"), "main", CaseMode::Smart), None);
+
+        // agrees with rank_candidates
+        let candidates = Candidate::collect("src/lib.rs".as_bytes(), b'\n', false, false).unwrap();
+        let ranked = rank_candidates(candidates, "src lib", false, Tiebreak::Length, CaseMode::Smart, None);
+        assert_eq!(Some((ranked[0].rank, ranked[0].ranges.clone())), score("src/lib.rs", Some("lib.rs"), "src lib", CaseMode::Smart));
+    }
+
+    #[test]
+    fn score_candidates_by_index() {
+        let candidates = Candidate::collect("src/lib.rs\nsrc/ui.rs\nlib/ui.zig\nREADME.md".as_bytes(), b'\n', false, false).unwrap();
+
+        for (query, keep_order) in [("ui", false), ("ui", true), ("lib", false), ("s r", false), ("xyz", false)] {
+            let ranked = rank_candidates(candidates.clone(), query, keep_order, Tiebreak::Length, CaseMode::Smart, None);
+            let scored = score_candidates(&candidates, query, keep_order, Tiebreak::Length, CaseMode::Smart, None);
+            assert_eq!(ranked.len(), scored.len());
+            for (candidate, scored) in ranked.iter().zip(scored.iter()) {
+                assert_eq!(candidate.path, candidates[scored.index].path);
+                assert_eq!(candidate.rank, scored.rank);
+                assert_eq!(candidate.ranges, scored.ranges);
+            }
+        }
+    }
+
+    #[test]
+    fn max_results_top_k() {
+        // duplicates and equal ranks, so the tiebreaks decide the cut
+        let input = "src/lib.rs\nsrc/ui.rs\nlib/ui.zig\nREADME.md\nsrc/lib.rs\nsrc/a/lib.rs\nb/lib.rs\na/lib.rs\nlib.rs";
+        let candidates = Candidate::collect(input.as_bytes(), b'\n', false, false).unwrap();
+
+        for query in ["lib", "s", "rs", "", "xyz"] {
+            for keep_order in [false, true] {
+                for tiebreak in [Tiebreak::Length, Tiebreak::Begin, Tiebreak::Index] {
+                    let all = score_candidates(&candidates, query, keep_order, tiebreak, CaseMode::Smart, None);
+                    for max in 0..=candidates.len() + 1 {
+                        let top = score_candidates(&candidates, query, keep_order, tiebreak, CaseMode::Smart, Some(max));
+                        assert_eq!(top, all[..max.min(all.len())], "{:?} {}", query, max);
+                    }
+                }
+            }
+        }
+
+        let ranked = rank_candidates(candidates, "lib", false, Tiebreak::Length, CaseMode::Smart, Some(2));
+        assert_eq!(paths(&ranked), ["lib.rs", "a/lib.rs"]);
+    }
+
+    #[test]
+    fn empty_query_keeps_everything() {
+        let candidates = Candidate::collect("src/ui.rs\nREADME.md\nsrc/lib.rs".as_bytes(), b'\n', false, false).unwrap();
+
+        for query in ["", "   "] {
+            let ranked = rank_candidates(candidates.clone(), query, false, Tiebreak::Length, CaseMode::Smart, None);
+            assert_eq!(paths(&ranked), ["src/ui.rs", "README.md", "src/lib.rs"]);
+            assert!(ranked.iter().all(|c| c.rank == 0.0 && c.ranges.is_empty()));
+
+            let scored = score_candidates(&candidates, query, false, Tiebreak::Length, CaseMode::Smart, None);
+            assert_eq!(Vec::from_iter(scored.iter().map(|s| s.index)), [0, 1, 2]);
+        }
+    }
+
+    #[test]
+    fn multi_line_query() {
+        assert_eq!(split_query("src lib\n'rs\r\n\n!main\n"), ["src", "lib", "'rs", "!main"]);
+        assert!(split_query("\n \n").is_empty());
+
+        let candidates = Candidate::collect("src/lib.rs\nsrc/main.rs\nlib/mod.rs\nsrc/ui.rs\n".as_bytes(), b'\n', false, false).unwrap();
+        let rank = |query| rank_candidates(candidates.clone(), query, false, Tiebreak::Length, CaseMode::Smart, None);
+
+        // every line has to match, and the ranges of all of them are highlighted
+        let ranked = rank("'src\n'lib");
+        assert_eq!(paths(&ranked), ["src/lib.rs"]);
+        assert_eq!(ranked[0].ranges, [Range::new(0, 3), Range::new(4, 7)]);
+        assert_eq!(ranked, rank("'src 'lib"));
+
+        // operators apply to the tokens of their own line
+        assert_eq!(paths(&rank("'src\n!main !lib")), ["src/ui.rs"]);
+        assert_eq!(paths(&rank("\n'mod\n\n")), ["lib/mod.rs"]);
+        assert_eq!(rank("\n\n").len(), 4);
+    }
+
+    #[test]
+    fn skip_empty_tokens() {
+        assert_eq!(split_query("foo  bar"), ["foo", "bar"]);
+        assert_eq!(split_query("foo "), ["foo"]);
+        assert!(split_query("   ").is_empty());
+
+        assert_eq!(score("src/lib.rs", Some("lib.rs"), "src  lib", CaseMode::Smart), score("src/lib.rs", Some("lib.rs"), "src lib", CaseMode::Smart));
+        assert_eq!(score("src/lib.rs", Some("lib.rs"), "lib ", CaseMode::Smart), score("src/lib.rs", Some("lib.rs"), "lib", CaseMode::Smart));
+        assert_eq!(score("src/lib.rs", Some("lib.rs"), "   ", CaseMode::Smart), Some((0.0, vec![])));
+    }
+
+    #[test]
+    fn empty_tokens_and_names() {
+        let mut range = Range::default();
+        let path = Haystack { units: b"src/lib.rs", lower: b"src/lib.rs", start: 0 };
+        let name = Haystack { units: b"lib.rs", lower: b"lib.rs", start: 4 };
+        assert_eq!(rank_token(path, Some(name), &mut range, b"", CaseMode::Ignore), Some(0.0));
+        assert_eq!(range, Range::default());
+
+        let mut ranges = vec![];
+        let mut tokens = Matcher::new("lib").tokens;
+        tokens.insert(0, Token { kind: TokenKind::Fuzzy, text: String::new(), chars: vec![], case: CaseMode::Ignore });
+        assert_eq!(rank_tokens(b"src/lib.rs", b"src/lib.rs", Some(4..10), &tokens, &mut ranges), Some(0.375));
+        assert_eq!(ranges, [Range::new(4, 7)]);
+
+        assert!(rank_filename(2.0, 1, 0).is_finite());
+        assert_eq!(score("src/", Some(""), "src", CaseMode::Smart), score("src/", None, "src", CaseMode::Smart));
+
+        let mut candidates = Candidate::collect("src/\nsrc/lib.rs".as_bytes(), b'\n', false, false).unwrap();
+        candidates[0].name = Some(String::new());
+        let ranked = rank_candidates(candidates, "s", false, Tiebreak::Length, CaseMode::Smart, None);
+        assert!(ranked.iter().all(|c| c.rank.is_finite()));
+        assert_eq!(paths(&ranked), ["src/", "src/lib.rs"]);
+    }
+
+    #[test]
+    fn tiebreak_equal_ranks() {
+        let candidates = Candidate::collect("x/a\nabcd\nab".as_bytes(), b'\n', true, false).unwrap();
+        for (tiebreak, expected) in [
+            (Tiebreak::Length, ["ab", "x/a", "abcd"]),
+            (Tiebreak::Begin, ["ab", "abcd", "x/a"]),
+            (Tiebreak::Index, ["x/a", "abcd", "ab"]),
+        ] {
+            let ranked = rank_candidates(candidates.clone(), "a", false, tiebreak, CaseMode::Smart, None);
+            assert!(ranked.iter().all(|c| c.rank == ranked[0].rank));
+            assert_eq!(paths(&ranked), expected, "{:?}", tiebreak);
+        }
+    }
+
+    #[test]
+    fn smart_case_per_token() {
+        assert!(score("foo/BAR", None, "bar", CaseMode::Smart).is_some());
+        assert!(score("foo/BAR", None, "Foo", CaseMode::Smart).is_none());
+        assert!(score("foo/BAR", None, "Foo bar", CaseMode::Smart).is_none());
+        assert_eq!(
+            score("Foo/BAR", None, "Foo bar", CaseMode::Smart),
+            Some((4.0, vec![Range::new(0, 3), Range::new(4, 7)]))
+        );
+        assert!(score("Foo/BAR", None, "Foo bar", CaseMode::Respect).is_none());
+
+        let candidates = Candidate::collect("foo/BAR\nFoo/BAR\nFoo/baz".as_bytes(), b'\n', true, false).unwrap();
+        assert_eq!(paths(&rank_candidates(candidates, "Foo bar", false, Tiebreak::Length, CaseMode::Smart, None)), ["Foo/BAR"]);
+    }
+
+    #[test]
+    fn exclusive_range_ends() {
+        let ranges = |path, query| score(path, None, query, CaseMode::Smart).unwrap().1;
+
+        // one char, several in a row, fuzzy and exact
+        assert_eq!(ranges("src/lib.rs", "l"), [Range::new(4, 5)]);
+        assert_eq!(ranges("src/lib.rs", "lib"), [Range::new(4, 7)]);
+        assert_eq!(ranges("src/lib.rs", "'.rs"), [Range::new(7, 10)]);
+        assert_eq!(&"src/lib.rs"[4..7], "lib");
+
+        // in bytes, ending after the last char
+        assert_eq!(ranges("a/é", "é"), [Range::new(2, 4)]);
+        assert_eq!(ranges("a/éb", "'éb"), [Range::new(2, 5)]);
+    }
+
+    #[test]
+    fn merged_ranges() {
+        let ranges = |query| score("src/lib.rs", Some("lib.rs"), query, CaseMode::Smart).unwrap().1;
+
+        // overlapping, touching, and out of order
+        assert_eq!(ranges("lib li"), [Range::new(4, 7)]);
+        assert_eq!(ranges("'src/ 'lib"), [Range::new(0, 7)]);
+        assert_eq!(ranges("lib src"), [Range::new(0, 3), Range::new(4, 7)]);
+        assert_eq!(ranges("'.rs ib"), [Range::new(5, 10)]);
+
+        // in bytes for non-ascii paths
+        let (_, ranges) = score("ü/über.rs", Some("über.rs"), "üb be", CaseMode::Smart).unwrap();
+        assert_eq!(ranges, [Range::new(3, 7)]);
+
+        let mut ranges = vec![Range::new(9, 10), Range::new(2, 5), Range::new(3, 4), Range::new(5, 7)];
+        merge_ranges(&mut ranges);
+        assert_eq!(ranges, [Range::new(2, 7), Range::new(9, 10)]);
+
+        // ranges with a gap stay apart
+        let mut ranges = vec![Range::new(4, 6), Range::new(0, 3)];
+        merge_ranges(&mut ranges);
+        assert_eq!(ranges, [Range::new(0, 3), Range::new(4, 6)]);
+    }
+
+    #[test]
+    fn cached_lowercase() {
+        let input = "src/FooBar.rs\nSRC/lib.rs\nsrc/fooBAR/x.rs\nREADME.md\nsrc/lib.rs\nÜBER/Foo.rs\n1 Foo BAR";
+        let candidates = Candidate::collect(input.as_bytes(), b'\n', false, false).unwrap();
+        assert_eq!(candidates[0].lower.as_deref(), Some("src/foobar.rs"));
+        assert_eq!(candidates[4].lower, None);
+        assert_eq!(candidates[5].lower, None);
+
+        let fields = Nth { first: 2, last: None };
+        let displayed = Vec::from_iter(candidates.iter().map(|c| c.clone().with_display(&[fields], None, false)));
+        assert_eq!(displayed[6].lower.as_deref(), Some("foo bar"));
+        let nth = Vec::from_iter(candidates.iter().map(|c| c.clone().with_fields(fields, None, false)));
+
+        // the same ranks and ranges as lowercasing while matching
+        for candidates in [candidates, displayed, nth] {
+            let uncached = Vec::from_iter(candidates.iter().map(|c| Candidate { lower: None, ..c.clone() }));
+            for query in ["foo", "fb", "Bar", "src lib", "'bar", "'BAR", "!foo", "über", "rs"] {
+                for case in [CaseMode::Smart, CaseMode::Ignore, CaseMode::Respect] {
+                    let ranked = |candidates: &Vec<Candidate>| Vec::from_iter(
+                        rank_candidates(candidates.clone(), query, false, Tiebreak::Length, case, None)
+                            .into_iter().map(|c| (c.path, c.rank, c.ranges)),
+                    );
+                    assert_eq!(ranked(&candidates), ranked(&uncached), "{:?}", query);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn case_mode_overrides() {
+        let candidates = Candidate::collect("src/FOO.rs\nsrc/foo.rs\nsrc/Foo.rs".as_bytes(), b'\n', false, false).unwrap();
+        let rank = |query, case| {
+            let ranked = rank_candidates(candidates.clone(), query, false, Tiebreak::Length, case, None);
+            let mut paths = Vec::from_iter(ranked.into_iter().map(|c| c.path));
+            paths.sort();
+            paths
+        };
+
+        assert_eq!(rank("foo", CaseMode::Smart), ["src/FOO.rs", "src/Foo.rs", "src/foo.rs"]);
+        assert_eq!(rank("foo", CaseMode::Respect), ["src/foo.rs"]);
+        assert_eq!(rank("FOO", CaseMode::Smart), ["src/FOO.rs"]);
+        assert_eq!(rank("FOO", CaseMode::Ignore), ["src/FOO.rs", "src/Foo.rs", "src/foo.rs"]);
+        assert_eq!(rank("'Foo", CaseMode::Ignore), ["src/FOO.rs", "src/Foo.rs", "src/foo.rs"]);
+        assert!(rank("foo !FOO", CaseMode::Ignore).is_empty());
+        assert_eq!(rank("foo !FOO", CaseMode::Respect), ["src/foo.rs"]);
+        assert_eq!(score("docs/été.md", None, "ÉTÉ", CaseMode::Ignore).map(|(_, r)| r), Some(vec![Range::new(5, 10)]));
+    }
+
+    #[test]
+    fn unicode_matching() {
+        // case folding beyond ascii, with byte ranges for highlighting
+        assert_eq!(score("docs/Été.md", Some("Été.md"), "été", CaseMode::Smart).map(|(_, r)| r), Some(vec![Range::new(5, 10)]));
+        assert_eq!(score("docs/été.md", Some("été.md"), "Été", CaseMode::Smart), None);
+        assert_eq!(score("İstanbul", None, "ist", CaseMode::Smart).map(|(_, r)| r), Some(vec![Range::new(0, 4)]));
+
+        // distances count chars rather than bytes
+        assert_eq!(score("é/a", None, "éa", CaseMode::Smart).map(|(rank, _)| rank), score("e/a", None, "ea", CaseMode::Smart).map(|(rank, _)| rank));
+
+        assert_eq!(score("文档/测试.txt", Some("测试.txt"), "测试", CaseMode::Smart).map(|(_, r)| r), Some(vec![Range::new(7, 13)]));
+        assert_eq!(score("文档/测试.txt", Some("测试.txt"), "'档/", CaseMode::Smart).map(|(_, r)| r), Some(vec![Range::new(3, 7)]));
+        assert_eq!(score("文档/测试.txt", Some("测试.txt"), "测 !文档", CaseMode::Smart), None);
+
+        let candidates = Candidate::collect("über/a.txt\nÜBER.txt\nuber.txt".as_bytes(), b'\n', false, false).unwrap();
+        assert_eq!(paths(&rank_candidates(candidates, "über", false, Tiebreak::Length, CaseMode::Smart, None)), ["ÜBER.txt", "über/a.txt"]);
+    }
+
+    #[test]
+    fn matcher_ranks_candidates() {
+        let matcher = Matcher::new("src lib !test");
+        let mut candidates = Candidate::collect("src/lib.rs\nsrc/test/lib.rs\nREADME.md".as_bytes(), b'\n', false, false).unwrap();
+
+        assert!(matcher.rank(&mut candidates[0]));
+        assert_eq!(
+            (candidates[0].rank, &candidates[0].ranges),
+            (2.375, &vec![Range::new(0, 3), Range::new(4, 7)])
+        );
+
+        for candidate in candidates[1..].iter_mut() {
+            let before = candidate.clone();
+            assert!(!matcher.rank(candidate));
+            assert_eq!(*candidate, before);
+        }
+
+        // a matcher can be reused, and agrees with score
+        let matcher = Matcher::new("Lib");
+        for path in ["src/lib.rs", "src/Lib.rs", "Lib/main.rs"] {
+            let mut candidate = Candidate::collect(path.as_bytes(), b'\n', false, false).unwrap().remove(0);
+            let expected = score(path, candidate.name.as_deref(), "Lib", CaseMode::Smart);
+            assert_eq!(matcher.rank(&mut candidate), expected.is_some());
+            if let Some((rank, ranges)) = expected {
+                assert_eq!((candidate.rank, candidate.ranges), (rank, ranges));
+            }
+        }
+    }
+
+    #[test]
+    fn camel_case_word_starts() {
+        let rank = |path, query| score(path, None, query, CaseMode::Smart).map(|(rank, _)| rank).unwrap();
+
+        assert!(is_word_start(b"getNextState", 3));
+        assert!(!is_word_start(b"getnextstate", 3));
+        assert!(!is_word_start(b"GETNEXT", 3));
+
+        assert!(rank("getNextState", "gns") < rank("getnextstate", "gns"));
+        assert_eq!(rank("getNextState", "gNS"), rank("getNextState", "gns"));
+        assert_eq!(rank("src/getNextState", "'Next"), 1.0);
+        assert_eq!(rank("src/getnextstate", "'next"), 3.0);
+    }
+
+    #[test]
+    fn filename_prefix_bonus() {
+        let rank = |path: &str, query| {
+            let name = Path::new(path).file_name().and_then(|n| n.to_str());
+            score(path, name, query, CaseMode::Smart).map(|(rank, _)| rank).unwrap()
+        };
+
+        assert!(rank("src/lib_my.rs", "lib") < rank("src/my_lib.rs", "lib"));
+        assert!(rank("a/ui.rs", "ui") < rank("a/b_ui.rs", "ui"));
+        assert!(rank("lib.rs", "lib") < rank("src/liberal.rs", "lib"));
+
+        let candidates = Candidate::collect("src/my_lib.rs\nsrc/lib_my.rs".as_bytes(), b'\n', false, false).unwrap();
+        assert_eq!(paths(&rank_candidates(candidates, "lib", false, Tiebreak::Length, CaseMode::Smart, None)), ["src/lib_my.rs", "src/my_lib.rs"]);
+    }
+
+    #[test]
+    fn name_not_ending_path() {
+        // the name is ignored, the whole path is ranked as if there were none
+        assert_eq!(score("a", Some("abc"), "a", CaseMode::Smart), score("a", None, "a", CaseMode::Smart));
+        assert_eq!(score("src/lib.rs", Some("src"), "src", CaseMode::Smart), score("src/lib.rs", None, "src", CaseMode::Smart));
+        assert_eq!(score("src/lib.rs", Some("src"), "lib", CaseMode::Smart), Some((2.0, vec![Range::new(4, 7)])));
+    }
+
+    #[test]
+    fn literal_query() {
+        let candidates = Candidate::from_lines(["foo barista", "bar/foo", "foo_bar", "a/Foo Bar.txt"].map(String::from), false);
+        let literal = |query, case| Vec::from_iter(rank_candidates_with(candidates.clone(), &Matcher::literal(query, case), false, Tiebreak::Length, None).into_iter().map(|c| c.path));
+
+        // the space has to match too, in the order given
+        assert_eq!(literal("foo bar", CaseMode::Smart), ["foo barista", "a/Foo Bar.txt"]);
+        assert_eq!(paths(&rank_candidates(candidates.clone(), "foo bar", false, Tiebreak::Length, CaseMode::Smart, None)).len(), 4);
+        assert_eq!(literal("fo ba", CaseMode::Smart), ["foo barista", "a/Foo Bar.txt"]);
+        assert_eq!(literal("Foo Bar", CaseMode::Smart), ["a/Foo Bar.txt"]);
+        assert_eq!(literal("foo bar", CaseMode::Respect), ["foo barista"]);
+        // prefixes are part of the text
+        assert!(literal("!foo", CaseMode::Smart).is_empty());
+        assert_eq!(literal("  ", CaseMode::Smart).len(), 4);
+
+        let ranked = rank_candidates_with(candidates, &Matcher::literal("o b", CaseMode::Smart), false, Tiebreak::Length, None);
+        assert_eq!(ranked[0].path, "foo barista");
+        assert_eq!(ranked[0].ranges, [Range::new(2, 5)]);
+        assert!(Matcher::literal("\n", CaseMode::Smart).is_empty() && !Matcher::literal("a b", CaseMode::Smart).is_empty());
+    }
+
+    #[test]
+    fn rank_by_reference() {
+        let candidates = Candidate::from_lines(["src/main.rs", "README.md", "src/lib.rs", "lib/main.zig"].map(String::from), false);
+        assert_eq!(rank_candidates_ref(&candidates, "main", false), [0, 3]);
+        assert_eq!(rank_candidates_ref(&candidates, "lib", false), [2, 3]);
+        assert_eq!(rank_candidates_ref(&candidates, "", true), [0, 1, 2, 3]);
+        assert!(rank_candidates_ref(&candidates, "xyz", false).is_empty());
+
+        let ranked = rank_candidates(candidates.clone(), "s", false, Tiebreak::Length, CaseMode::Smart, None);
+        let indices = rank_candidates_ref(&candidates, "s", false);
+        assert_eq!(paths(&ranked), Vec::from_iter(indices.iter().map(|&i| candidates[i].path.as_str())));
+    }
+
+    #[test]
+    fn narrowing_queries() {
+        assert!(narrows("", "src"));
+        assert!(narrows("sr", "src"));
+        assert!(narrows("src", "src l"));
+        assert!(narrows("'s", "'src"));
+        assert!(!narrows("src", "sr"));
+        assert!(!narrows("src", "lib"));
+        // a lone ' is fuzzy, followed by more it is exact
+        assert!(!narrows("src '", "src 'l"));
+        assert!(!narrows("!", "!t"));
+        assert!(!narrows("src", "src !t"));
+    }
+
+    #[test]
+    fn rescore_as_the_query_grows() {
+        let candidates = Candidate::collect("src/main.rs\nsrc/Main.zig\nREADME.md\nsrc/lib.rs\nlib/main.rs\nmain".as_bytes(), b'\n', false, false).unwrap();
+        for keep_order in [false, true] {
+            for query in ["src main", "m a", "'src ma", "Ma", "  ma"] {
+                let mut scored = score_candidates(&candidates, "", keep_order, Tiebreak::Length, CaseMode::Smart, None);
+                for end in 1..=query.len() {
+                    let (old, query) = (&query[..end - 1], &query[..end]);
+                    let all = score_candidates(&candidates, query, keep_order, Tiebreak::Length, CaseMode::Smart, None);
+                    // after a lone ' there is no shortcut
+                    if !narrows(old, query) {
+                        assert_eq!(old, "'");
+                        scored = all;
+                        continue;
+                    }
+                    scored = rescore(&scored, &candidates, &Matcher::new(query), keep_order, Tiebreak::Length);
+                    assert_eq!(scored, all, "{:?}", query);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn score_streamed_candidates() {
+        let all = Candidate::collect("src/ui.rs\nREADME.md\nsrc/lib.rs\nlib/ui.zig\nui".as_bytes(), b'\n', false, false).unwrap();
+
+        for (query, keep_order) in [("ui", false), ("ui", true), ("", false), ("xyz", false)] {
+            let mut scored = score_candidates(&all[..2], query, keep_order, Tiebreak::Length, CaseMode::Smart, None);
+            score_more(&mut scored, &all[..4], 2, query, keep_order, Tiebreak::Length, CaseMode::Smart);
+            score_more(&mut scored, &all, 4, query, keep_order, Tiebreak::Length, CaseMode::Smart);
+            assert_eq!(scored, score_candidates(&all, query, keep_order, Tiebreak::Length, CaseMode::Smart, None), "{:?}", query);
+        }
+
+        // records are yielded one at a time, before the input ends
+        let mut stream = Candidate::stream("first\n\nsecond".as_bytes(), b'\n', false, false);
+        assert_eq!(stream.next().unwrap().unwrap().path, "first");
+        assert_eq!(stream.next().unwrap().unwrap().path, "second");
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn nth_field_spans() {
+        let nth = |first, last| Nth { first, last };
+        let record = "  1234 pts/0   vim src/lib.rs";
+        let field = |n: Nth, delimiter| n.span(record, delimiter).map(|span| &record[span]);
+
+        assert_eq!(field(nth(1, Some(1)), None), Some("1234"));
+        assert_eq!(field(nth(2, Some(2)), None), Some("pts/0"));
+        assert_eq!(field(nth(3, None), None), Some("vim src/lib.rs"));
+        assert_eq!(field(nth(2, Some(3)), None), Some("pts/0   vim"));
+        assert_eq!(field(nth(4, Some(9)), None), Some("src/lib.rs"));
+        assert_eq!(field(nth(5, Some(5)), None), None);
+        assert_eq!(field(nth(0, Some(1)), None), None);
+        assert_eq!(field(nth(3, Some(2)), None), None);
+
+        assert_eq!(field(nth(1, Some(1)), Some(' ')), Some(""));
+        assert_eq!(field(nth(3, Some(3)), Some(' ')), Some("1234"));
+        assert_eq!(nth(2, Some(2)).span("a:b:c", Some(':')), Some(2..3));
+        assert_eq!(nth(2, None).span("a::c", Some(':')), Some(2..4));
+    }
+
+    #[test]
+    fn collect_field_spans() {
+        let content = "a\tbb\t\tccc\n  1234 pts/0   vim\n";
+        let spans = |delimiter| Vec::from_iter(Candidate::collect_fields(content.as_bytes(), b'\n', delimiter, false, false).unwrap()
+            .into_iter().map(|c| c.fields));
+        let range = |start, end| Range { start, end };
+
+        let tabs = spans(Some('\t'));
+        assert_eq!(tabs[0], [range(0, 1), range(2, 4), range(5, 5), range(6, 9)]);
+        assert_eq!(tabs[1], [range(0, 18)]);
+
+        let spaces = spans(None);
+        assert_eq!(spaces[0], [range(0, 1), range(2, 4), range(6, 9)]);
+        assert_eq!(spaces[1], [range(2, 6), range(7, 12), range(15, 18)]);
+
+        // the spans pick the same bytes as splitting the record again
+        let record = "  1234 pts/0   vim";
+        let nth = Nth { first: 2, last: Some(3) };
+        assert_eq!(nth.span_in(&spaces[1]), nth.span(record, None));
+        assert_eq!(&record[nth.span_in(&spaces[1]).unwrap()], "pts/0   vim");
+
+        // nothing is recorded by default
+        assert!(Candidate::collect(content.as_bytes(), b'\n', false, false).unwrap()[0].fields.is_empty());
+    }
+
+    #[test]
+    fn match_nth_field() {
+        let content = "1 lib main\n2 main lib\n3 src/lib.rs x\n4";
+        let nth = Nth { first: 2, last: Some(2) };
+        let candidates = Vec::from_iter(Candidate::collect(content.as_bytes(), b'\n', false, false).unwrap()
+            .into_iter().map(|c| c.with_fields(nth, None, false)));
+        assert_eq!(candidates[2].name.as_deref(), Some("lib.rs"));
+
+        // only the second field is matched, the whole line is returned
+        let ranked = rank_candidates(candidates.clone(), "lib", false, Tiebreak::Length, CaseMode::Smart, None);
+        assert_eq!(paths(&ranked), ["1 lib main", "3 src/lib.rs x"]);
+        assert_eq!(ranked[0].ranges, [Range::new(2, 5)]);
+        assert_eq!(ranked[1].ranges, [Range::new(6, 9)]);
+
+        assert_eq!(paths(&rank_candidates(candidates.clone(), "main", false, Tiebreak::Length, CaseMode::Smart, None)), ["2 main lib"]);
+        assert!(rank_candidates(candidates.clone(), "1", false, Tiebreak::Length, CaseMode::Smart, None).is_empty());
+        assert_eq!(rank_candidates(candidates, "", false, Tiebreak::Length, CaseMode::Smart, None).len(), 4);
+    }
+
+    #[test]
+    fn match_filename_only() {
+        let candidates = Vec::from_iter(Candidate::collect("src/lib.rs\nlib/main.rs\ndocs/\n".as_bytes(), b'\n', false, false).unwrap()
+            .into_iter().map(Candidate::with_filename_only));
+        assert_eq!(candidates[0].field, Some(4..10));
+        assert_eq!(candidates[2].field, Some(0..4));
+
+        let ranked = rank_candidates(candidates.clone(), "lib", false, Tiebreak::Length, CaseMode::Smart, None);
+        assert_eq!(paths(&ranked), ["src/lib.rs"]);
+        assert_eq!(ranked[0].ranges, [Range::new(4, 7)]);
+        assert!(rank_candidates(candidates.clone(), "src", false, Tiebreak::Length, CaseMode::Smart, None).is_empty());
+        assert_eq!(paths(&rank_candidates(candidates.clone(), "doc", false, Tiebreak::Length, CaseMode::Smart, None)), ["docs/"]);
+
+        // the directories still match without it
+        let candidates = Candidate::collect("src/lib.rs\nlib/main.rs\n".as_bytes(), b'\n', false, false).unwrap();
+        assert_eq!(rank_candidates(candidates.clone(), "lib", false, Tiebreak::Length, CaseMode::Smart, None).len(), 2);
+
+        // plain candidates have no name to restrict to
+        let candidates = Vec::from_iter(Candidate::collect("src/lib.rs\n".as_bytes(), b'\n', true, false).unwrap()
+            .into_iter().map(Candidate::with_filename_only));
+        assert_eq!(candidates[0].field, None);
+        assert_eq!(rank_candidates(candidates, "src", false, Tiebreak::Length, CaseMode::Smart, None).len(), 1);
+    }
+
+    #[test]
+    fn trailing_slash_names() {
+        let candidates = Candidate::collect("src/\na/src\nx/docs/.\nSrc/\nü/Src/\n".as_bytes(), b'\n', false, false).unwrap();
+        assert_eq!(Vec::from_iter(candidates.iter().map(|c| c.name_start)), [Some(0), Some(2), Some(2), Some(0), Some(3)]);
+
+        // the name is matched with precedence wherever it is
+        let rank = |case, query, index: usize| {
+            let mut candidate = candidates[index].clone();
+            assert!(Matcher::with_case(query, case).rank(&mut candidate));
+            (candidate.rank, candidate.ranges)
+        };
+        assert_eq!(rank(CaseMode::Smart, "src", 0).0, rank(CaseMode::Smart, "src", 1).0);
+        assert_eq!(rank(CaseMode::Smart, "src", 0).1, [Range::new(0, 3)]);
+        assert_eq!(rank(CaseMode::Smart, "docs", 2).1, [Range::new(2, 6)]);
+
+        // and ranks and highlights the same in either case mode
+        assert_eq!(rank(CaseMode::Respect, "Src", 3), rank(CaseMode::Ignore, "src", 3));
+        assert_eq!(rank(CaseMode::Respect, "Src", 3), rank(CaseMode::Smart, "src", 0));
+        assert_eq!(rank(CaseMode::Respect, "Src", 3).1, [Range::new(0, 3)]);
+        assert_eq!(rank(CaseMode::Ignore, "src", 4), (rank(CaseMode::Ignore, "src", 3).0, vec![Range::new(3, 6)]));
+    }
+
+    #[test]
+    fn display_reordered_fields() {
+        let candidates = Vec::from_iter(Candidate::collect("1 main src/lib.rs\n2 lib x".as_bytes(), b'\n', false, false).unwrap()
+            .into_iter().map(|c| c.with_display(&[Nth { first: 3, last: Some(3) }, Nth { first: 2, last: Some(2) }], None, false)));
+        assert_eq!(candidates[0].text(), "src/lib.rs main");
+        assert_eq!(candidates[0].path, "1 main src/lib.rs");
+        assert_eq!(candidates[1].text(), "x lib");
+
+        // highlights index the shown text, the original line is kept for printing
+        let ranked = rank_candidates(candidates, "lib", false, Tiebreak::Length, CaseMode::Smart, None);
+        assert_eq!(paths(&ranked), ["2 lib x", "1 main src/lib.rs"]);
+        assert_eq!(ranked[0].ranges, [Range::new(2, 5)]);
+        assert_eq!(ranked[1].ranges, [Range::new(4, 7)]);
     }
 }
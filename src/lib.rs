@@ -1,11 +1,10 @@
-#![feature(io_safety)]
 pub mod filter;
 pub mod ui;
 
-pub use filter::{Candidate, rank_candidates};
-pub use ui::Terminal;
+pub use filter::{Candidate, CaseMode, Matcher, Nth, Range, Tiebreak, rank_candidates, score};
+pub use ui::{Terminal, Renderer, Height, Layout, Action, Key, MouseButton, Selection};
 
 pub fn other_error<S: Into<String>>(simple_msg: S) -> std::io::Error {
-    std::io::Error::new(std::io::ErrorKind::Other, simple_msg.into())
+    std::io::Error::other(simple_msg.into())
 }
 
@@ -1,17 +1,91 @@
-#![feature(result_option_inspect)]
-use std::io::{Write, self, Read, BufReader, BufRead};
-use zf::other_error;
+use std::io::{self, Write, BufRead, BufReader, IsTerminal};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use zf::{other_error, Action, CaseMode, Height, Key, Layout, Nth, Selection, Tiebreak};
+use Apply::{Flag, Value};
 
-const HELP_STR: &'static str = r#"Usage: zf [options]
+const HELP_STR: &str = r#"Usage: zf [options]
 
     -f, --filter     Skip interactive use and filter using the given query
+    -q, --query      Start the interactive search with the given query
+        --query-file Read the query from the given file instead, without its trailing
+                     newline. to filter with it, give --filter an empty query
+    -d, --delimiter  Set the character separating input lines (default \n)
+        --read0      Read input delimited by NUL characters
+        --dedup      Leave out the lines already read before, keeping the first one
+        --ansi       Take the color escape sequences out of the input lines and draw them
+                     in their colors. what gets matched and printed is the plain text
+        --nth        Only match the given fields of each line, like 2, 2..4 or 3..
+        --with-nth   Show only the given comma separated fields, in that order, like
+                     2,1 or 3..,1. the whole line is still printed
+        --field-delimiter
+                     Set the character separating the fields for --nth and
+                     --with-nth (default runs of whitespace)
+        --print0     Terminate each output line with a NUL character
+        --print-query
+                     Print the final query before the selection
+        --select-1   Select the only match of the initial query without searching
+        --exit-0     Exit right away when the initial query matches nothing. neither
+                     applies with --stream
+        --no-matches-error
+                     Exit with status 1 when --filter matches nothing
+        --max-results
+                     Keep only the given number of best matches, which spares sorting
+                     the rest
+        --reverse    Print the matches of --filter worst first, unless -k is given
+        --with-score Print the rank of each match of --filter before its path, separated
+                     by a tab. lower ranks are better matches
+        --ansi-output
+                     Color the matched parts of the printed paths when stdout is a
+                     terminal
+        --json       Print the matches of --filter as a JSON array of objects with their
+                     path, rank and matched byte ranges (ends exclusive)
+        --bind       Bind comma separated keys to actions, like ctrl-j:select,ctrl-k:up.
+                     actions are up, down, page-up, page-down, first, last, left, right,
+                     home, end, word-left, word-right, backspace, delete, delete-word,
+                     delete-line, toggle-mark, previous-history, next-history, select,
+                     close and ignore
+        --expect     End the search when one of the comma separated keys is pressed,
+                     like ctrl-x or tab, and print its name before the selection
+        --input      Read candidates from the given file instead of stdin
+        --history    Keep the queries of finished searches in the given file. up at the
+                     top of the list recalls them, down goes back
+        --stream     Start searching while the candidates are still being read
+        --preview    Show the output of a command next to the candidates, {} is
+                     replaced by the selected path
+        --prompt     Set the prompt shown before the query (default "> ")
+        --layout     Draw the prompt on top with the list below it (default), at the
+                     bottom with the list growing upwards (reverse), or at the bottom
+                     with the list read from the top (reverse-list)
     -k, --keep-order Don't sort by rank and preserve order of lines read on stdin
+        --no-sort    Same as --keep-order
+        --tiebreak   Order candidates of equal rank by length, begin or index
+                     (default length)
     -l, --lines      Set the maximum number of result lines to show (default 10)
+        --height     Set the result lines as a number or a percentage of the terminal
+                     height like 40%, overrides --lines
+        --tabstop    Set the columns between the tab stops tabs are drawn up to (default 8)
+        --keep-right Show the end of paths too long for the terminal instead of the start
+        --alt-screen Draw on the alternate screen, leaving the scrollback untouched
+        --no-alt-screen
+                     Draw below the cursor in the current screen (default)
     -p, --plain      Disable filename match prioritization
+        --filename-only
+                     Match only the filename of each path, never the directories
+        --literal    Match the whole query as one fuzzy token, spaces included, instead
+                     of each space separated word on its own
+        --no-split   Same as --literal
+    -i, --ignore-case
+                     Always match case insensitively
+    -s, --case-sensitive
+                     Always match case sensitively (default is smart case)
     -v, --version    Show version information and exit
-    -h, --help       Display this help and exit"#;
+    -h, --help       Display this help and exit
 
-const VERSION_STR: &'static str = "0.5-dev";
+Long options taking a value also accept it after an =, like --lines=12. Default
+options can be set in the ZF_DEFAULT_OPTS environment variable"#;
+
+const VERSION_STR: &str = "0.5-dev";
 
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -20,9 +94,43 @@ pub struct Config {
     pub version: bool,
     pub skip_ui: bool,
     pub keep_order: bool,
+    pub tiebreak: Tiebreak,
+    pub layout: Layout,
     pub lines: usize,
+    pub height: Option<Height>,
     pub plain: bool,
+    pub filename_only: bool,
+    pub case: CaseMode,
+    pub literal: bool,
     pub query: String,
+    pub delimiter: u8,
+    pub ansi: bool,
+    pub dedup: bool,
+    pub nth: Option<Nth>,
+    pub with_nth: Vec<Nth>,
+    pub field_delimiter: Option<char>,
+    pub print0: bool,
+    pub print_query: bool,
+    pub json: bool,
+    pub ansi_output: bool,
+    pub with_score: bool,
+    pub debug_scores: bool,
+    pub reverse: bool,
+    pub max_results: Option<usize>,
+    pub no_matches_error: bool,
+    pub select_1: bool,
+    pub exit_0: bool,
+    pub expect: Vec<Key>,
+    pub bind: Vec<(Key, Action)>,
+    pub input: Option<PathBuf>,
+    pub history: Option<PathBuf>,
+    pub query_file: Option<PathBuf>,
+    pub prompt: String,
+    pub tabstop: usize,
+    pub keep_right: bool,
+    pub alt_screen: bool,
+    pub stream: bool,
+    pub preview: Option<String>,
 }
 
 impl Default for Config {
@@ -32,119 +140,653 @@ impl Default for Config {
             version: false,
             skip_ui: false,
             keep_order: false,
+            tiebreak: Tiebreak::Length,
+            layout: Layout::Default,
             lines: 10,
+            height: None,
             plain: false,
+            filename_only: false,
+            case: CaseMode::Smart,
+            literal: false,
             query: "".into(),
+            delimiter: b'\n',
+            ansi: false,
+            dedup: false,
+            nth: None,
+            with_nth: vec![],
+            field_delimiter: None,
+            print0: false,
+            print_query: false,
+            json: false,
+            ansi_output: false,
+            with_score: false,
+            debug_scores: false,
+            reverse: false,
+            max_results: None,
+            no_matches_error: false,
+            select_1: false,
+            exit_0: false,
+            expect: vec![],
+            bind: vec![],
+            input: None,
+            history: None,
+            query_file: None,
+            prompt: "> ".into(),
+            tabstop: zf::ui::TABSTOP,
+            keep_right: false,
+            alt_screen: false,
+            stream: false,
+            preview: None,
         }
     }
 }
 
 pub type AnyError = Box<dyn std::error::Error + 'static>;
 
+// what an option does to the config
+enum Apply {
+    Flag(fn(&mut Config)),
+    // with the value after the option. errors say what was expected instead
+    Value(fn(&mut Config, &str) -> Result<(), String>),
+}
+
+// an option of the command line, by its short and long name
+struct Opt {
+    short: Option<&'static str>,
+    long: &'static str,
+    apply: Apply,
+}
+
+impl Opt {
+    fn takes_value(&self) -> bool {
+        matches!(self.apply, Apply::Value(_))
+    }
+}
+
+// the error of a value that doesn't parse
+fn expects(what: &str, value: &str) -> String {
+    format!("expects {}, got '{}'", what, value)
+}
+
+// every option, see `Config::parse_from`
+const OPTIONS: &[Opt] = &[
+    Opt { short: Some("-h"), long: "--help", apply: Flag(|c| c.help = true) },
+    Opt { short: Some("-v"), long: "--version", apply: Flag(|c| c.version = true) },
+    Opt { short: Some("-k"), long: "--keep-order", apply: Flag(|c| c.keep_order = true) },
+    Opt { short: None, long: "--no-sort", apply: Flag(|c| c.keep_order = true) },
+    Opt { short: Some("-p"), long: "--plain", apply: Flag(|c| c.plain = true) },
+    Opt { short: None, long: "--filename-only", apply: Flag(|c| c.filename_only = true) },
+    Opt { short: Some("-i"), long: "--ignore-case", apply: Flag(|c| c.case = CaseMode::Ignore) },
+    Opt { short: Some("-s"), long: "--case-sensitive", apply: Flag(|c| c.case = CaseMode::Respect) },
+    Opt { short: None, long: "--literal", apply: Flag(|c| c.literal = true) },
+    Opt { short: None, long: "--no-split", apply: Flag(|c| c.literal = true) },
+    Opt { short: None, long: "--read0", apply: Flag(|c| c.delimiter = 0) },
+    Opt { short: None, long: "--ansi", apply: Flag(|c| c.ansi = true) },
+    Opt { short: None, long: "--dedup", apply: Flag(|c| c.dedup = true) },
+    Opt { short: None, long: "--print0", apply: Flag(|c| c.print0 = true) },
+    Opt { short: None, long: "--print-query", apply: Flag(|c| c.print_query = true) },
+    Opt { short: None, long: "--json", apply: Flag(|c| c.json = true) },
+    Opt { short: None, long: "--ansi-output", apply: Flag(|c| c.ansi_output = true) },
+    Opt { short: None, long: "--with-score", apply: Flag(|c| c.with_score = true) },
+    // not in the help, for bug reports about the order
+    Opt { short: None, long: "--debug-scores", apply: Flag(|c| c.debug_scores = true) },
+    Opt { short: None, long: "--reverse", apply: Flag(|c| c.reverse = true) },
+    Opt { short: None, long: "--no-matches-error", apply: Flag(|c| c.no_matches_error = true) },
+    Opt { short: None, long: "--select-1", apply: Flag(|c| c.select_1 = true) },
+    Opt { short: None, long: "--exit-0", apply: Flag(|c| c.exit_0 = true) },
+    Opt { short: None, long: "--keep-right", apply: Flag(|c| c.keep_right = true) },
+    Opt { short: None, long: "--alt-screen", apply: Flag(|c| c.alt_screen = true) },
+    Opt { short: None, long: "--no-alt-screen", apply: Flag(|c| c.alt_screen = false) },
+    Opt { short: None, long: "--stream", apply: Flag(|c| c.stream = true) },
+    Opt { short: None, long: "--prompt", apply: Value(|c, v| { c.prompt = v.to_owned(); Ok(()) }) },
+    Opt { short: None, long: "--preview", apply: Value(|c, v| { c.preview = Some(v.to_owned()); Ok(()) }) },
+    Opt { short: None, long: "--input", apply: Value(|c, v| { c.input = Some(v.into()); Ok(()) }) },
+    Opt { short: None, long: "--history", apply: Value(|c, v| { c.history = Some(v.into()); Ok(()) }) },
+    Opt { short: Some("-l"), long: "--lines", apply: Value(|c, v| {
+        c.lines = v.parse().ok().filter(|&lines| lines > 0).ok_or_else(|| expects("a number of lines above 0", v))?;
+        Ok(())
+    }) },
+    Opt { short: None, long: "--tabstop", apply: Value(|c, v| {
+        c.tabstop = v.parse().ok().filter(|&tabstop| tabstop > 0).ok_or_else(|| expects("a number of columns above 0", v))?;
+        Ok(())
+    }) },
+    Opt { short: None, long: "--max-results", apply: Value(|c, v| {
+        c.max_results = Some(v.parse().map_err(|_| expects("a number", v))?);
+        Ok(())
+    }) },
+    Opt { short: Some("-f"), long: "--filter", apply: Value(|c, v| { c.skip_ui = true; c.query = v.to_owned(); Ok(()) }) },
+    Opt { short: None, long: "--height", apply: Value(|c, v| {
+        c.height = Some(parse_height(v).ok_or_else(|| expects("a number of lines above 0 or a percentage up to 100%", v))?);
+        Ok(())
+    }) },
+    Opt { short: Some("-q"), long: "--query", apply: Value(|c, v| { c.query = v.to_owned(); Ok(()) }) },
+    Opt { short: None, long: "--query-file", apply: Value(|c, v| { c.query_file = Some(v.into()); Ok(()) }) },
+    Opt { short: None, long: "--expect", apply: Value(|c, v| {
+        c.expect = parse_keys(v).ok_or_else(|| expects("a comma separated list of keys", v))?;
+        Ok(())
+    }) },
+    Opt { short: None, long: "--bind", apply: Value(|c, v| {
+        c.bind.extend(parse_bindings(v).ok_or_else(|| expects("comma separated key:action pairs", v))?);
+        Ok(())
+    }) },
+    Opt { short: None, long: "--tiebreak", apply: Value(|c, v| {
+        c.tiebreak = parse_tiebreak(v).ok_or_else(|| expects("one of length, begin or index", v))?;
+        Ok(())
+    }) },
+    Opt { short: None, long: "--layout", apply: Value(|c, v| {
+        c.layout = parse_layout(v).ok_or_else(|| expects("one of default, reverse or reverse-list", v))?;
+        Ok(())
+    }) },
+    Opt { short: None, long: "--nth", apply: Value(|c, v| {
+        c.nth = Some(parse_nth(v).ok_or_else(|| expects("a field number or a range like 2..4", v))?);
+        Ok(())
+    }) },
+    Opt { short: None, long: "--with-nth", apply: Value(|c, v| {
+        c.with_nth = v.split(',').map(parse_nth).collect::<Option<_>>()
+            .ok_or_else(|| expects("comma separated field numbers or ranges like 2,1 or 3..", v))?;
+        Ok(())
+    }) },
+    Opt { short: None, long: "--field-delimiter", apply: Value(|c, v| {
+        c.field_delimiter = Some(parse_field_delimiter(v).ok_or_else(|| expects("a single character", v))?);
+        Ok(())
+    }) },
+    Opt { short: Some("-d"), long: "--delimiter", apply: Value(|c, v| {
+        c.delimiter = parse_delimiter(v).ok_or_else(|| expects("a single character", v))?;
+        Ok(())
+    }) },
+];
+
+// the option called `name`, by its short or long name
+fn option(name: &str) -> Option<&'static Opt> {
+    OPTIONS.iter().find(|opt| opt.long == name || opt.short == Some(name))
+}
+
+// `args` with each `--option=value` split into `--option value`. the values of options are
+// left as they are, even when they look like that, and so is `=` after the other options
+fn split_values<I: IntoIterator<Item = String>>(args: I) -> Vec<String> {
+    let mut split = vec![];
+    let mut is_value = false;
+    for arg in args {
+        let inline = arg.split_once('=')
+            .filter(|(name, _)| !is_value && name.starts_with("--") && option(name).is_some_and(Opt::takes_value));
+        if let Some((name, value)) = inline {
+            split.extend([name.to_owned(), value.to_owned()]);
+            is_value = false;
+        } else {
+            is_value = !is_value && option(&arg).is_some_and(Opt::takes_value);
+            split.push(arg);
+        }
+    }
+    split
+}
+
 impl Config {
     pub fn parse(args: &[String]) -> Result<Self, AnyError> {
-        let mut config = Config::default();
+        let default_opts = std::env::var("ZF_DEFAULT_OPTS").unwrap_or_default();
+        Self::parse_with_defaults(&default_opts, args)
+    }
 
-        let mut skip = false;
-        for idx in 1..args.len() {
-            if skip { skip = false; continue; }
+    /// parse the whitespace separated options in `default_opts` before `args`, so
+    /// the options given on the command line take precedence
+    pub fn parse_with_defaults(default_opts: &str, args: &[String]) -> Result<Self, AnyError> {
+        let opts = default_opts.split_whitespace().map(String::from);
+        Self::parse_from(opts.chain(args.iter().skip(1).cloned()))
+    }
 
-            match &*args[idx] {
-                "-h" | "--help" => {
-                    config.help = true;
-                    break;
-                },
-                "-v" | "--version" => {
-                    config.version = true;
-                    break;
-                },
-                "-k" | "--keep-order" => {
-                    config.keep_order = true;
-                },
-                "-p" | "--plain" => {
-                    config.plain = true;
-                },
-                "-l" | "--lines" => {
-                    if idx + 1 < args.len() {
-                        config.lines = args[idx+1].parse()?;
-                        skip = true;
-                        if config.lines == 0 {
-                            return Err(Box::new(other_error("InvalidCharacter")));
-                        }
-                    } else {
-                        return Err(Box::new(other_error(format!("option '{}' requires an argument\n{}", args[idx], HELP_STR))));
-                    }
-                },
-                "-f" | "--filter" => {
-                    config.skip_ui = true;
-                    if idx + 1 < args.len() {
-                        config.query = args[idx+1].clone();
-                        skip = true;
-                    } else {
-                        return Err(Box::new(other_error(format!("option '{}' requires an argument\n{}", args[idx], HELP_STR))));
-                    }
+    /// parse the options in `args`, which unlike `parse` don't start with the program name,
+    /// and without looking at `ZF_DEFAULT_OPTS`
+    pub fn parse_from<I: IntoIterator<Item = String>>(args: I) -> Result<Self, AnyError> {
+        let mut config = Config::default();
+
+        let mut args = split_values(args).into_iter();
+        while let Some(arg) = args.next() {
+            let Some(opt) = option(&arg) else {
+                return Err(Box::new(other_error(format!("unrecognized option '{}'\n{}", arg, HELP_STR))));
+            };
+            match opt.apply {
+                Flag(apply) => apply(&mut config),
+                Value(apply) => {
+                    let Some(value) = args.next() else {
+                        return Err(Box::new(other_error(format!("option '{}' requires an argument\n{}", arg, HELP_STR))));
+                    };
+                    apply(&mut config, &value).map_err(|e| other_error(format!("option '{}' {}\n{}", arg, e, HELP_STR)))?;
                 },
-                _ => {
-                    return Err(Box::new(other_error(format!(
-                        "unrecognized option '{}'\n{}", args[idx], HELP_STR
-                    ))));
-                }
             }
         }
 
+        if config.query_file.is_some() && !config.query.is_empty() {
+            return Err(Box::new(other_error(format!("option '--query-file' can't be given with a query\n{}", HELP_STR))));
+        }
+
         Ok(config)
     }
 }
 
-fn main() -> Result<(), AnyError>{
-    let mut stdout = std::io::stdout();
-    let mut stderr = std::io::stderr();
+// a number of lines, or a percentage of the terminal like `40%`
+fn parse_height(arg: &str) -> Option<Height> {
+    if let Some(percent) = arg.strip_suffix('%') {
+        let percent: usize = percent.parse().ok()?;
+        (percent <= 100).then_some(Height::Percent(percent))
+    } else {
+        let lines: usize = arg.parse().ok()?;
+        (lines > 0).then_some(Height::Lines(lines))
+    }
+}
 
-    let args = Vec::from_iter(std::env::args());
-    let config = Config::parse(&args).inspect_err(|e| eprintln!("{}", e))?;
-    println!("{:?}", config);
+// a single ascii character, or one of the escapes `\t`, `\n` and `\0`
+fn parse_delimiter(arg: &str) -> Option<u8> {
+    match arg {
+        "\\t" => Some(b'\t'),
+        "\\n" => Some(b'\n'),
+        "\\0" => Some(0),
+        _ if arg.len() == 1 && arg.is_ascii() => Some(arg.as_bytes()[0]),
+        _ => None,
+    }
+}
+
+// key:action pairs like `ctrl-j:select,ctrl-k:up`
+fn parse_bindings(arg: &str) -> Option<Vec<(Key, Action)>> {
+    arg.split(',').map(|binding| {
+        let (key, action) = binding.split_once(':')?;
+        Some((Key::from_name(key)?, Action::from_name(action)?))
+    }).collect()
+}
+
+// fields count from 1, `N..M` includes the field M and `N..` goes on to the last field
+fn parse_nth(arg: &str) -> Option<Nth> {
+    let (first, last) = match arg.split_once("..") {
+        Some((first, "")) => (first.parse().ok()?, None),
+        Some((first, last)) => (first.parse().ok()?, Some(last.parse().ok()?)),
+        None => {
+            let field = arg.parse().ok()?;
+            (field, Some(field))
+        },
+    };
+    if first == 0 || last.is_some_and(|last| last < first) { return None; }
+    Some(Nth { first, last })
+}
+
+// any single character, or the escape `\t`
+fn parse_field_delimiter(arg: &str) -> Option<char> {
+    if arg == "\\t" { return Some('\t'); }
+    let mut chars = arg.chars();
+    let delimiter = chars.next()?;
+    if chars.next().is_some() { return None; }
+    Some(delimiter)
+}
+
+fn parse_tiebreak(arg: &str) -> Option<Tiebreak> {
+    match arg {
+        "length" => Some(Tiebreak::Length),
+        "begin" => Some(Tiebreak::Begin),
+        "index" => Some(Tiebreak::Index),
+        _ => None,
+    }
+}
+
+fn parse_layout(arg: &str) -> Option<Layout> {
+    match arg {
+        "default" => Some(Layout::Default),
+        "reverse" => Some(Layout::Reverse),
+        "reverse-list" => Some(Layout::ReverseList),
+        _ => None,
+    }
+}
+
+// key names like `ctrl-x,tab`
+fn parse_keys(arg: &str) -> Option<Vec<Key>> {
+    arg.split(',').map(Key::from_name).collect()
+}
+
+// the file given with --input, or stdin
+fn open_input(config: &Config) -> io::Result<Box<dyn BufRead + Send>> {
+    if let Some(path) = &config.input {
+        let file = std::fs::File::open(path).map_err(|e| other_error(format!(
+            "unable to open '{}': {}", path.display(), e
+        )))?;
+        Ok(Box::new(BufReader::new(file)))
+    } else {
+        Ok(Box::new(BufReader::new(std::io::stdin())))
+    }
+}
+
+// the query in the --query-file at `path`, without the newline ending the file
+fn read_query(path: &std::path::Path) -> io::Result<String> {
+    let content = std::fs::read_to_string(path).map_err(|e| other_error(format!(
+        "unable to read '{}': {}", path.display(), e
+    )))?;
+    let query = content.strip_suffix('\n').map_or(content.as_str(), |q| q.strip_suffix('\r').unwrap_or(q));
+    Ok(query.to_owned())
+}
+
+// every record of `input`, see `select_fields`
+fn collect<R: BufRead>(config: &Config, input: R) -> io::Result<Vec<zf::Candidate>> {
+    let candidates = zf::Candidate::collect(input, config.delimiter, config.plain, true)?;
+    let mut seen = HashSet::new();
+    let candidates = candidates.into_iter().map(|c| select_fields(config, c));
+    Ok(Vec::from_iter(candidates.filter(|c| first_seen(config, &mut seen, c))))
+}
+
+// whether `candidate` is the first with its path among the `seen` ones, always without --dedup
+fn first_seen(config: &Config, seen: &mut HashSet<String>, candidate: &zf::Candidate) -> bool {
+    !config.dedup || seen.insert(candidate.path.clone())
+}
+
+// show the fields given with --with-nth, and match the ones given with --nth among those,
+// or only the filename in them with --filename-only
+fn select_fields(config: &Config, mut candidate: zf::Candidate) -> zf::Candidate {
+    if config.ansi {
+        candidate = candidate.with_ansi(config.plain);
+    }
+    if !config.with_nth.is_empty() {
+        candidate = candidate.with_display(&config.with_nth, config.field_delimiter, config.plain);
+    }
+    if let Some(nth) = config.nth {
+        candidate = candidate.with_fields(nth, config.field_delimiter, config.plain);
+    }
+    if config.filename_only {
+        candidate = candidate.with_filename_only();
+    }
+    candidate
+}
+
+// the initial query, parsed with --literal and the case given
+fn matcher(config: &Config) -> zf::Matcher {
+    if config.literal {
+        zf::Matcher::literal(&config.query, config.case)
+    } else {
+        zf::Matcher::with_case(&config.query, config.case)
+    }
+}
+
+// the non-interactive path: rank everything read from `input` and write the matches.
+// returns how many matched
+fn filter<R: BufRead, W: Write>(config: &Config, input: R, mut out: W) -> io::Result<usize> {
+    let candidates = collect(config, input)?;
+    let mut ranked = zf::filter::rank_candidates_with(candidates, &matcher(config), config.keep_order, config.tiebreak, config.max_results);
+    // the worst matches first, ties included, while the input order stays as it is
+    if config.reverse && !config.keep_order {
+        ranked.reverse();
+    }
+
+    if config.debug_scores {
+        write_debug_scores(&ranked, io::stderr().lock())?;
+    }
+
+    let matched = ranked.len();
+    if config.json {
+        return write_json(&ranked, out).map(|_| matched);
+    }
+    if config.print_query {
+        write_line(config, &config.query, &mut out)?;
+    }
+    for candidate in ranked {
+        if config.with_score {
+            write!(out, "{:.SCORE_DECIMALS$}\t", candidate.rank)?;
+        }
+        write_path(config, &candidate, &mut out)?;
+    }
+    Ok(matched)
+}
+
+// the path of `candidate` as a line, with --ansi-output its matches colored. the ranges
+// are in the shown text, so paths shown differently with --with-nth stay plain
+fn write_path<W: Write>(config: &Config, candidate: &zf::Candidate, mut out: W) -> io::Result<()> {
+    if !config.ansi_output || candidate.display.is_some() {
+        return write_line(config, &candidate.path, out);
+    }
+    zf::Renderer::new(&mut out).highlighted(&candidate.path, &candidate.ranges)?;
+    write_line(config, "", out)
+}
+
+// the decimals of the ranks printed with --with-score
+const SCORE_DECIMALS: usize = 3;
+
+// each candidate's full rank and matched ranges, then its path, one per line
+fn write_debug_scores<W: Write>(candidates: &[zf::Candidate], mut out: W) -> io::Result<()> {
+    for candidate in candidates {
+        write!(out, "{}\t", candidate.rank)?;
+        for (idx, range) in candidate.ranges.iter().enumerate() {
+            if idx > 0 { out.write_all(b" ")?; }
+            write!(out, "[{}..{}]", range.start, range.end)?;
+        }
+        writeln!(out, "\t{}", candidate.path)?;
+    }
+    Ok(())
+}
+
+// the query with --print-query, then the key with --expect (empty for enter), then the paths
+fn write_selection<W: Write>(config: &Config, selection: &Selection, mut out: W) -> io::Result<()> {
+    if config.print_query {
+        write_line(config, &selection.query, &mut out)?;
+    }
+    if !config.expect.is_empty() {
+        let key = selection.key.map(|key| key.to_string()).unwrap_or_default();
+        write_line(config, &key, &mut out)?;
+    }
+    for candidate in &selection.candidates {
+        write_path(config, candidate, &mut out)?;
+    }
+    Ok(())
+}
+
+// the candidates as one JSON array, so paths with newlines or odd bytes can't be misread
+fn write_json<W: Write>(candidates: &[zf::Candidate], mut out: W) -> io::Result<()> {
+    out.write_all(b"[")?;
+    for (index, candidate) in candidates.iter().enumerate() {
+        if index > 0 {
+            out.write_all(b",")?;
+        }
+        out.write_all(b"{\"path\":")?;
+        write_json_string(&candidate.path, &mut out)?;
+        // JSON has no infinity or NaN
+        if candidate.rank.is_finite() {
+            write!(out, ",\"rank\":{}", candidate.rank)?;
+        } else {
+            out.write_all(b",\"rank\":null")?;
+        }
+        out.write_all(b",\"ranges\":[")?;
+        for (index, range) in candidate.ranges.iter().enumerate() {
+            if index > 0 {
+                out.write_all(b",")?;
+            }
+            write!(out, "[{},{}]", range.start, range.end)?;
+        }
+        out.write_all(b"]}")?;
+    }
+    out.write_all(b"]\n")
+}
 
+fn write_json_string<W: Write>(s: &str, mut out: W) -> io::Result<()> {
+    out.write_all(b"\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => out.write_all(b"\\\"")?,
+            '\\' => out.write_all(b"\\\\")?,
+            '\n' => out.write_all(b"\\n")?,
+            '\r' => out.write_all(b"\\r")?,
+            '\t' => out.write_all(b"\\t")?,
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+            c => write!(out, "{}", c)?,
+        }
+    }
+    out.write_all(b"\"")
+}
+
+fn write_line<W: Write>(config: &Config, line: &str, mut out: W) -> io::Result<()> {
+    out.write_all(line.as_bytes())?;
+    out.write_all(if config.print0 { b"\0" } else { b"\n" })
+}
+
+// the help or the version, printed instead of searching. help wins when both are asked for
+fn info_text(config: &Config) -> Option<&'static str> {
     if config.help {
-        write!(stdout, "{}", HELP_STR)?;
+        Some(HELP_STR)
     } else if config.version {
-        write!(stdout, "{}", VERSION_STR)?;
+        Some(VERSION_STR)
     } else {
-        let candidates = zf::Candidate::collect(BufReader::new(std::io::stdin()), b'\n', config.plain);
-        if candidates.len() > 0 {
-            if config.skip_ui {
-                for candidate in zf::rank_candidates(candidates, &config.query, config.keep_order) {
-                    println!("{}", candidate.path);
-                }
-            } else {
-                if let Ok(select)  = {
-                    let mut terminal = zf::Terminal::new(candidates.len().min(config.lines))?;
-                    let r = terminal.run(candidates, config.keep_order);
-                    // terminal.clean_up()?;
-                    r
-                } {
-                    println!("{}", select);
-                } else {
-                    println!("fail to get select");
-                }
-
-                // if let Ok(select) = terminal.run(candidates, config.keep_order) {
-                //     // println!("{}", select.path);
-                // } else {
-                //     std::process::exit(1);
-                // }
+        None
+    }
+}
+
+fn main() -> Result<(), AnyError>{
+    let mut stdout = std::io::stdout();
+
+    let args = Vec::from_iter(std::env::args());
+    let mut config = Config::parse(&args).inspect_err(|e| eprintln!("{}", e))?;
+    if let Some(path) = &config.query_file {
+        config.query = read_query(path).inspect_err(|e| eprintln!("{}", e))?;
+    }
+    // colors would end up in the files and pipes read by other programs
+    config.ansi_output &= stdout.is_terminal();
+
+    if let Some(text) = info_text(&config) {
+        write!(stdout, "{}", text)?;
+    } else if config.skip_ui {
+        let matched = open_input(&config)
+            .and_then(|input| filter(&config, input, stdout.lock()))
+            .inspect_err(|e| eprintln!("{}", e))?;
+        if matched == 0 && config.no_matches_error {
+            stdout.flush()?;
+            std::process::exit(1);
+        }
+    } else if config.stream {
+        let input = open_input(&config).inspect_err(|e| eprintln!("{}", e))?;
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let reader_config = config.clone();
+        // reads until the input ends, or until the search is over and nobody receives
+        std::thread::spawn(move || {
+            let config = reader_config;
+            let mut seen = HashSet::new();
+            for candidate in zf::Candidate::stream(input, config.delimiter, config.plain, true) {
+                let candidate = candidate.map(|c| select_fields(&config, c));
+                if candidate.as_ref().is_ok_and(|c| !first_seen(&config, &mut seen, c)) { continue; }
+                if sender.send(candidate).is_err() { break; }
             }
+        });
+
+        let history = load_history(config.history.as_deref()).inspect_err(|e| eprintln!("{}", e))?;
+        // the number of candidates is unknown up front
+        let height = config.height.unwrap_or(Height::Lines(config.lines));
+        let selected = {
+            let mut terminal = terminal(&config, height, history).inspect_err(|e| eprintln!("{}", e))?;
+            terminal.run_streaming(receiver, config.keep_order, config.tiebreak, &config.query)
+                .inspect_err(|e| eprintln!("{}", e))?
+        };
+        write_selected(&config, selected)?;
+    } else {
+        let candidates = open_input(&config)
+            .and_then(|input| collect(&config, input))
+            .inspect_err(|e| eprintln!("{}", e))?;
+        let history = load_history(config.history.as_deref()).inspect_err(|e| eprintln!("{}", e))?;
+        if let Some(selected) = resolve_early(&config, &candidates) {
+            write_selected(&config, selected)?;
+        } else if !candidates.is_empty() {
+            let selected = {
+                let height = match config.height {
+                    Some(percent @ Height::Percent(_)) => percent,
+                    Some(Height::Lines(lines)) => Height::Lines(candidates.len().min(lines)),
+                    None => Height::Lines(candidates.len().min(config.lines)),
+                };
+                // the terminal is dropped, and the drawn lines erased, before printing
+                let mut terminal = terminal(&config, height, history).inspect_err(|e| eprintln!("{}", e))?;
+                terminal.run(&candidates, config.keep_order, config.tiebreak, &config.query)?
+            };
+            write_selected(&config, selected)?;
         }
 
     }
     Ok(())
 }
 
+// the terminal to search in, set up with every option from `config`. without a controlling
+// terminal keys can only come from stdin when the candidates don't
+fn terminal(config: &Config, height: Height, history: Vec<String>) -> io::Result<zf::Terminal> {
+    let mut terminal = match zf::Terminal::new(height, config.prompt.clone(), config.keep_right, config.alt_screen) {
+        Ok(terminal) => terminal,
+        Err(e) if e.kind() != io::ErrorKind::NotConnected => return Err(e),
+        Err(_) if config.input.is_some() => zf::Terminal::from_stdin(height, config.prompt.clone(), config.keep_right, config.alt_screen)?,
+        Err(_) => return Err(other_error("no controlling terminal; use --filter")),
+    };
+    terminal.set_preview(config.preview.clone());
+    terminal.set_expect(config.expect.clone());
+    terminal.set_bindings(config.bind.clone());
+    terminal.set_case(config.case);
+    terminal.set_literal(config.literal);
+    terminal.set_max_results(config.max_results);
+    terminal.set_history(history);
+    terminal.set_layout(config.layout);
+    terminal.set_tabstop(config.tabstop);
+    Ok(terminal)
+}
+
+// with --select-1 and --exit-0, what the search ends with when the initial query leaves
+// a single match or none, without showing the terminal. `None` when there is a search
+fn resolve_early(config: &Config, candidates: &[zf::Candidate]) -> Option<Option<Selection>> {
+    if !config.select_1 && !config.exit_0 { return None; }
+
+    // to tell apart a single match from several
+    let scored = zf::filter::score_candidates_with(candidates, &matcher(config), config.keep_order, config.tiebreak, Some(2));
+    match scored.as_slice() {
+        [] if config.exit_0 => Some(None),
+        [only] if config.select_1 => {
+            let mut candidate = candidates[only.index].clone();
+            candidate.rank = only.rank;
+            candidate.ranges = only.ranges.clone();
+            Some(Some(Selection { query: config.query.clone(), key: None, candidates: vec![candidate] }))
+        },
+        _ => None,
+    }
+}
+
+// print what was selected, or exit with an error when the search was aborted
+fn write_selected(config: &Config, selection: Option<Selection>) -> io::Result<()> {
+    let Some(selection) = selection else { std::process::exit(1); };
+    if let Some(path) = &config.history {
+        save_history(path, &selection.query).inspect_err(|e| eprintln!("{}", e))?;
+    }
+    write_selection(config, &selection, std::io::stdout().lock())
+}
+
+// the most queries kept in the --history file
+const HISTORY_SIZE: usize = 1000;
+
+// the queries in the --history file at `path`, oldest first. a file that doesn't exist yet,
+// or no file at all, is empty
+fn load_history(path: Option<&Path>) -> io::Result<Vec<String>> {
+    let Some(path) = path else { return Ok(vec![]); };
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(other_error(format!("unable to read '{}': {}", path.display(), e))),
+    };
+    let mut history = Vec::from_iter(content.lines().filter(|line| !line.is_empty()).map(String::from));
+    history.drain(..history.len().saturating_sub(HISTORY_SIZE));
+    Ok(history)
+}
+
+// add `query` to the end of the history at `path`, unless it is empty or repeats the last one
+fn save_history(path: &Path, query: &str) -> io::Result<()> {
+    let mut history = load_history(Some(path))?;
+    if query.is_empty() || history.last().is_some_and(|last| last == query) {
+        return Ok(());
+    }
+    history.push(query.to_owned());
+    history.drain(..history.len().saturating_sub(HISTORY_SIZE));
+
+    let mut content = history.join("\n");
+    content.push('\n');
+    std::fs::write(path, content).map_err(|e| other_error(format!(
+        "unable to write '{}': {}", path.display(), e
+    )))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     fn check_eq_config(args: &[&'static str], config: Config) {
-        let out = Config::parse(&Vec::from_iter(args.into_iter().map(|&s| s.to_owned()))).expect(
+        let out = Config::parse_from(args[1..].iter().map(|&s| s.to_owned())).expect(
             "Expect an Ok(...) not Err(...)"
         );
         assert_eq!(out, config);
@@ -156,11 +798,70 @@ mod tests {
             (vec!["zf"], Config::default()),
             (vec!["zf", "--help"], Config { help: true ,..Config::default()}),
             (vec!["zf", "--version"], Config { version: true ,..Config::default()}),
-            (vec!["zf", "-v", "-h"], Config { version: true, help: false,..Config::default()}),
+            (vec!["zf", "-v", "-h"], Config { version: true, help: true,..Config::default()}),
+            (vec!["zf", "-h", "-k"], Config { help: true, keep_order: true,..Config::default()}),
             (vec!["zf", "-f", "query"], Config { skip_ui: true, query: "query".into(), help: false,..Config::default()}),
             (vec!["zf", "-l", "12"], Config { lines: 12, help: false,..Config::default()}),
+            (vec!["zf", "--height", "40%"], Config { height: Some(Height::Percent(40)),..Config::default()}),
+            (vec!["zf", "--height", "100%"], Config { height: Some(Height::Percent(100)),..Config::default()}),
+            (vec!["zf", "--height", "12"], Config { height: Some(Height::Lines(12)),..Config::default()}),
             (vec!["zf", "-k", "-p"], Config { keep_order: true, plain: true,..Config::default()}),
             (vec!["zf", "--keep-order", "--plain"], Config { keep_order: true, plain: true,..Config::default()}),
+            (vec!["zf", "--no-sort"], Config { keep_order: true,..Config::default()}),
+            (vec!["zf", "--no-sort", "-k"], Config { keep_order: true,..Config::default()}),
+            (vec!["zf", "--filename-only"], Config { filename_only: true,..Config::default()}),
+            (vec!["zf", "-d", "\\t"], Config { delimiter: b'\t',..Config::default()}),
+            (vec!["zf", "-d", ","], Config { delimiter: b',',..Config::default()}),
+            (vec!["zf", "--read0"], Config { delimiter: 0,..Config::default()}),
+            (vec!["zf", "--keep-right"], Config { keep_right: true,..Config::default()}),
+            (vec!["zf", "--alt-screen"], Config { alt_screen: true,..Config::default()}),
+            (vec!["zf", "--stream"], Config { stream: true,..Config::default()}),
+            (vec!["zf", "--preview", "cat {}"], Config { preview: Some("cat {}".into()),..Config::default()}),
+            (vec!["zf", "--alt-screen", "--no-alt-screen"], Config { alt_screen: false,..Config::default()}),
+            (vec!["zf", "--tiebreak", "begin"], Config { tiebreak: Tiebreak::Begin,..Config::default()}),
+            (vec!["zf", "--layout", "default"], Config::default()),
+            (vec!["zf", "--layout", "reverse"], Config { layout: Layout::Reverse,..Config::default()}),
+            (vec!["zf", "--layout", "reverse-list"], Config { layout: Layout::ReverseList,..Config::default()}),
+            (vec!["zf", "--tiebreak", "index", "-k"], Config { tiebreak: Tiebreak::Index, keep_order: true,..Config::default()}),
+            (vec!["zf", "-i"], Config { case: CaseMode::Ignore,..Config::default()}),
+            (vec!["zf", "--case-sensitive"], Config { case: CaseMode::Respect,..Config::default()}),
+            (vec!["zf", "-s", "--ignore-case"], Config { case: CaseMode::Ignore,..Config::default()}),
+            (vec!["zf", "-i", "-s", "-f", "foo"], Config { case: CaseMode::Respect, skip_ui: true, query: "foo".into(),..Config::default()}),
+            (vec!["zf", "--nth", "2"], Config { nth: Some(Nth { first: 2, last: Some(2) }),..Config::default()}),
+            (vec!["zf", "--nth", "2..4"], Config { nth: Some(Nth { first: 2, last: Some(4) }),..Config::default()}),
+            (vec!["zf", "--nth", "3.."], Config { nth: Some(Nth { first: 3, last: None }),..Config::default()}),
+            (vec!["zf", "--with-nth", "2,1"], Config { with_nth: vec![Nth { first: 2, last: Some(2) }, Nth { first: 1, last: Some(1) }],..Config::default()}),
+            (vec!["zf", "--with-nth", "3..,1"], Config { with_nth: vec![Nth { first: 3, last: None }, Nth { first: 1, last: Some(1) }],..Config::default()}),
+            (vec!["zf", "--field-delimiter", ":"], Config { field_delimiter: Some(':'),..Config::default()}),
+            (vec!["zf", "--field-delimiter", "\\t", "-d", ","], Config { field_delimiter: Some('\t'), delimiter: b',',..Config::default()}),
+            (vec!["zf", "--bind", "ctrl-j:select,ctrl-k:up"], Config { bind: vec![(Key::Control(0x0a), Action::Select), (Key::Control(0x0b), Action::LineUp)],..Config::default()}),
+            (vec!["zf", "--bind", "tab:down", "--bind", "esc:ignore"], Config { bind: vec![(Key::Control(0x09), Action::LineDown), (Key::Esc, Action::Pass)],..Config::default()}),
+            (vec!["zf", "--ansi-output", "-f", "rs"], Config { ansi_output: true, skip_ui: true, query: "rs".into(),..Config::default()}),
+            (vec!["zf", "--ansi", "--with-nth", "2"], Config { ansi: true, with_nth: vec![Nth { first: 2, last: Some(2) }],..Config::default()}),
+            (vec!["zf", "--tabstop", "4"], Config { tabstop: 4,..Config::default()}),
+            (vec!["zf", "--dedup", "-k"], Config { dedup: true, keep_order: true,..Config::default()}),
+            (vec!["zf", "--literal", "-f", "a b"], Config { literal: true, skip_ui: true, query: "a b".into(),..Config::default()}),
+            (vec!["zf", "--no-split"], Config { literal: true,..Config::default()}),
+            (vec!["zf", "--print-query"], Config { print_query: true,..Config::default()}),
+            (vec!["zf", "--max-results", "5"], Config { max_results: Some(5),..Config::default()}),
+            (vec!["zf", "--no-matches-error"], Config { no_matches_error: true,..Config::default()}),
+            (vec!["zf", "--select-1", "--exit-0"], Config { select_1: true, exit_0: true,..Config::default()}),
+            (vec!["zf", "--reverse"], Config { reverse: true,..Config::default()}),
+            (vec!["zf", "--with-score"], Config { with_score: true,..Config::default()}),
+            (vec!["zf", "--debug-scores"], Config { debug_scores: true,..Config::default()}),
+            (vec!["zf", "--json", "-f", "rs"], Config { json: true, skip_ui: true, query: "rs".into(),..Config::default()}),
+            (vec!["zf", "--expect", "ctrl-x"], Config { expect: vec![Key::Control(0x18)],..Config::default()}),
+            (vec!["zf", "--expect", "tab,enter"], Config { expect: vec![Key::Control(0x09), Key::Enter],..Config::default()}),
+            (vec!["zf", "--read0", "--print0"], Config { delimiter: 0, print0: true,..Config::default()}),
+            (vec!["zf", "--input", "files.txt"], Config { input: Some("files.txt".into()),..Config::default()}),
+            (vec!["zf", "--history", "h.txt"], Config { history: Some("h.txt".into()),..Config::default()}),
+            (vec!["zf", "--query-file", "q.txt"], Config { query_file: Some("q.txt".into()),..Config::default()}),
+            (vec!["zf", "-f", "", "--query-file", "q.txt"], Config { skip_ui: true, query_file: Some("q.txt".into()),..Config::default()}),
+            (vec!["zf", "--query", "foo"], Config { query: "foo".into(),..Config::default()}),
+            (vec!["zf", "-q", "foo", "-l", "3"], Config { query: "foo".into(), lines: 3,..Config::default()}),
+            (vec!["zf", "--prompt", "search❯ "], Config { prompt: "search❯ ".into(),..Config::default()}),
+            (vec!["zf", "--read0", "-f", "a"], Config { delimiter: 0, skip_ui: true, query: "a".into(),..Config::default()}),
+            (vec!["zf", "--delimiter", "\t", "-p"], Config { delimiter: b'\t', plain: true,..Config::default()}),
         ].into_iter() {
             check_eq_config(&args, out);
         }
@@ -170,8 +871,480 @@ mod tests {
             (vec!["zf", "asdf"]),
             (vec!["zf", "bad arg here", "--help"]),
             (vec!["zf", "--lines", "-10"]),
+            (vec!["zf", "-d", "abc"]),
+            (vec!["zf", "-d", ""]),
+            (vec!["zf", "-d", "é"]),
+            (vec!["zf", "--tiebreak"]),
+            (vec!["zf", "--max-results"]),
+            (vec!["zf", "--max-results", "-1"]),
+            (vec!["zf", "--preview"]),
+            (vec!["zf", "--expect"]),
+            (vec!["zf", "--bind"]),
+            (vec!["zf", "--bind", "ctrl-j"]),
+            (vec!["zf", "--bind", "ctrl-j:jump"]),
+            (vec!["zf", "--bind", "ctrl-j:select,"]),
+            (vec!["zf", "--bind", "hyper-j:select"]),
+            (vec!["zf", "--nth"]),
+            (vec!["zf", "--nth", "0"]),
+            (vec!["zf", "--nth", "4..2"]),
+            (vec!["zf", "--nth", "..2"]),
+            (vec!["zf", "--nth", "x"]),
+            (vec!["zf", "--with-nth"]),
+            (vec!["zf", "--with-nth", "2,"]),
+            (vec!["zf", "--with-nth", "2,0"]),
+            (vec!["zf", "--field-delimiter", "ab"]),
+            (vec!["zf", "--field-delimiter", ""]),
+            (vec!["zf", "--expect", "ctrl-x,"]),
+            (vec!["zf", "--expect", "hyper-x"]),
+            (vec!["zf", "--tiebreak", "rank"]),
+            (vec!["zf", "--layout"]),
+            (vec!["zf", "--tabstop"]),
+            (vec!["zf", "--tabstop", "0"]),
+            (vec!["zf", "--tabstop", "-2"]),
+            (vec!["zf", "--layout", "top"]),
+            (vec!["zf", "--delimiter"]),
+            (vec!["zf", "--input"]),
+            (vec!["zf", "--height"]),
+            (vec!["zf", "--prompt"]),
+            (vec!["zf", "--query"]),
+            (vec!["zf", "--query-file"]),
+            (vec!["zf", "-q", "foo", "--query-file", "q.txt"]),
+            (vec!["zf", "--query-file", "q.txt", "-f", "foo"]),
+            (vec!["zf", "--height", "%"]),
+            (vec!["zf", "--height", "abc%"]),
+            (vec!["zf", "--height", "101%"]),
+            (vec!["zf", "--height", "-4%"]),
+            (vec!["zf", "--height", "0"]),
         ].into_iter() {
-            assert!(Config::parse(&Vec::from_iter(args.into_iter().map(|s| s.to_owned()))).is_err());
+            assert!(Config::parse_from(args[1..].iter().map(|&s| s.to_owned())).is_err());
+        }
+    }
+
+    #[test]
+    fn help_and_version() {
+        let parse = |args: &[&str]| Config::parse_from(args.iter().map(|&s| s.to_owned()));
+
+        // both are recorded whatever the order, and help wins
+        for args in [["-v", "-h"], ["-h", "-v"], ["--help", "--version"]] {
+            let config = parse(&args).unwrap();
+            assert!(config.help && config.version);
+            assert_eq!(info_text(&config), Some(HELP_STR));
+        }
+        assert_eq!(info_text(&parse(&["-v", "-k"]).unwrap()), Some(VERSION_STR));
+        assert_eq!(info_text(&parse(&["-k"]).unwrap()), None);
+
+        // the arguments after them are still checked
+        assert!(parse(&["-h", "--bogus"]).is_err());
+        assert!(parse(&["-v", "--lines", "0"]).is_err());
+        assert!(parse(&["--version", "--query"]).is_err());
+    }
+
+    #[test]
+    fn options_round_trip() {
+        // a value each option with one accepts
+        let sample = |long| match long {
+            "--lines" | "--max-results" | "--height" | "--tabstop" => "7",
+            "--expect" => "ctrl-x",
+            "--bind" => "ctrl-j:down",
+            "--tiebreak" => "index",
+            "--layout" => "reverse",
+            "--nth" | "--with-nth" => "2",
+            "--field-delimiter" | "--delimiter" => ":",
+            _ => "x",
+        };
+        let parse = |args: &[&str]| Config::parse_from(args.iter().map(|&s| s.to_owned())).unwrap();
+
+        // no name is declared twice
+        let mut names = Vec::from_iter(OPTIONS.iter().flat_map(|opt| std::iter::once(opt.long).chain(opt.short)));
+        names.sort_unstable();
+        let count = names.len();
+        names.dedup();
+        assert_eq!(names.len(), count);
+
+        for opt in OPTIONS {
+            let config = if opt.takes_value() {
+                let value = sample(opt.long);
+                let config = parse(&[opt.long, value]);
+                assert_eq!(parse(&[&format!("{}={}", opt.long, value)]), config);
+                assert!(Config::parse_from([opt.long.to_owned()]).is_err(), "{}", opt.long);
+                config
+            } else {
+                parse(&[opt.long])
+            };
+            if let Some(short) = opt.short {
+                let args = if opt.takes_value() { vec![short, sample(opt.long)] } else { vec![short] };
+                assert_eq!(parse(&args), config, "{}", short);
+            }
+
+            // only the way back to the default does nothing on its own
+            assert_eq!(config == Config::default(), opt.long == "--no-alt-screen", "{}", opt.long);
+            if opt.long != "--debug-scores" {
+                assert!(HELP_STR.contains(opt.long), "{} isn't in the help", opt.long);
+            }
+        }
+    }
+
+    #[test]
+    fn parse_equals_values() {
+        let parse = |args: &[&str]| Config::parse_from(args.iter().map(|&s| s.to_owned()));
+
+        assert_eq!(parse(&["--lines=12"]).unwrap(), Config { lines: 12, ..Config::default() });
+        assert_eq!(parse(&["--filter=foo"]).unwrap(), Config { skip_ui: true, query: "foo".into(), ..Config::default() });
+        assert_eq!(parse(&["--query=a=b", "-k"]).unwrap(), Config { query: "a=b".into(), keep_order: true, ..Config::default() });
+        assert_eq!(parse(&["--prompt="]).unwrap(), Config { prompt: "".into(), ..Config::default() });
+        assert_eq!(parse(&["--tiebreak=index", "--lines", "3"]).unwrap(), Config { tiebreak: Tiebreak::Index, lines: 3, ..Config::default() });
+        // values that look like options are still values
+        assert_eq!(parse(&["--query", "--lines=2"]).unwrap(), Config { query: "--lines=2".into(), ..Config::default() });
+        assert_eq!(parse(&["-q", "--plain=x"]).unwrap(), Config { query: "--plain=x".into(), ..Config::default() });
+
+        assert!(parse(&["--lines="]).is_err());
+        assert!(parse(&["--lines"]).is_err());
+        assert!(parse(&["--lines=x"]).is_err());
+        assert!(parse(&["--plain=yes"]).is_err());
+        assert!(parse(&["--bogus=1"]).is_err());
+        // short options don't take `=`
+        assert!(parse(&["-l=12"]).is_err());
+    }
+
+    #[test]
+    fn parse_from_iterator() {
+        assert_eq!(Config::parse_from(Vec::new()).unwrap(), Config::default());
+        assert_eq!(Config::parse_from(std::iter::empty()).unwrap(), Config::default());
+
+        // there is no program name to skip
+        let args = ["-k", "--prompt", "$"].map(String::from);
+        assert_eq!(Config::parse_from(args).unwrap(), Config { keep_order: true, prompt: "$".into(), ..Config::default() });
+        assert_eq!(Config::parse_from(["-f".to_string(), "lib".to_string()]).unwrap(), Config { skip_ui: true, query: "lib".into(), ..Config::default() });
+        assert!(Config::parse_from(["zf".to_string()]).is_err());
+        assert!(Config::parse_from(["--prompt".to_string()]).is_err());
+
+        // the same as parsing with the program name in front
+        let args = ["zf", "-l", "5", "--tiebreak", "index"].map(String::from);
+        assert_eq!(Config::parse_with_defaults("", &args).unwrap(), Config::parse_from(args[1..].to_vec()).unwrap());
+    }
+
+    #[test]
+    fn parse_default_opts() {
+        let parse = |opts, args: &[&str]| {
+            Config::parse_with_defaults(opts, &Vec::from_iter(args.iter().map(|&s| s.to_owned())))
+        };
+
+        assert_eq!(parse("", &["zf"]).unwrap(), Config::default());
+        assert_eq!(
+            parse("  -k\t--prompt $ ", &["zf"]).unwrap(),
+            Config { keep_order: true, prompt: "$".into(), ..Config::default() },
+        );
+        assert_eq!(
+            parse("-l 5 --prompt $", &["zf", "-p", "-l", "20"]).unwrap(),
+            Config { lines: 20, plain: true, prompt: "$".into(), ..Config::default() },
+        );
+        assert_eq!(
+            parse("-f a", &["zf", "-f", "b"]).unwrap(),
+            Config { skip_ui: true, query: "b".into(), ..Config::default() },
+        );
+        assert!(parse("--bogus", &["zf"]).is_err());
+        assert!(parse("--prompt", &["zf"]).is_err());
+    }
+
+    fn check_filter(args: &[&'static str], input: &[u8]) -> Vec<u8> {
+        let config = Config::parse_from(args[1..].iter().map(|&s| s.to_owned())).unwrap();
+        let mut out = vec![];
+        filter(&config, input, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn filter_read0() {
+        let input = b"src/main.rs\0src/new\nline.rs\0README.md\0";
+        assert_eq!(check_filter(&["zf", "--read0", "-f", "line"], input), b"src/new\nline.rs\n");
+        assert_eq!(check_filter(&["zf", "--read0", "-f", "rs"], input), b"src/main.rs\nsrc/new\nline.rs\n");
+        assert_eq!(check_filter(&["zf", "-f", "line"], input), b"line.rs\x00README.md\x00\n");
+    }
+
+    #[test]
+    fn filter_print0() {
+        let input = b"src/main.rs\nsrc/lib.rs\nREADME.md\n";
+        assert_eq!(check_filter(&["zf", "--print0", "-f", "rs"], input), b"src/lib.rs\0src/main.rs\0");
+        assert_eq!(check_filter(&["zf", "-f", "rs"], input), b"src/lib.rs\nsrc/main.rs\n");
+
+        let input = b"src/new\nline.rs\0README.md\0";
+        assert_eq!(check_filter(&["zf", "--read0", "--print0", "-f", "line"], input), b"src/new\nline.rs\0");
+    }
+
+    #[test]
+    fn filter_max_results() {
+        let input = b"src/main.rs\nsrc/lib.rs\nREADME.md\nlib.rs\n";
+        assert_eq!(check_filter(&["zf", "--max-results", "2", "-f", "rs"], input), b"lib.rs\nsrc/lib.rs\n");
+        assert_eq!(check_filter(&["zf", "--max-results", "2", "-k", "-f", "rs"], input), b"src/main.rs\nsrc/lib.rs\n");
+        assert_eq!(check_filter(&["zf", "--max-results", "0", "-f", "rs"], input), b"");
+    }
+
+    #[test]
+    fn resolve_without_search() {
+        let candidates = collect(&Config::default(), "src/main.rs\nsrc/lib.rs\nREADME.md\n".as_bytes()).unwrap();
+        let resolve = |args: &[&str]| {
+            let config = Config::parse_from(args[1..].iter().map(|&s| s.to_owned())).unwrap();
+            resolve_early(&config, &candidates)
+        };
+
+        // no matches
+        assert_eq!(resolve(&["zf", "--exit-0", "-q", "xyz"]), Some(None));
+        assert_eq!(resolve(&["zf", "--select-1", "-q", "xyz"]), None);
+
+        // a single match
+        let selected = resolve(&["zf", "--select-1", "-q", "lib"]).unwrap().unwrap();
+        assert_eq!(selected.query, "lib");
+        assert_eq!(selected.key, None);
+        assert_eq!(Vec::from_iter(selected.candidates.iter().map(|c| c.path.as_str())), ["src/lib.rs"]);
+        assert_eq!(selected.candidates[0].ranges, [zf::Range::new(4, 7)]);
+        assert_eq!(resolve(&["zf", "--exit-0", "-q", "lib"]), None);
+
+        // several matches, or neither flag
+        assert_eq!(resolve(&["zf", "--select-1", "--exit-0", "-q", "rs"]), None);
+        assert_eq!(resolve(&["zf", "--select-1", "--exit-0"]), None);
+        assert_eq!(resolve(&["zf", "-q", "xyz"]), None);
+    }
+
+    #[test]
+    fn history_file() {
+        let path = std::env::temp_dir().join(format!("zf-history-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        // nothing yet
+        assert!(load_history(Some(&path)).unwrap().is_empty());
+        assert!(load_history(None).unwrap().is_empty());
+
+        for query in ["lib", "src", "", "src", "main"] {
+            save_history(&path, query).unwrap();
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "lib\nsrc\nmain\n");
+        assert_eq!(load_history(Some(&path)).unwrap(), ["lib", "src", "main"]);
+
+        // only the newest are kept
+        for index in 0..HISTORY_SIZE {
+            save_history(&path, &index.to_string()).unwrap();
+        }
+        let history = load_history(Some(&path)).unwrap();
+        assert_eq!(history.len(), HISTORY_SIZE);
+        assert_eq!(history[0], "0");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn filter_reverse() {
+        let input = b"src/main.rs\nsrc/lib.rs\nREADME.md\nlib.rs\n";
+        assert_eq!(check_filter(&["zf", "-f", "rs"], input), b"lib.rs\nsrc/lib.rs\nsrc/main.rs\n");
+        assert_eq!(check_filter(&["zf", "--reverse", "-f", "rs"], input), b"src/main.rs\nsrc/lib.rs\nlib.rs\n");
+
+        // the input order is kept either way
+        assert_eq!(check_filter(&["zf", "--reverse", "-k", "-f", "rs"], input), b"src/main.rs\nsrc/lib.rs\nlib.rs\n");
+        assert_eq!(check_filter(&["zf", "--reverse", "-k", "-f", "lib"], input), b"src/lib.rs\nlib.rs\n");
+    }
+
+    #[test]
+    fn filter_with_score() {
+        let input = b"src/main.rs\nsrc/lib.rs\nREADME.md\n";
+        let out = String::from_utf8(check_filter(&["zf", "--with-score", "-f", "lib"], input)).unwrap();
+        assert_eq!(out, "0.375\tsrc/lib.rs\n");
+
+        let out = String::from_utf8(check_filter(&["zf", "--with-score", "-k", "-f", "r"], input)).unwrap();
+        let lines = Vec::from_iter(out.lines().map(|line| line.split_once('\t').unwrap()));
+        assert_eq!(Vec::from_iter(lines.iter().map(|(_, path)| *path)), ["src/main.rs", "src/lib.rs", "README.md"]);
+        for (rank, _) in lines {
+            assert_eq!(rank.split_once('.').unwrap().1.len(), 3);
+            assert!(rank.parse::<f64>().unwrap() >= 0.0);
+        }
+    }
+
+    #[test]
+    fn debug_scores_format() {
+        let input = b"src/lib.rs\nREADME.md\nlib.rs\n";
+        let candidates = collect(&Config::default(), &input[..]).unwrap();
+        let ranked = zf::rank_candidates(candidates, "l s", false, Tiebreak::Length, CaseMode::Smart, None);
+        let mut out = vec![];
+        write_debug_scores(&ranked, &mut out).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let lines = Vec::from_iter(out.lines());
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("\t[0..1] [5..6]\tlib.rs"), "{}", lines[0]);
+        assert!(lines[1].ends_with("\t[4..5] [9..10]\tsrc/lib.rs"), "{}", lines[1]);
+        let rank: f64 = lines[0].split('\t').next().unwrap().parse().unwrap();
+        assert_eq!(rank, ranked[0].rank);
+
+        assert_eq!(check_filter(&["zf", "--debug-scores", "-f", "l s"], input), b"lib.rs\nsrc/lib.rs\n");
+    }
+
+    #[test]
+    fn filter_filename_only() {
+        let input = b"src/lib.rs\nlib/main.rs\nsrc/ui.rs\n";
+        assert_eq!(check_filter(&["zf", "--filename-only", "-f", "lib"], input), b"src/lib.rs\n");
+        assert_eq!(check_filter(&["zf", "--filename-only", "-f", "src"], input), b"");
+        assert_eq!(check_filter(&["zf", "-f", "src"], input), b"src/ui.rs\nsrc/lib.rs\n");
+    }
+
+    #[test]
+    fn filter_json() {
+        let input = "src/lib.rs\nsrc/\"q\"\tlib\n".as_bytes();
+        let out = String::from_utf8(check_filter(&["zf", "--json", "-f", "lib"], input)).unwrap();
+        assert_eq!(out, concat!(
+            r#"[{"path":"src/lib.rs","rank":0.375,"ranges":[[4,7]]},"#,
+            r#"{"path":"src/\"q\"\tlib","rank":1.1428571428571428,"ranges":[[8,11]]}]"#,
+            "\n",
+        ));
+    }
+
+    #[test]
+    fn filter_case_mode() {
+        let input = b"src/FOO.rs\nsrc/foo.rs\n";
+        assert_eq!(check_filter(&["zf", "-f", "foo"], input), b"src/FOO.rs\nsrc/foo.rs\n");
+        assert_eq!(check_filter(&["zf", "-s", "-f", "foo"], input), b"src/foo.rs\n");
+        assert_eq!(check_filter(&["zf", "-f", "FOO"], input), b"src/FOO.rs\n");
+        assert_eq!(check_filter(&["zf", "-i", "-f", "FOO"], input), b"src/FOO.rs\nsrc/foo.rs\n");
+    }
+
+    #[test]
+    fn filter_nth_field() {
+        let input = b"1 lib main\n2 main lib\n3 src/lib.rs x\n";
+        assert_eq!(check_filter(&["zf", "--nth", "2", "-f", "lib"], input), b"1 lib main\n3 src/lib.rs x\n");
+        assert_eq!(check_filter(&["zf", "--nth", "2", "-f", "main"], input), b"2 main lib\n");
+        assert_eq!(check_filter(&["zf", "--nth", "2..", "-f", "main"], input), b"2 main lib\n1 lib main\n");
+
+        let input = b"a:lib\nlib:b\n";
+        assert_eq!(check_filter(&["zf", "--nth", "2", "--field-delimiter", ":", "-f", "lib"], input), b"a:lib\n");
+    }
+
+    #[test]
+    fn filter_with_nth() {
+        // the shown fields are matched, the whole line is printed
+        let input = b"1 lib main\n2 main lib\n";
+        assert_eq!(check_filter(&["zf", "--with-nth", "3", "-f", "lib"], input), b"2 main lib\n");
+        assert_eq!(check_filter(&["zf", "--with-nth", "3,2", "--nth", "1", "-f", "main"], input), b"1 lib main\n");
+
+        let input = b"a:src/lib.rs:12\n";
+        let config = Config { with_nth: vec![Nth { first: 3, last: Some(3) }, Nth { first: 2, last: Some(2) }],
+            field_delimiter: Some(':'), ..Config::default() };
+        let candidates = collect(&config, &input[..]).unwrap();
+        assert_eq!(candidates[0].text(), "12:src/lib.rs");
+        assert_eq!(candidates[0].path, "a:src/lib.rs:12");
+    }
+
+    #[test]
+    fn filter_print_query() {
+        let input = b"src/main.rs\nsrc/lib.rs\nREADME.md\n";
+        assert_eq!(check_filter(&["zf", "--print-query", "-f", "rs"], input), b"rs\nsrc/lib.rs\nsrc/main.rs\n");
+        assert_eq!(check_filter(&["zf", "--print-query", "-f", "zzz"], input), b"zzz\n");
+        assert_eq!(check_filter(&["zf", "--print-query", "--print0", "-f", "md"], input), b"md\0README.md\0");
+    }
+
+    #[test]
+    fn selection_output_order() {
+        let candidates = zf::Candidate::collect(&b"src/main.rs\nsrc/lib.rs"[..], b'\n', false, false).unwrap();
+        let selection = Selection { query: "rs".into(), key: Some(Key::Control(0x18)), candidates };
+        let write = |config: &Config, selection: &Selection| {
+            let mut out = vec![];
+            write_selection(config, selection, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let config = Config::default();
+        assert_eq!(write(&config, &selection), "src/main.rs\nsrc/lib.rs\n");
+
+        let config = Config { print_query: true, ..Config::default() };
+        assert_eq!(write(&config, &selection), "rs\nsrc/main.rs\nsrc/lib.rs\n");
+
+        let config = Config { print_query: true, expect: vec![Key::Control(0x18)], ..Config::default() };
+        assert_eq!(write(&config, &selection), "rs\nctrl-x\nsrc/main.rs\nsrc/lib.rs\n");
+
+        // ended by enter, the key line is left empty
+        let config = Config { expect: vec![Key::Control(0x18)], ..Config::default() };
+        let selection = Selection { key: None, ..selection };
+        assert_eq!(write(&config, &selection), "\nsrc/main.rs\nsrc/lib.rs\n");
+    }
+
+    #[test]
+    fn filter_input_file() {
+        let path = std::env::temp_dir().join(format!("zf-input-{}.txt", std::process::id()));
+        std::fs::write(&path, "src/main.rs\nsrc/lib.rs\nREADME.md\n").unwrap();
+
+        let config = Config { input: Some(path.clone()), skip_ui: true, query: "md".into(), ..Config::default() };
+        let mut out = vec![];
+        filter(&config, open_input(&config).unwrap(), &mut out).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(out, b"README.md\n");
+
+        let config = Config { input: Some(path), ..Config::default() };
+        let err = open_input(&config).err().expect("the file was removed");
+        assert!(err.to_string().starts_with("unable to open"));
+    }
+
+    #[test]
+    fn query_file() {
+        let path = std::env::temp_dir().join(format!("zf-query-{}.txt", std::process::id()));
+        for (content, query) in [("main rs\n", "main rs"), ("main\r\n", "main"), ("lib", "lib"), ("a\nb\n\n", "a\nb\n"), ("", "")] {
+            std::fs::write(&path, content).unwrap();
+            assert_eq!(read_query(&path).unwrap(), query);
         }
+        std::fs::remove_file(&path).unwrap();
+
+        let err = read_query(&path).expect_err("the file was removed");
+        assert!(err.to_string().starts_with("unable to read"));
+    }
+
+    #[test]
+    fn ansi_output() {
+        let input = b"src/main.rs\nREADME.md\n";
+        assert_eq!(check_filter(&["zf", "--ansi-output", "-f", "main"], input), b"src/\x1b[36mmain\x1b[39m.rs\n");
+        assert_eq!(
+            check_filter(&["zf", "--ansi-output", "--with-score", "--print0", "-f", "md"], input),
+            b"0.778\tREADME.\x1b[36mmd\x1b[39m\0",
+        );
+        assert_eq!(check_filter(&["zf", "-f", "main"], input), b"src/main.rs\n");
+
+        let config = Config { ansi_output: true, ..Config::default() };
+        let mut candidate = collect(&config, &b"src/main.rs"[..]).unwrap().remove(0);
+        candidate.ranges = vec![zf::Range::new(0, 3), zf::Range::new(4, 8)];
+        let selection = Selection { query: "main".into(), key: None, candidates: vec![candidate.clone()] };
+        let mut out = vec![];
+        write_selection(&config, &selection, &mut out).unwrap();
+        assert_eq!(out, b"\x1b[36msrc\x1b[39m/\x1b[36mmain\x1b[39m.rs\n");
+
+        // the ranges are in the shown text rather than the path
+        candidate.display = Some("main".into());
+        let selection = Selection { candidates: vec![candidate], ..selection };
+        let mut out = vec![];
+        write_selection(&config, &selection, &mut out).unwrap();
+        assert_eq!(out, b"src/main.rs\n");
+    }
+
+    #[test]
+    fn filter_ansi() {
+        let input = b"\x1b[33mabc1234\x1b[m fix \x1b[32mmain.rs\x1b[m\n\x1b[33mdef5678\x1b[m docs\n";
+        assert_eq!(check_filter(&["zf", "--ansi", "-f", "fix"], input), b"abc1234 fix main.rs\n");
+        // the escapes are neither matched nor printed
+        assert_eq!(check_filter(&["zf", "--ansi", "-f", "33m"], input), b"");
+        assert_eq!(check_filter(&["zf", "-f", "33m"], input).len(), input.len());
+        assert_eq!(check_filter(&["zf", "--ansi", "--with-nth", "2", "-f", "docs"], input), b"def5678 docs\n");
+    }
+
+    #[test]
+    fn filter_dedup() {
+        let input = b"src/lib.rs\nREADME.md\nsrc/lib.rs\nsrc/ui.rs\nREADME.md\n";
+        assert_eq!(check_filter(&["zf", "--dedup", "-k", "-f", ""], input), b"src/lib.rs\nREADME.md\nsrc/ui.rs\n");
+        assert_eq!(check_filter(&["zf", "--dedup", "-f", "rs"], input), b"src/ui.rs\nsrc/lib.rs\n");
+        assert_eq!(check_filter(&["zf", "-k", "-f", ""], input), input);
+
+        // the same once the escapes are taken out
+        let input = b"\x1b[31msrc/lib.rs\x1b[m\nsrc/lib.rs\n";
+        assert_eq!(check_filter(&["zf", "--dedup", "--ansi", "-f", "lib"], input), b"src/lib.rs\n");
+        assert_eq!(check_filter(&["zf", "--dedup", "-f", "lib"], input).len(), input.len());
+    }
+
+    #[test]
+    fn filter_literal() {
+        let input = b"bar/foo\nfoo barista\n";
+        assert_eq!(check_filter(&["zf", "--literal", "-f", "foo bar"], input), b"foo barista\n");
+        assert_eq!(check_filter(&["zf", "-f", "foo bar"], input), b"foo barista\nbar/foo\n");
+        assert_eq!(check_filter(&["zf", "--no-split", "-k", "-f", "o b"], input), b"foo barista\n");
     }
 }
@@ -1,71 +1,184 @@
+use std::fmt;
 use std::io::{self, BufWriter, Write, Read};
-use std::os::unix::prelude::{AsRawFd, RawFd, FromRawFd, OwnedFd};
+use std::os::unix::prelude::{AsRawFd, RawFd, OwnedFd};
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::time::{Duration, Instant};
 
 
-use crate::{Candidate, other_error};
-use crate::filter::{self, Range};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+use crate::Candidate;
+use crate::filter::{self, CaseMode, Matcher, Range, Scored, Sgr, Tiebreak};
 
 #[derive(Debug, Clone, Copy)]
 pub enum Attributes {
     Reset,
     Reverse,
     FgCyan,
+    FgBrightYellow,
     FgDefault,
+    /// a color from the 256-color palette
+    Fg256(u8),
+    /// a 24-bit truecolor
+    FgRgb(u8, u8, u8),
 }
 
 impl Attributes {
-    pub fn tou8(&self) -> u8 {
+    /// the SGR parameter list, without the leading CSI and the final `m`
+    pub fn params(&self) -> String {
         use Attributes::*;
 
-        match self {
-            Reset     => 0,
-            Reverse   => 7,
-            FgCyan    => 36,
-            FgDefault => 39,
+        match *self {
+            Reset          => "0".to_string(),
+            Reverse        => "7".to_string(),
+            FgCyan         => "36".to_string(),
+            FgBrightYellow => "93".to_string(),
+            FgDefault      => "39".to_string(),
+            Fg256(n)       => format!("38;5;{}", n),
+            FgRgb(r, g, b) => format!("38;2;{};{};{}", r, g, b),
         }
     }
 }
 
-pub struct Terminal {
-    owned_fd: OwnedFd,
-    reader: std::fs::File,
-    writer: BufWriter<std::fs::File>,
+/// a text to draw with the colors it came with, see `Candidate::with_ansi`
+#[derive(Debug, Clone, Copy)]
+pub struct Colored<'a> {
+    pub text: &'a str,
+    pub colors: &'a [Sgr],
+}
+
+impl<'a> From<&'a str> for Colored<'a> {
+    fn from(text: &'a str) -> Self {
+        Self { text, colors: &[] }
+    }
+}
+
+/// how many rows to draw candidates in
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Height {
+    Lines(usize),
+    /// a percentage of the window's rows
+    Percent(usize),
+}
+
+impl Height {
+    /// the rows to draw in a window that is `window_rows` tall. at least one, even when
+    /// the window has no room left next to the prompt
+    pub fn rows(&self, window_rows: usize) -> usize {
+        let most = window_rows.saturating_sub(1).max(1);
+        match *self {
+            Height::Lines(lines) => lines.clamp(1, most),
+            Height::Percent(percent) => (window_rows * percent / 100).clamp(1, most),
+        }
+    }
+}
+
+/// where the prompt is drawn and which way the list grows from it
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum Layout {
+    /// the prompt on top, with the best match right below it
+    #[default]
+    Default,
+    /// the prompt at the bottom, with the best match right above it and the list
+    /// growing upwards
+    Reverse,
+    /// the prompt at the bottom, with the list still read from the top
+    ReverseList,
+}
+
+impl Layout {
+    fn prompt_on_top(self) -> bool {
+        self == Layout::Default
+    }
+}
 
+// the tty drawn on, put back into its original mode when dropped
+struct Tty {
+    owned_fd: OwnedFd,
     term: termios::Termios,
     raw_term: termios::Termios,
-    max_height: usize,
+}
+
+impl Drop for Tty {
+    fn drop(&mut self) {
+        termios::tcsetattr(self.owned_fd.as_raw_fd(), termios::TCSANOW, &self.term).expect("fail to reset optons");
+    }
+}
+
+/// reads keys from `R` and draws to `W`. the defaults are what `new` hands out; any other
+/// reader and writer, like a `Cursor` and a `Vec<u8>` in tests, go through `headless`
+pub struct Terminal<R: Read = Box<dyn Read>, W: Write = Box<dyn Write>> {
+    // `None` without a tty, when keys come from any reader and the window has a fixed size
+    tty: Option<Tty>,
+    reader: R,
+    renderer: Renderer<BufWriter<W>>,
+    nodelay: bool,
+    // the reader of a terminal without a tty ran out of keys
+    closed: bool,
+
+    max_height: Height,
     height: usize,
+    prompt: String,
+    keep_right: bool,
+    alt_screen: bool,
+    timeout: Option<Duration>,
+    preview: Option<String>,
+    // the preview output for the candidate at `previewed`
+    preview_lines: Vec<String>,
+    previewed: Option<usize>,
+    // keys that end the search like enter does, and are reported back
+    expect: Vec<Key>,
+    bindings: HashMap<Key, Action>,
+    case: CaseMode,
+    literal: bool,
+    tabstop: usize,
+    max_results: Option<usize>,
+    history: Vec<String>,
+    layout: Layout,
+    // the screen row of the prompt as the tty reported it, to tell which candidate was clicked
+    prompt_row: Option<usize>,
+    // keys read ahead, like the characters of a paste
+    pending: VecDeque<Key>,
+    // reused between redraws
+    frame: Vec<u8>,
 }
 
-impl Drop for Terminal {
+impl<R: Read, W: Write> Drop for Terminal<R, W> {
+    // every exit path out of `run` ends here, so this is where the drawn lines
+    // get erased before the tty is handed back to the shell
     fn drop(&mut self) {
-        let _ = termios::tcsetattr(self.rawfd(), termios::TCSANOW, &self.term).expect("fail to reset optons");
-        println!("finish closing file...");
+        let _ = self.clean_up();
+        let _ = self.renderer.leave_screen(self.alt_screen)
+            .and_then(|_| self.renderer.bracketed_paste(false))
+            .and_then(|_| self.renderer.mouse(false))
+            .and_then(|_| self.renderer.flush());
     }
 }
 
 impl Terminal {
-    fn rawfd(&self) -> RawFd {
-        self.owned_fd.as_raw_fd()
+    /// with `keep_right` paths too long for the window show their end rather than their start.
+    /// with `alt_screen` drawing happens on the alternate screen, leaving the scrollback alone.
+    /// fails with `ErrorKind::NotConnected` when there is no controlling terminal to open
+    pub fn new(max_height: Height, prompt: String, keep_right: bool, alt_screen: bool) -> io::Result<Self> {
+        let tty = std::fs::OpenOptions::new().read(true).write(true).open("/dev/tty")
+            .map_err(|e| io::Error::new(io::ErrorKind::NotConnected, e))?;
+        Self::with_fd(OwnedFd::from(tty), max_height, prompt, keep_right, alt_screen)
     }
 
-    pub fn clean_up(&mut self) -> io::Result<()> {
-        for _ in 0..self.height {
-            self.clean_line()?;
-            self.cursor_down(1)?;
-        }
-        self.clear_line()?;
-        self.cursor_up(self.height)?;
-        self.writer.flush()?;
-
-        Ok(())
+    /// same as `new` without a controlling terminal, as in CI or containers: keys are read from
+    /// stdin and drawing goes to stderr, see `headless`. only usable when nothing else reads stdin
+    pub fn from_stdin(max_height: Height, prompt: String, keep_right: bool, alt_screen: bool) -> io::Result<Self> {
+        Self::headless(Box::new(io::stdin()), Box::new(io::stderr()), max_height, prompt, keep_right, alt_screen)
     }
 
-    pub fn new(max_height: usize) -> io::Result<Self> {
-        let owned_fd = OwnedFd::from(std::fs::OpenOptions::new().read(true).write(true).open("/dev/tty")?);
+    // same as `new`, drawing on the terminal at `owned_fd`
+    fn with_fd(owned_fd: OwnedFd, max_height: Height, prompt: String, keep_right: bool, alt_screen: bool) -> io::Result<Self> {
         let fd = owned_fd.as_raw_fd();
-        let writer = BufWriter::new(unsafe { std::fs::File::from_raw_fd(fd) });
-        let reader = unsafe { std::fs::File::from_raw_fd(fd) };
+        // each handle owns a duplicate, so every descriptor is closed exactly once
+        let writer: Box<dyn Write> = Box::new(std::fs::File::from(owned_fd.try_clone()?));
+        let reader: Box<dyn Read> = Box::new(std::fs::File::from(owned_fd.try_clone()?));
 
         let term = termios::Termios::from_fd(fd)?;
         let mut raw_term = term;
@@ -74,241 +187,376 @@ impl Terminal {
         raw_term.c_lflag &= !(termios::ICANON | termios::ECHO | termios::ISIG);
 
         termios::tcsetattr(fd, termios::TCSANOW, &raw_term)?;
+        let tty = Tty { owned_fd, term, raw_term };
+        Self::with_backend(Some(tty), reader, writer, max_height, prompt, keep_right, alt_screen)
+    }
+}
+
+impl<R: Read, W: Write> Terminal<R, W> {
+    fn rawfd(&self) -> Option<RawFd> {
+        self.tty.as_ref().map(|tty| tty.owned_fd.as_raw_fd())
+    }
+
+    pub fn clean_up(&mut self) -> io::Result<()> {
+        // nothing was drawn yet
+        if self.height == 0 { return Ok(()); }
+
+        self.erase(self.height)?;
+        self.renderer.flush()
+    }
+
+    /// a terminal reading keys from `reader` and drawing to `writer`, in a window of 80x24.
+    /// reads always block, so timeouts don't apply, and a search ends as aborted once
+    /// `reader` runs out of keys
+    pub fn headless(
+        reader: R,
+        writer: W,
+        max_height: Height,
+        prompt: String,
+        keep_right: bool,
+        alt_screen: bool,
+    ) -> io::Result<Self> {
+        Self::with_backend(None, reader, writer, max_height, prompt, keep_right, alt_screen)
+    }
+
+    /// everything drawn so far, up to the last flush
+    pub fn writer(&self) -> &W {
+        self.renderer.get_ref().get_ref()
+    }
 
-        Ok(Self { owned_fd, reader, writer, term, raw_term, max_height, height: 0, })
+    fn with_backend(
+        tty: Option<Tty>,
+        reader: R,
+        writer: W,
+        max_height: Height,
+        prompt: String,
+        keep_right: bool,
+        alt_screen: bool,
+    ) -> io::Result<Self> {
+        let mut renderer = Renderer::new(BufWriter::new(writer));
+        renderer.enter_screen(alt_screen)?;
+        renderer.bracketed_paste(true)?;
+        renderer.mouse(true)?;
+        renderer.flush()?;
+
+        Ok(Self { tty, reader, renderer, nodelay: false, closed: false, max_height, height: 0, prompt, keep_right, alt_screen,
+            timeout: None, preview: None, preview_lines: vec![], previewed: None, expect: vec![], bindings: HashMap::new(),
+            case: CaseMode::Smart, literal: false, tabstop: TABSTOP, max_results: None, history: vec![], layout: Layout::Default, prompt_row: None, pending: VecDeque::new(), frame: vec![] })
     }
 
-    // ensure enough room to draw all lines of output by drawing blank lines,
-    // effectively scrolling the view. + 1 to also include the prompt's offset
-    pub fn run(&mut self, candidates: Vec<Candidate>, keep_order: bool) -> io::Result<usize> {
-        let mut query: String = String::new();
-        let mut state = State::default();
+    /// search `candidates` interactively, starting from `query`. the `Selection` holds the marked
+    /// candidates, or the highlighted one if none are marked, along with the final query and the
+    /// expected key that ended the search. `None` means the search was aborted
+    pub fn run(&mut self, candidates: &[Candidate], keep_order: bool, tiebreak: Tiebreak, query: &str) -> io::Result<Option<Selection>> {
+        self.determine_height()?;
+        let mut session = self.session(candidates, keep_order, tiebreak);
+        self.run_session(&mut session, query, None)
+    }
 
+    /// same as `run`, but the candidates keep coming in from `source` during the search,
+    /// until every sender is dropped. a read error from `source` ends the search
+    pub fn run_streaming(&mut self, source: Receiver<io::Result<Candidate>>, keep_order: bool, tiebreak: Tiebreak, query: &str) -> io::Result<Option<Selection>> {
         self.determine_height()?;
-        self.scroll_down(self.height)?;
-        self.cursor_up(self.height)?;
+        let mut session = self.session(&[], keep_order, tiebreak);
+        session.spinner = Some(0);
+
+        // wake up regularly to take in new candidates while there are no keys
+        let timeout = self.timeout;
+        self.timeout = Some(timeout.map_or(STREAM_POLL, |t| t.min(STREAM_POLL)));
+        let out = self.run_session(&mut session, query, Some((&source, timeout)));
+        self.timeout = timeout;
+        out
+    }
+
+    // a session with the keys and matching set up for this terminal
+    fn session<'a>(&self, candidates: &'a [Candidate], keep_order: bool, tiebreak: Tiebreak) -> Session<'a> {
+        let mut session = Session::new(candidates, keep_order, tiebreak, self.height);
+        session.expect = self.expect.clone();
+        session.bindings = self.bindings.clone();
+        session.case = self.case;
+        session.literal = self.literal;
+        session.max_results = self.max_results;
+        session.history.entries = self.history.clone();
+        session.layout = self.layout;
+        // the list shown before there is a query
+        session.filtered.truncate(self.max_results.unwrap_or(usize::MAX));
+        session
+    }
+
+    // `source` also has the timeout to go back to once it runs dry
+    fn run_session(
+        &mut self,
+        session: &mut Session,
+        query: &str,
+        mut source: Option<(&Receiver<io::Result<Candidate>>, Option<Duration>)>,
+    ) -> io::Result<Option<Selection>> {
+        // the query is edited on one line. its lines are separate tokens either way
+        session.set_query(&query.replace(['\r', '\n'], " "));
 
-        let mut filtered = candidates.clone();
-        let mut old_state = state.clone();
-        let mut old_query = query.clone();
+        self.reserve()?;
+
+        let mut old_state = session.state;
+        let mut old_marked = session.marked.len();
 
         let mut redraw = true;
         loop {
-            // did the query change?
-            if query != old_query {
-                if query.len() == 0 {
-                    filtered = candidates.clone();
+            // were more candidates read? the spinner turns each time there is a look
+            if let Some((receiver, timeout)) = source {
+                if !session.receive(receiver)? {
+                    // the input ended, nothing left to wake up for
+                    source = None;
+                    self.timeout = timeout;
+                    session.spinner = None;
                 } else {
-                    filtered = filter::rank_candidates(candidates.clone(), &query, keep_order);
-                    redraw = true;
-                    state.selected = 0;
-                    old_query = query.clone();
+                    session.spinner = session.spinner.map(|tick| tick.wrapping_add(1));
                 }
+                redraw = true;
+            }
+
+            // was the window resized?
+            if self.update_height()? {
+                session.set_height(self.height);
+                redraw = true;
+            }
+
+            // did the query change?
+            if session.update_filter() {
+                redraw = true;
             }
 
             // did the selection move?
-            if redraw || state.cursor != old_state.cursor || state.selected != old_state.selected {
-                self.draw(&state, &query, &filtered, candidates.len())?;
-                old_state = state.clone();
+            if redraw || session.state.cursor != old_state.cursor || session.state.selected != old_state.selected
+                || session.marked.len() != old_marked {
+                self.draw(session)?;
+                old_state = session.state;
+                old_marked = session.marked.len();
                 redraw = false;
             }
 
-            let visible_rows = self.height.min(filtered.len());
-            if let Some(key) = self.read_key() {
-                match key_to_action(key) {
-                    Action::Close => break,
-                    Action::Byte(byte) => {
-                        assert!(state.cursor <= query.len(), "internal error");
-                        query.insert(state.cursor, byte as char);
-                        state.cursor += 1;
-                    },
-                    Action::DeleteWord => {
-                        action_delete_word(&mut query, &mut state.cursor);
-                    },
-                    Action::Backspace => {
-                        if query.len() > 0 && state.cursor == query.len() {
-                            query.pop();
-                            state.cursor -= 1;
-                        } else if query.len() > 0 && state.cursor > 0 {
-                            query.remove(state.cursor);
-                            state.cursor -= 1;
-                        }
-                    }
-                    Action::Pass => { },
-                    _ => {  }
+            if let Some(keys) = self.read_burst()? {
+                session.prompt_row = self.prompt_row;
+                if let Some(exit) = session.handle_keys(keys) {
+                    return Ok(session.finish(exit));
                 }
+            } else if self.closed {
+                return Ok(None);
             }
         }
-
-        Ok(0)
     }
 
-    fn draw_candidate(&mut self, candidate: &Candidate, width: usize, selected: bool) -> io::Result<()> {
-        let out: io::Result<()> = {
-            if selected { self.sgr(Attributes::Reverse)?; }
-            let path = shrink_str(&candidate.path, width);
-
-            if candidate.ranges.len() == 0 {
-                self.writer.write(path.as_bytes())?;
-            } else {
-                // self.writer.write(format!("{:?}", candidate.ranges).as_bytes())?;
-                for (flag, slice) in IterRanges::new(candidate.ranges.iter(), path.len()) {
-                    let segment = &path[slice.start..slice.end];
-                    if flag {
-                        self.sgr(Attributes::FgCyan)?;
-                    } else {
-                        self.sgr(Attributes::FgDefault)?;
-                    }
-
-                    // self.writer.write(format!("{:?} ", slice).as_bytes())?;
-                    self.writer.write(segment.as_bytes())?;
-                }
+    fn draw(&mut self, session: &Session) -> io::Result<()> {
+        let size = self.window_size();
+        // the prompt alone when the window is too short for the candidates too
+        let rows = if size.y > self.height { self.height } else { 0 };
+
+        // run the preview command again when the selection changed
+        if let Some(command) = &self.preview {
+            let selected = session.filtered.get(session.state.selected).map(|s| s.index);
+            if selected != self.previewed {
+                self.preview_lines = match selected {
+                    Some(index) => run_preview(command, &session.candidates[index].path, self.height),
+                    None => vec![],
+                };
+                self.previewed = selected;
             }
+        }
 
-            Ok(())
-        };
-
-        self.sgr(Attributes::Reset)?;
-        out
+        // the whole frame is assembled first and reaches the terminal in a single write
+        self.frame.clear();
+        let preview = self.preview.as_ref().map(|_| self.preview_lines.as_slice());
+        let mut renderer = Renderer::new(&mut self.frame);
+        renderer.set_tabstop(self.tabstop);
+        renderer.frame(session, rows, size.x, &self.prompt, self.keep_right, preview)?;
+        self.renderer.get_mut().write_all(&self.frame)?;
+        self.renderer.flush()
     }
 
-    fn draw(&mut self, state: &State, query: &str, candidates: &[Candidate], len: usize) -> io::Result<()> {
-        let width = self.window_size()?.x;
-
-        // draw the candidates
-        for line in 0..self.height {
-            self.cursor_down(1)?;
-            self.clear_line()?;
-            if line < candidates.len() {
-                self.draw_candidate(&candidates[line], width, line == state.selected)?;
-            }
-        }
-        self.sgr(Attributes::Reset)?;
-        self.cursor_up(self.height)?;
+    fn nodelay(&mut self, state: bool) -> io::Result<()> {
+        self.nodelay = state;
+        self.read_mode(if state { 0 } else { 1 }, 0)
+    }
 
-        // draw the prompt
-        {
-            self.clear_line()?;
-            self.writer.write(b"> ")?;
-            self.writer.write(shrink_str(query, width.saturating_sub(2)).as_bytes())?;
-        }
+    // reads wait for `vmin` bytes, or with `vmin` 0 up to `vtime` tenths of a second for any.
+    // only a tty has read modes
+    fn read_mode(&mut self, vmin: u8, vtime: u8) -> io::Result<()> {
+        let Some(tty) = &mut self.tty else { return Ok(()); };
+        tty.raw_term.c_cc[termios::VMIN] = vmin;
+        tty.raw_term.c_cc[termios::VTIME] = vtime;
+        termios::tcsetattr(tty.owned_fd.as_raw_fd(), termios::TCSANOW, &tty.raw_term)?;
+        Ok(())
+    }
 
-        // draw info if there is room
-        let prompt_width = 2;
-        let info_str = format!("{}/{}", candidates.len(), len);
-        let spacing = width.saturating_sub(
-            prompt_width + query.len() + info_str.len()
-        );
+    fn is_nodelay(&self) -> bool {
+        self.nodelay
+    }
 
-        if spacing >= 1 {
-            self.cursor_right(spacing)?;
-            self.writer.write(info_str.as_bytes())?;
-        }
+    /// show the output of `command` next to the candidates, with `{}` replaced by the
+    /// path of the selected one
+    pub fn set_preview(&mut self, command: Option<String>) {
+        self.preview = command;
+        self.previewed = None;
+    }
 
-        // position the cursor at the edit location
-        self.cursor_col(1)?;
-        self.cursor_right((width-1).min(state.cursor+2))?;
+    /// stop waiting for a key after `timeout`, so `read_key` returns `None` and `run` wakes
+    /// up without input. `None` waits for as long as it takes
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
 
-        self.writer.flush()
+    /// end the search when one of `keys` is pressed, the same as enter would, and
+    /// report the key in the returned `Selection`
+    pub fn set_expect(&mut self, keys: Vec<Key>) {
+        self.expect = keys;
     }
 
-    fn write(&mut self, num: usize, chr: char) -> io::Result<()> {
-        self.writer.write(b"\x1b[")?;
-        self.writer.write(num.to_string().as_bytes())?;
-        self.writer.write(&[chr as u8])?;
-        Ok(())
+    /// do `action` when `key` is pressed instead of what it does by default. later
+    /// bindings of the same key win
+    pub fn set_bindings<I: IntoIterator<Item=(Key, Action)>>(&mut self, bindings: I) {
+        self.bindings = HashMap::from_iter(bindings);
     }
 
-    fn cursor_up(&mut self, num: usize) -> io::Result<()> {
-        self.write(num, 'A')
+    /// match the case of the query according to `case` instead of smart case
+    pub fn set_case(&mut self, case: CaseMode) {
+        self.case = case;
     }
 
-    fn cursor_col(&mut self, col: usize) -> io::Result<()> {
-        self.write(col, 'G')
+    /// draw the tabs of candidates up to the next multiple of `tabstop` columns
+    pub fn set_tabstop(&mut self, tabstop: usize) {
+        self.tabstop = tabstop;
     }
 
-    fn cursor_down(&mut self, num: usize) -> io::Result<()> {
-        self.write(num, 'B')
+    /// match the whole query as one token, spaces included, see `Matcher::literal`
+    pub fn set_literal(&mut self, literal: bool) {
+        self.literal = literal;
     }
 
-    fn cursor_right(&mut self, num: usize) -> io::Result<()> {
-        self.write(num, 'C')
+    /// draw the prompt and the list as `layout` says
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.layout = layout;
     }
 
-    fn cursor_left(&mut self, num: usize) -> io::Result<()> {
-        self.write(num, 'D')
+    /// the queries of earlier searches, oldest first, to recall with up at the top of the
+    /// list or with the history actions
+    pub fn set_history(&mut self, history: Vec<String>) {
+        self.history = history;
     }
 
-    fn clear_line(&mut self) -> io::Result<()> {
-        self.cursor_col(1)?;
-        self.write(2, 'K')
+    /// rank no more than `max_results` of the matches, leaving the worse ones out of the list
+    pub fn set_max_results(&mut self, max_results: Option<usize>) {
+        self.max_results = max_results;
     }
 
-    fn scroll_down(&mut self, num: usize) -> io::Result<()> {
-        for _ in 0..num {
-            self.writer.write(b"\n")?;
-        }
-        Ok(())
+    // the size of the tty, or what `COLUMNS` and `LINES` say when it doesn't know
+    fn window_size(&self) -> WinSize {
+        let Some(fd) = self.rawfd() else { return HEADLESS_SIZE; };
+        fallback_size(tty_size(fd), |name| std::env::var(name).ok())
     }
 
-    fn clean_line(&mut self) -> io::Result<()> {
-        self.cursor_col(1)?;
-        self.write(22, 'K')?;
+    fn determine_height(&mut self) -> io::Result<()> {
+        let win_size = self.window_size();
+        self.height = self.max_height.rows(win_size.y);
         Ok(())
     }
 
-    fn sgr(&mut self, code: Attributes) -> io::Result<()> {
-        self.write(code.tou8() as usize,  'm')
+    // recompute the height for the current window size. when it changed the drawn
+    // region is erased and room is made for the new height, and true is returned
+    fn update_height(&mut self) -> io::Result<bool> {
+        let old_height = self.height;
+        self.determine_height()?;
+        if self.height == old_height { return Ok(false); }
+
+        self.erase(old_height)?;
+        self.reserve()?;
+        Ok(true)
     }
 
-    fn nodelay(&mut self, state: bool) -> io::Result<()> {
-        self.raw_term.c_cc[termios::os::linux::VMIN] = if state { 0 } else { 1 };
-        termios::tcsetattr(self.rawfd(), termios::TCSANOW, &self.raw_term)?;
+    // make room for the prompt and `height` lines below the cursor, scrolling if needed,
+    // and move to where the prompt goes
+    fn reserve(&mut self) -> io::Result<()> {
+        self.renderer.scroll_down(self.height)?;
+        if self.layout.prompt_on_top() {
+            self.renderer.cursor_up(self.height)?;
+        }
+
+        // ask the tty where that is, the answer is read like a key
+        self.prompt_row = None;
+        if self.tty.is_some() {
+            self.renderer.get_mut().write_all(CURSOR_POSITION)?;
+            self.renderer.flush()?;
+        }
         Ok(())
     }
 
-    fn window_size(&self) -> io::Result<WinSize> {
-        unsafe {
-            let mut win_size: libc::winsize = std::mem::zeroed();
-            if libc::ioctl(self.rawfd(), libc::TIOCGWINSZ, &mut win_size ) != 0 {
-                return Err(other_error("ioctl call failed"));
-            }
-            Ok(WinSize { x: win_size.ws_col as _, y: win_size.ws_row as _ })
+    // erase the prompt and the `height` lines drawn with it, leaving the cursor on the top one
+    fn erase(&mut self, height: usize) -> io::Result<()> {
+        if !self.layout.prompt_on_top() {
+            self.renderer.cursor_up(height)?;
         }
+        self.renderer.erase_lines(height)
     }
 
-    fn determine_height(&mut self) -> io::Result<()> {
-        let win_size = self.window_size()?;
-        self.height = self.max_height.clamp(1, win_size.y - 1);
-        Ok(())
+    // the next key along with everything already queued after it, like the rest of a paste,
+    // so the candidates are ranked once for the whole burst. `None` when no key was read
+    fn read_burst(&mut self) -> io::Result<Option<Vec<Key>>> {
+        let Some(key) = self.read_key() else { return Ok(None); };
+        let mut keys = vec![key];
+        self.nodelay(true)?;
+        while let Some(key) = self.read_key() {
+            keys.push(key);
+        }
+        self.nodelay(false)?;
+        Ok(Some(keys))
     }
 
     // *block* until read a key or timeout(return None)
     pub fn read_key(&mut self) -> Option<Key> {
+        if let Some(key) = self.pending.pop_front() {
+            return Some(key);
+        }
+
+        let Some(fd) = self.rawfd() else {
+            // nothing can be read without blocking
+            if self.nodelay { return None; }
+            return self.read_next_key();
+        };
+
+        if let Some(timeout) = self.timeout.filter(|_| !self.is_nodelay()) {
+            if !wait_for_input(fd, timeout).ok()? {
+                return None;
+            }
+        }
+
+        self.read_next_key()
+    }
+
+    // the key sent by the next bytes of `reader`, `None` when there are none
+    fn read_next_key(&mut self) -> Option<Key> {
         let mut byte: u8 = 0;
-        if let Ok(_) = self.reader.read_exact(std::slice::from_mut(&mut byte)) {
-            if byte == ('\x1b' as u8) {
-                self.nodelay(true).ok()?;
-                let mut seq = [0; 2];
-                let out = self.reader.read_exact(&mut seq);
-                self.nodelay(false).ok()?;
-                if out.is_err() { return None; }
-
-                // DECCKM mode sends \x1bO* instead of \x1b[*
-                if matches!(seq[0] as char, 'O' | '[') {
-                    return match seq[1] as char {
-                        'A' => Some(Key::Up),
-                        'B' => Some(Key::Down),
-                        'C' => Some(Key::Right),
-                        'D' => Some(Key::Left),
-                        '3' => Some(read_delete(&self.reader)),
-                        _ => Some(Key::Esc)
-                    }
+        if self.reader.read_exact(std::slice::from_mut(&mut byte)).is_ok() {
+            if byte == b'\x1b' {
+                // the rest of a sequence follows right away, a lone esc is followed by nothing.
+                // afterwards go back to blocking, or to not blocking while draining queued input
+                let was_nodelay = self.is_nodelay();
+                self.read_mode(0, ESCAPE_TIMEOUT).ok()?;
+                let escape = read_escape(&mut self.reader);
+                self.nodelay(was_nodelay).ok()?;
+
+                if let Escape::Position(row) = escape {
+                    self.prompt_row = Some(row);
+                    // while draining a burst the reply is skipped rather than ending it
+                    return if self.is_nodelay() { self.read_key() } else { None };
                 }
-                return Some(Key::Esc)
+                let Escape::Paste = escape else { return Some(escape.key()); };
+                // pasted text goes into the query as it is, its newlines and control
+                // characters don't act as keys. the end of a paste is always sent
+                self.nodelay(false).ok()?;
+                let text = read_paste(&mut self.reader);
+                self.nodelay(was_nodelay).ok()?;
+                self.pending.extend(text.chars().map(Key::Character));
+                return self.pending.pop_front();
             }
 
-            if byte == '\r' as u8 {
+            if byte == b'\r' {
                 return Some(Key::Enter);
             } else if byte == 127 {
                 return Some(Key::Backspace)
@@ -319,20 +567,28 @@ impl Terminal {
             }
 
             if unsafe { libc::isprint(byte as _) } > 0 {
-                return Some(Key::Character(byte));
+                return Some(Key::Character(byte as char));
+            }
+
+            if !byte.is_ascii() {
+                return read_utf8(byte, &mut self.reader).map(Key::Character);
             }
 
-            return Some(Key::Esc)
+            Some(Key::Esc)
         } else {
+            // without a tty reads block, so this is the end of the input
+            self.closed = self.tty.is_none();
             None
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub enum Key {
-    Character(u8),
+    Character(char),
     Control(u8),
+    /// a key pressed together with alt, sent as esc followed by the key
+    Alt(u8),
     Esc,
     Up,
     Down,
@@ -340,27 +596,165 @@ pub enum Key {
     Right,
     Backspace,
     Delete,
+    Home,
+    End,
+    PageUp,
+    PageDown,
     Enter,
+    /// a mouse button pressed at column `x` and row `y` of the screen, counting from 1
+    Mouse { button: MouseButton, x: usize, y: usize },
+}
+
+/// the mouse buttons told apart in a `Key::Mouse`
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum MouseButton {
+    Left,
+    Middle,
+    Right,
+    WheelUp,
+    WheelDown,
+    /// a button let go, the mouse dragged, or a button not listed
+    Other,
+}
+
+impl Key {
+    /// the key called `name`, like `ctrl-x`, `alt-b`, `alt-bspace`, `tab`, `enter`, `esc`,
+    /// `up`, `down`, `left`, `right`, `bspace`, `del`, `home`, `end`, `pgup` or `pgdn`
+    pub fn from_name(name: &str) -> Option<Key> {
+        let key = match name {
+            "tab" => Key::Control(ctrl('i')),
+            "enter" => Key::Enter,
+            "esc" => Key::Esc,
+            "up" => Key::Up,
+            "down" => Key::Down,
+            "left" => Key::Left,
+            "right" => Key::Right,
+            "bspace" => Key::Backspace,
+            "del" => Key::Delete,
+            "home" => Key::Home,
+            "end" => Key::End,
+            "pgup" => Key::PageUp,
+            "pgdn" => Key::PageDown,
+            "alt-bspace" => Key::Alt(127),
+            _ => {
+                if let Some(ch) = name.strip_prefix("alt-") {
+                    match ch.as_bytes() {
+                        &[c] if c.is_ascii_graphic() => Key::Alt(c),
+                        _ => return None,
+                    }
+                } else {
+                    let ch = name.strip_prefix("ctrl-")?;
+                    match ch.as_bytes() {
+                        &[c] if c.is_ascii_lowercase() => Key::Control(ctrl(c as char)),
+                        _ => return None,
+                    }
+                }
+            },
+        };
+        Some(key)
+    }
+}
+
+// the inverse of `Key::from_name`, plain characters are written as they are
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Key::Character(c) => write!(f, "{}", c),
+            Key::Control(c) if c == ctrl('i') => write!(f, "tab"),
+            Key::Control(c) => write!(f, "ctrl-{}", (c | 0x60) as char),
+            Key::Alt(127) => write!(f, "alt-bspace"),
+            Key::Alt(c) => write!(f, "alt-{}", c as char),
+            Key::Esc => write!(f, "esc"),
+            Key::Up => write!(f, "up"),
+            Key::Down => write!(f, "down"),
+            Key::Left => write!(f, "left"),
+            Key::Right => write!(f, "right"),
+            Key::Backspace => write!(f, "bspace"),
+            Key::Delete => write!(f, "del"),
+            Key::Home => write!(f, "home"),
+            Key::End => write!(f, "end"),
+            Key::PageUp => write!(f, "pgup"),
+            Key::PageDown => write!(f, "pgdn"),
+            Key::Enter => write!(f, "enter"),
+            Key::Mouse { .. } => write!(f, "mouse"),
+        }
+    }
 }
 
-#[derive(Clone, Copy, Eq, PartialEq)]
-enum Action {
-    Byte(u8),
+/// what a key does in an interactive search
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    /// insert the character at the cursor
+    Char(char),
     LineUp,
     LineDown,
+    /// move the selection up by the drawn height
+    PageUp,
+    PageDown,
+    /// select the first candidate
+    First,
+    /// select the last candidate
+    Last,
     CursorLeft,
     CursorLeftMost,
     CursorRight,
     CursorRightMost,
+    /// to the start of the word before the cursor
+    CursorWordLeft,
+    /// to the end of the word after the cursor
+    CursorWordRight,
     Backspace,
     Delete,
     DeleteWord,
     DeleteLine,
+    ToggleMark,
+    /// recall the query searched for before the one shown
+    HistoryPrev,
+    /// go back towards the query that was typed before recalling any
+    HistoryNext,
     Select,
     Close,
     Pass,
 }
 
+// the names actions are bound by, every action but `Char` has one
+const ACTION_NAMES: &[(&str, Action)] = &[
+    ("up", Action::LineUp),
+    ("down", Action::LineDown),
+    ("page-up", Action::PageUp),
+    ("page-down", Action::PageDown),
+    ("first", Action::First),
+    ("last", Action::Last),
+    ("left", Action::CursorLeft),
+    ("right", Action::CursorRight),
+    ("home", Action::CursorLeftMost),
+    ("end", Action::CursorRightMost),
+    ("word-left", Action::CursorWordLeft),
+    ("word-right", Action::CursorWordRight),
+    ("backspace", Action::Backspace),
+    ("delete", Action::Delete),
+    ("delete-word", Action::DeleteWord),
+    ("delete-line", Action::DeleteLine),
+    ("toggle-mark", Action::ToggleMark),
+    ("previous-history", Action::HistoryPrev),
+    ("next-history", Action::HistoryNext),
+    ("select", Action::Select),
+    ("close", Action::Close),
+    ("ignore", Action::Pass),
+];
+
+impl Action {
+    /// the action called `name`, like `select`, `up` or `delete-word`
+    pub fn from_name(name: &str) -> Option<Action> {
+        ACTION_NAMES.iter().find(|(n, _)| *n == name).map(|&(_, action)| action)
+    }
+
+    /// the name `from_name` takes, `None` for `Char`
+    pub fn name(&self) -> Option<&'static str> {
+        ACTION_NAMES.iter().find(|(_, a)| a == self).map(|&(name, _)| name)
+    }
+}
+
 const fn ctrl(ch: char) -> u8 {
     (ch as u8) & 0x1f
 }
@@ -368,6 +762,7 @@ const fn ctrl(ch: char) -> u8 {
 fn ctrl_to_action(key: u8) -> Action {
     match key {
         c if c == ctrl('c') => Action::Close,
+        c if c == ctrl('i') => Action::ToggleMark,
         c if c == ctrl('w') => Action::DeleteWord,
         c if c == ctrl('u') => Action::DeleteLine,
         c if c == ctrl('h') => Action::Backspace,
@@ -382,114 +777,2541 @@ fn ctrl_to_action(key: u8) -> Action {
     }
 }
 
-fn key_to_action(key: Key) -> Action {
+fn alt_to_action(key: u8) -> Action {
+    match key {
+        b'b' => Action::CursorWordLeft,
+        b'f' => Action::CursorWordRight,
+        127 => Action::DeleteWord,
+        _ => Action::Pass,
+    }
+}
+
+// the action bound to `key` in `bindings`, or else the default one
+fn key_to_action(key: Key, bindings: &HashMap<Key, Action>) -> Action {
+    if let Some(&action) = bindings.get(&key) {
+        return action;
+    }
+
     match key {
-        Key::Character(c) => Action::Byte(c),
+        Key::Character(c) => Action::Char(c),
         Key::Control(c) => ctrl_to_action(c),
+        Key::Alt(c) => alt_to_action(c),
         Key::Backspace => Action::Backspace,
         Key::Delete => Action::Delete,
         Key::Up => Action::LineUp,
         Key::Down => Action::LineDown,
+        Key::PageUp => Action::PageUp,
+        Key::PageDown => Action::PageDown,
+        Key::Home => Action::First,
+        Key::End => Action::Last,
         Key::Left => Action::CursorLeft,
         Key::Right => Action::CursorRight,
         Key::Enter => Action::Select,
         Key::Esc => Action::Close,
+        // handled by the session, which knows where the candidates are drawn
+        Key::Mouse { .. } => Action::Pass,
     }
 
 }
 
-fn read_delete<R: Read>(mut file: R) -> Key {
-    let mut byte: u8 = 0;
-    if let Ok(_) = file.read_exact(&mut std::slice::from_mut(&mut byte)) {
-        if byte as char == '~' {
-            return Key::Delete;
+// read the continuation bytes of the utf8 sequence started by `first`
+fn read_utf8<R: Read>(first: u8, mut file: R) -> Option<char> {
+    let len = match first {
+        0x00..=0x7f => 1,
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => return None,
+    };
+    let mut buf = [first, 0, 0, 0];
+    file.read_exact(&mut buf[1..len]).ok()?;
+    std::str::from_utf8(&buf[..len]).ok()?.chars().next()
+}
+
+// what an escape sequence stands for
+#[derive(Debug, Eq, PartialEq)]
+enum Escape {
+    Key(Key),
+    // the start of pasted text, `\x1b[200~`
+    Paste,
+    // the row of the cursor, reported for `CURSOR_POSITION`
+    Position(usize),
+}
+
+impl Escape {
+    // esc for the start of a paste, to take the sequence as a key after all
+    fn key(self) -> Key {
+        match self {
+            Escape::Key(key) => key,
+            Escape::Paste | Escape::Position(_) => Key::Esc,
         }
     }
-    Key::Esc
+}
+
+// the text of a paste up to its end marker, without the control characters
+fn read_paste<R: Read>(mut file: R) -> String {
+    let mut bytes = vec![];
+    while !bytes.ends_with(PASTE_END) {
+        let Some(byte) = read_byte(&mut file) else { break; };
+        bytes.push(byte);
+    }
+    if bytes.ends_with(PASTE_END) {
+        bytes.truncate(bytes.len() - PASTE_END.len());
+    }
+    String::from_iter(String::from_utf8_lossy(&bytes).chars().filter(|c| !c.is_control()))
+}
+
+// the escape sequence after the leading \x1b. `file` doesn't block, the rest of a
+// sequence arrives together with the \x1b while a lone esc is followed by nothing
+fn read_escape<R: Read>(mut file: R) -> Escape {
+    let Some(byte) = read_byte(&mut file) else { return Escape::Key(Key::Esc); };
+
+    match byte {
+        // a CSI sequence: parameter and intermediate bytes, up to the final byte
+        b'[' => {
+            let mut params = vec![];
+            loop {
+                match read_byte(&mut file) {
+                    None if params.is_empty() => return Escape::Key(Key::Alt(b'[')),
+                    Some(byte @ 0x20..=0x3f) => params.push(byte),
+                    Some(b'~') if params == b"200" => return Escape::Paste,
+                    Some(byte @ (b'M' | b'm')) if params.first() == Some(&b'<') => {
+                        return Escape::Key(mouse_key(&params[1..], byte == b'M'));
+                    },
+                    Some(b'R') => match csi_numbers(&params)[..] {
+                        [Some(row), Some(_)] => return Escape::Position(row),
+                        _ => return Escape::Key(Key::Esc),
+                    },
+                    Some(byte @ 0x40..=0x7e) => return Escape::Key(csi_key(&params, byte)),
+                    _ => return Escape::Key(Key::Esc),
+                }
+            }
+        },
+        // DECCKM mode sends \x1bO* instead of \x1b[*
+        b'O' => Escape::Key(match read_byte(&mut file) {
+            Some(byte) => csi_key(&[], byte),
+            None => Key::Alt(b'O'),
+        }),
+        // alt held down together with a printable key or backspace
+        b if b.is_ascii_graphic() || b == 127 => Escape::Key(Key::Alt(b)),
+        _ => Escape::Key(Key::Esc),
+    }
+}
+
+// the key of a CSI sequence with the parameter bytes `params` and the `last` byte. modifiers,
+// the parameters after a `;` like in `\x1b[1;5C`, are not told apart
+fn csi_key(params: &[u8], last: u8) -> Key {
+    let first = params.split(|&b| b == b';').next().unwrap_or_default();
+    let number = std::str::from_utf8(first).ok().and_then(|n| n.parse::<usize>().ok());
+
+    match (last, number) {
+        (b'A', _) => Key::Up,
+        (b'B', _) => Key::Down,
+        (b'C', _) => Key::Right,
+        (b'D', _) => Key::Left,
+        (b'H', _) => Key::Home,
+        (b'F', _) => Key::End,
+        (b'~', Some(1 | 7)) => Key::Home,
+        (b'~', Some(3)) => Key::Delete,
+        (b'~', Some(4 | 8)) => Key::End,
+        (b'~', Some(5)) => Key::PageUp,
+        (b'~', Some(6)) => Key::PageDown,
+        _ => Key::Esc,
+    }
+}
+
+// the `;` separated numbers of CSI parameters, `None` for the ones that aren't
+fn csi_numbers(params: &[u8]) -> Vec<Option<usize>> {
+    Vec::from_iter(params.split(|&b| b == b';').map(|n| std::str::from_utf8(n).ok()?.parse().ok()))
+}
+
+// the mouse event of a SGR mouse sequence `\x1b[<button;x;yM`, with the parameters after
+// the `<`. a final `m` instead of `M` is a release
+fn mouse_key(params: &[u8], pressed: bool) -> Key {
+    let [Some(code), Some(x), Some(y)] = csi_numbers(params)[..] else { return Key::Esc; };
+    // shift, alt and ctrl held down don't make a difference
+    let button = match code & !(4 | 8 | 16) {
+        _ if !pressed => MouseButton::Other,
+        0 => MouseButton::Left,
+        1 => MouseButton::Middle,
+        2 => MouseButton::Right,
+        64 => MouseButton::WheelUp,
+        65 => MouseButton::WheelDown,
+        _ => MouseButton::Other,
+    };
+    Key::Mouse { button, x, y }
+}
+
+fn read_byte<R: Read>(file: &mut R) -> Option<u8> {
+    let mut byte: u8 = 0;
+    file.read_exact(std::slice::from_mut(&mut byte)).ok()?;
+    Some(byte)
 }
 
 #[derive(Debug, Clone, Default, Copy)]
 struct State {
     pub cursor: usize,
     pub selected: usize,
+    // index of the first candidate drawn
+    pub offset: usize,
 }
 
-#[derive(Debug, Clone, Default)]
-struct WinSize {
-    x: usize,
-    y: usize,
+impl State {
+    // select `selected`, clamped to the `len` candidates, and scroll it into the `height` drawn
+    fn select(&mut self, selected: usize, len: usize, height: usize) {
+        self.selected = selected.min(len.saturating_sub(1));
+        if self.selected < self.offset {
+            self.offset = self.selected;
+        } else if self.selected >= self.offset + height {
+            self.offset = self.selected + 1 - height;
+        }
+    }
 }
 
-fn shrink_str(s: &str, width: usize) -> &str {
-    let mut last_width = 0;
-    for (idx, chr) in s.chars().enumerate() {
-        if idx > width {
+// how often to look for new candidates while they are streamed in
+const STREAM_POLL: Duration = Duration::from_millis(50);
+
+// tenths of a second to wait for the rest of an escape sequence before taking it as esc
+const ESCAPE_TIMEOUT: u8 = 1;
+
+// pasted text is sent between `PASTE_START` and `PASTE_END` while this is on
+const BRACKETED_PASTE_ON: &[u8] = b"\x1b[?2004h";
+const BRACKETED_PASTE_OFF: &[u8] = b"\x1b[?2004l";
+const PASTE_END: &[u8] = b"\x1b[201~";
+
+// clicks and the wheel are reported as SGR mouse sequences while this is on
+const MOUSE_ON: &[u8] = b"\x1b[?1000h\x1b[?1006h";
+const MOUSE_OFF: &[u8] = b"\x1b[?1006l\x1b[?1000l";
+
+// asks the tty to report the cursor position
+const CURSOR_POSITION: &[u8] = b"\x1b[6n";
+
+// the longest time between two clicks on a candidate that picks it like enter
+const DOUBLE_CLICK: Duration = Duration::from_millis(500);
 
-            return &s[..last_width];
+// drawn in front of the counts while candidates are still being read
+const SPINNER: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+// drawn in front of marked candidates
+const MARKER: &[u8] = b"* ";
+
+/// the columns between tab stops unless `set_tabstop` says otherwise
+pub const TABSTOP: usize = 8;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Exit {
+    Select(usize),
+    // one of the expected keys was pressed
+    Expect(Key),
+    Close,
+}
+
+/// what an interactive search ended with
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selection {
+    /// the query as it was when the search ended
+    pub query: String,
+    /// the expected key that ended the search, `None` when it was ended by enter
+    pub key: Option<Key>,
+    pub candidates: Vec<Candidate>,
+}
+
+// the queries of earlier searches, oldest first, and the one shown while going through them
+#[derive(Debug, Default)]
+struct History {
+    entries: Vec<String>,
+    position: Option<usize>,
+    // the query typed before recalling any, to come back to
+    draft: String,
+}
+
+impl History {
+    // whether `query` is still the entry recalled last, and not edited since
+    fn browsing(&self, query: &str) -> bool {
+        self.position.is_some_and(|position| self.entries[position] == query)
+    }
+
+    // the entry before the one shown as `query`, or the newest one
+    fn older(&mut self, query: &str) -> Option<String> {
+        let position = match self.position.filter(|_| self.browsing(query)) {
+            Some(0) => return None,
+            Some(position) => position - 1,
+            None => {
+                let position = self.entries.len().checked_sub(1)?;
+                self.draft = query.to_owned();
+                position
+            },
+        };
+        self.position = Some(position);
+        Some(self.entries[position].clone())
+    }
+
+    // the entry after the one shown as `query`, or the draft after the newest one
+    fn newer(&mut self, query: &str) -> Option<String> {
+        if !self.browsing(query) { return None; }
+        let next = self.position? + 1;
+        if next < self.entries.len() {
+            self.position = Some(next);
+            Some(self.entries[next].clone())
+        } else {
+            self.position = None;
+            Some(std::mem::take(&mut self.draft))
         }
-        last_width += chr.len_utf8();
     }
-    &s[..last_width]
 }
 
-struct IterRanges<I> {
-    iter: I,
-    stop: usize,
+/// the editing state of an interactive search, kept apart from the tty so it
+/// can be driven by any source of keys
+struct Session<'a> {
+    // borrowed, unless candidates are added while searching
+    candidates: Cow<'a, [Candidate]>,
+    keep_order: bool,
+    tiebreak: Tiebreak,
+    case: CaseMode,
+    literal: bool,
+    max_results: Option<usize>,
+    height: usize,
 
-    last: Option<Range>,
-    start: usize,
+    query: String,
+    old_query: String,
+    state: State,
+    filtered: Vec<Scored>,
+    // indices into `candidates`, in the order they were marked
+    marked: Vec<usize>,
+    expect: Vec<Key>,
+    bindings: HashMap<Key, Action>,
+    history: History,
+    layout: Layout,
+    // the screen row of the prompt, when known clicks select the candidate under them
+    prompt_row: Option<usize>,
+    // the candidate clicked last and when, to tell a double click
+    last_click: Option<(usize, Instant)>,
+    // how often the input was looked at while more candidates may still come
+    spinner: Option<usize>,
 }
 
-impl<'r, I: Iterator<Item=&'r Range>> IterRanges<I> {
-    fn new(mut iter: I, stop: usize) -> Self {
-        let last = iter.next().map(Clone::clone);
+impl<'a> Session<'a> {
+    fn new(candidates: &'a [Candidate], keep_order: bool, tiebreak: Tiebreak, height: usize) -> Self {
         Self {
-            iter,
-            stop,
+            candidates: Cow::Borrowed(candidates),
+            keep_order,
+            tiebreak,
+            case: CaseMode::Smart,
+            literal: false,
+            max_results: None,
+            height,
+
+            query: String::new(),
+            old_query: String::new(),
+            state: State::default(),
+            filtered: filter::score_candidates(candidates, "", keep_order, tiebreak, CaseMode::Smart, None),
+            marked: vec![],
+            expect: vec![],
+            bindings: HashMap::new(),
+            history: History::default(),
+            layout: Layout::Default,
+            prompt_row: None,
+            last_click: None,
+            spinner: None,
+        }
+    }
 
-            last,
-            start: 0,
+    // the candidates chosen by `exit`: every marked one, or else the highlighted one.
+    // an expected key ends the search even when nothing is highlighted
+    fn finish(&self, exit: Exit) -> Option<Selection> {
+        let (index, key) = match exit {
+            Exit::Select(index) => (Some(index), None),
+            Exit::Expect(key) => (self.filtered.get(self.state.selected).map(|s| s.index), Some(key)),
+            Exit::Close => return None,
+        };
+        let indices = if self.marked.is_empty() { Vec::from_iter(index) } else { self.marked.clone() };
+        let candidates = Vec::from_iter(indices.into_iter().map(|index| self.candidate(index)));
+        Some(Selection { query: self.query.clone(), key, candidates })
+    }
+
+    // the candidate at `index`, with the rank and ranges it is currently shown with
+    fn candidate(&self, index: usize) -> Candidate {
+        let candidate = self.candidates[index].clone();
+        match self.filtered.iter().find(|s| s.index == index) {
+            Some(scored) => Candidate { rank: scored.rank, ranges: scored.ranges.clone(), ..candidate },
+            None => candidate,
         }
     }
-}
 
-impl<'r, I: Iterator<Item=&'r Range>> Iterator for IterRanges<I> {
-    type Item = (bool, Range);
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.start >= self.stop { return None; }
-        if let Some(Range { start, mut end }) = self.last {
-            end = end + 1;
-            debug_assert!(self.start <= start);
-            debug_assert!(start < end, "start: {}, end: {}", start, end);
-            end = end.min(self.stop);
-            let flag; let out;
-
-            if self.start == start {
-                flag = true;
-                out = Range { start, end };
-
-                self.last = self.iter.next().map(Clone::clone);
-                self.start = end;
-            } else {
-                flag = false;
-                out = Range { start: self.start, end: start };
-                self.start = start;
-            };
+    fn set_query(&mut self, query: &str) {
+        self.query = query.to_owned();
+        self.state.cursor = self.query.len();
+    }
 
-            Some((flag, out))
-        } else {
-            if self.start < self.stop {
-                let out = Range { start: self.start, end: self.stop};
-                self.start = self.stop;
-                Some((false, out))
-            } else {
-                None
+    // add `more` candidates, ranked against the query the others are shown for
+    fn extend(&mut self, more: Vec<Candidate>) {
+        if more.is_empty() { return; }
+
+        let start = self.candidates.len();
+        self.candidates.to_mut().extend(more);
+        let matcher = self.matcher(&self.old_query);
+        filter::score_more_with(&mut self.filtered, &self.candidates, start, &matcher, self.keep_order, self.tiebreak);
+        // the best matches among the ones kept so far and the new ones
+        self.filtered.truncate(self.max_results.unwrap_or(usize::MAX));
+    }
+
+    // take in the candidates waiting in `source`. returns false once every sender is gone
+    fn receive(&mut self, source: &Receiver<io::Result<Candidate>>) -> io::Result<bool> {
+        let mut more = vec![];
+        let open = loop {
+            match source.try_recv() {
+                Ok(candidate) => more.push(candidate?),
+                Err(TryRecvError::Empty) => break true,
+                Err(TryRecvError::Disconnected) => break false,
             }
+        };
+        self.extend(more);
+        Ok(open)
+    }
+
+    // draw `height` lines of candidates from now on, scrolling to keep the selection in view
+    fn set_height(&mut self, height: usize) {
+        self.height = height;
+        let state = &mut self.state;
+        if state.selected >= state.offset + height {
+            state.offset = (state.selected + 1).saturating_sub(height);
         }
     }
-}
 
-fn action_delete_word(query: &str, cursor: &mut usize) {
+    // `query` parsed the way this search matches
+    fn matcher(&self, query: &str) -> Matcher {
+        if self.literal { Matcher::literal(query, self.case) } else { Matcher::with_case(query, self.case) }
+    }
+
+    // rerank the candidates if the query changed since the last call
+    fn update_filter(&mut self) -> bool {
+        if self.query == self.old_query { return false; }
+
+        let matcher = self.matcher(&self.query);
+        // typing more only leaves out matches, unless some were cut off by `max_results`
+        self.filtered = if self.max_results.is_none() && filter::narrows(&self.old_query, &self.query) {
+            filter::rescore(&self.filtered, &self.candidates, &matcher, self.keep_order, self.tiebreak)
+        } else {
+            filter::score_candidates_with(&self.candidates, &matcher, self.keep_order, self.tiebreak, self.max_results)
+        };
+        self.state.selected = 0;
+        self.state.offset = 0;
+        self.old_query = self.query.clone();
+        true
+    }
+
+    // handle `keys` in order, stopping at the first one that exits
+    fn handle_keys<I: IntoIterator<Item=Key>>(&mut self, keys: I) -> Option<Exit> {
+        keys.into_iter().find_map(|key| {
+            if self.expect.contains(&key) { return Some(Exit::Expect(key)); }
+            if let Key::Mouse { button, y, .. } = key { return self.mouse(button, y); }
+
+            let action = self.follow_layout(key_to_action(key, &self.bindings));
+
+            // at the top of the list, up goes back through the history, and down comes
+            // back from it while a recalled query is shown
+            let action = match action {
+                Action::LineUp if self.state.selected == 0 && !self.history.entries.is_empty() => Action::HistoryPrev,
+                Action::LineDown if self.state.selected == 0 && self.history.browsing(&self.query) => Action::HistoryNext,
+                action => action,
+            };
+            self.handle(action)
+        })
+    }
+
+    // up and down follow the list, which grows upwards in the reverse layout
+    fn follow_layout(&self, action: Action) -> Action {
+        match (action, self.layout) {
+            (Action::LineUp, Layout::Reverse) => Action::LineDown,
+            (Action::LineDown, Layout::Reverse) => Action::LineUp,
+            (Action::PageUp, Layout::Reverse) => Action::PageDown,
+            (Action::PageDown, Layout::Reverse) => Action::PageUp,
+            (action, _) => action,
+        }
+    }
+
+    // the wheel moves the selection. a click selects the candidate on screen row `y`, and a
+    // second click on it soon after picks it like enter
+    fn mouse(&mut self, button: MouseButton, y: usize) -> Option<Exit> {
+        match button {
+            MouseButton::WheelUp => return self.handle(self.follow_layout(Action::LineUp)),
+            MouseButton::WheelDown => return self.handle(self.follow_layout(Action::LineDown)),
+            MouseButton::Left => {},
+            _ => return None,
+        }
 
+        let prompt = self.prompt_row?;
+        let line = match self.layout {
+            Layout::Default => y.checked_sub(prompt + 1)?,
+            Layout::Reverse => prompt.checked_sub(y + 1)?,
+            Layout::ReverseList => (y + self.height).checked_sub(prompt)?,
+        };
+        if line >= self.height { return None; }
+        let index = self.state.offset + line;
+        let scored = self.filtered.get(index)?;
+
+        let now = Instant::now();
+        let double = self.last_click.is_some_and(|(last, at)| last == index && now - at <= DOUBLE_CLICK);
+        if double {
+            self.last_click = None;
+            return Some(Exit::Select(scored.index));
+        }
+        self.last_click = Some((index, now));
+        self.state.select(index, self.filtered.len(), self.height);
+        None
+    }
 
+    fn handle(&mut self, action: Action) -> Option<Exit> {
+        let state = &mut self.state;
+        let query = &mut self.query;
+        match action {
+            Action::Close => return Some(Exit::Close),
+            Action::Select => {
+                let selected = self.filtered.get(state.selected)?;
+                return Some(Exit::Select(selected.index));
+            },
+            Action::HistoryPrev => {
+                let query = self.history.older(query)?;
+                self.set_query(&query);
+            },
+            Action::HistoryNext => {
+                let query = self.history.newer(query)?;
+                self.set_query(&query);
+            },
+            Action::LineUp => {
+                state.select(state.selected.saturating_sub(1), self.filtered.len(), self.height);
+            },
+            Action::LineDown => {
+                state.select(state.selected + 1, self.filtered.len(), self.height);
+            },
+            Action::PageUp => {
+                state.select(state.selected.saturating_sub(self.height), self.filtered.len(), self.height);
+            },
+            Action::PageDown => {
+                state.select(state.selected + self.height, self.filtered.len(), self.height);
+            },
+            Action::First => {
+                state.select(0, self.filtered.len(), self.height);
+            },
+            Action::Last => {
+                state.select(usize::MAX, self.filtered.len(), self.height);
+            },
+            Action::ToggleMark => {
+                let scored = self.filtered.get(state.selected)?;
+                if let Some(position) = self.marked.iter().position(|&i| i == scored.index) {
+                    self.marked.remove(position);
+                } else {
+                    self.marked.push(scored.index);
+                }
+                return self.handle(Action::LineDown);
+            },
+            Action::CursorLeft => {
+                if let Some(chr) = query[..state.cursor].chars().next_back() {
+                    state.cursor -= chr.len_utf8();
+                }
+            },
+            Action::CursorRight => {
+                if let Some(chr) = query[state.cursor..].chars().next() {
+                    state.cursor += chr.len_utf8();
+                }
+            },
+            Action::CursorLeftMost => {
+                state.cursor = 0;
+            },
+            Action::CursorWordLeft => {
+                state.cursor = word_start_before(query, state.cursor);
+            },
+            Action::CursorWordRight => {
+                state.cursor = word_end_after(query, state.cursor);
+            },
+            Action::CursorRightMost => {
+                state.cursor = query.len();
+            },
+            Action::Char(chr) => {
+                assert!(query.is_char_boundary(state.cursor), "internal error");
+                query.insert(state.cursor, chr);
+                state.cursor += chr.len_utf8();
+            },
+            Action::DeleteWord => {
+                action_delete_word(query, &mut state.cursor);
+            },
+            Action::DeleteLine => {
+                query.replace_range(..state.cursor, "");
+                state.cursor = 0;
+            },
+            Action::Delete => {
+                if state.cursor < query.len() {
+                    query.remove(state.cursor);
+                }
+            },
+            Action::Backspace => {
+                // remove the char *before* the cursor
+                if let Some(chr) = query[..state.cursor].chars().next_back() {
+                    state.cursor -= chr.len_utf8();
+                    query.remove(state.cursor);
+                }
+            }
+            Action::Pass => { },
+        }
+
+        None
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct WinSize {
+    x: usize,
+    y: usize,
+}
+
+// the window size of a terminal without a tty, and of one that can't tell
+const HEADLESS_SIZE: WinSize = WinSize { x: 80, y: 24 };
+
+// the size of the window of the tty at `fd`. `None` when the ioctl fails, as it does on
+// a pipe, and for the zero size of a pseudo-terminal that was never given one
+#[cfg(feature = "winsize")]
+fn tty_size(fd: RawFd) -> Option<WinSize> {
+    let mut win_size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut win_size) } != 0 {
+        return None;
+    }
+    let size = WinSize { x: win_size.ws_col as _, y: win_size.ws_row as _ };
+    (size.x > 0 && size.y > 0).then_some(size)
+}
+
+// without the ioctl the size always comes from `fallback_size`
+#[cfg(not(feature = "winsize"))]
+fn tty_size(_fd: RawFd) -> Option<WinSize> {
+    None
+}
+
+// `size`, or else the `COLUMNS` and `LINES` variables looked up with `var`, or else
+// `HEADLESS_SIZE`. each of the two falls back on its own
+fn fallback_size(size: Option<WinSize>, var: impl Fn(&str) -> Option<String>) -> WinSize {
+    if let Some(size) = size { return size; }
+    let dimension = |name, default| var(name)
+        .and_then(|value| value.trim().parse::<usize>().ok())
+        .filter(|&value| value > 0)
+        .unwrap_or(default);
+    WinSize { x: dimension("COLUMNS", HEADLESS_SIZE.x), y: dimension("LINES", HEADLESS_SIZE.y) }
+}
+
+/// writes the escape sequences that move the cursor, set colors and draw the candidates
+/// and prompt, to any `Write`
+pub struct Renderer<W: Write> {
+    writer: W,
+    tabstop: usize,
+}
+
+impl<W: Write> Renderer<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer, tabstop: TABSTOP }
+    }
+
+    /// draw the tabs of candidates up to the next multiple of `tabstop` columns
+    pub fn set_tabstop(&mut self, tabstop: usize) {
+        self.tabstop = tabstop;
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// the CSI sequence `ESC [ num chr`
+    pub fn csi(&mut self, num: usize, chr: char) -> io::Result<()> {
+        // the digits of `num`, filled in from the back
+        let mut digits = [0; 20];
+        let mut start = digits.len();
+        let mut num = num;
+        loop {
+            start -= 1;
+            digits[start] = b'0' + (num % 10) as u8;
+            num /= 10;
+            if num == 0 { break; }
+        }
+
+        self.writer.write_all(b"\x1b[")?;
+        self.writer.write_all(&digits[start..])?;
+        self.writer.write_all(&[chr as u8])?;
+        Ok(())
+    }
+
+    pub fn sgr(&mut self, code: Attributes) -> io::Result<()> {
+        self.sgr_params(&code.params())
+    }
+
+    fn sgr_params(&mut self, params: &str) -> io::Result<()> {
+        self.writer.write_all(b"\x1b[")?;
+        self.writer.write_all(params.as_bytes())?;
+        self.writer.write_all(b"m")?;
+        Ok(())
+    }
+
+    /// `text` with the `ranges` in color, back to the default color after each
+    pub fn highlighted(&mut self, text: &str, ranges: &[Range]) -> io::Result<()> {
+        for (flag, slice) in IterRanges::new(ranges, text.len()) {
+            if flag { self.sgr(Attributes::FgCyan)?; }
+            self.writer.write_all(&text.as_bytes()[slice.start..slice.end])?;
+            if flag { self.sgr(Attributes::FgDefault)?; }
+        }
+        Ok(())
+    }
+
+    pub fn cursor_up(&mut self, num: usize) -> io::Result<()> {
+        self.csi(num, 'A')
+    }
+
+    /// blank lines, scrolling the view when the cursor is at the bottom
+    pub fn scroll_down(&mut self, num: usize) -> io::Result<()> {
+        for _ in 0..num {
+            self.writer.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    /// switch to the alternate screen, with the cursor in the top left corner
+    pub fn enter_screen(&mut self, alt_screen: bool) -> io::Result<()> {
+        if alt_screen {
+            self.writer.write_all(b"\x1b[?1049h\x1b[H")?;
+        }
+        Ok(())
+    }
+
+    /// switch back to the normal screen and its scrollback
+    pub fn leave_screen(&mut self, alt_screen: bool) -> io::Result<()> {
+        if alt_screen {
+            self.writer.write_all(b"\x1b[?1049l")?;
+        }
+        Ok(())
+    }
+
+    pub fn bracketed_paste(&mut self, on: bool) -> io::Result<()> {
+        self.writer.write_all(if on { BRACKETED_PASTE_ON } else { BRACKETED_PASTE_OFF })
+    }
+
+    /// report clicks and the mouse wheel as keys
+    pub fn mouse(&mut self, on: bool) -> io::Result<()> {
+        self.writer.write_all(if on { MOUSE_ON } else { MOUSE_OFF })
+    }
+
+    /// erase the prompt line and the `height` lines of candidates below it, then
+    /// return the cursor to the start of the prompt line
+    pub fn erase_lines(&mut self, height: usize) -> io::Result<()> {
+        for line in 0..=height {
+            if line > 0 {
+                self.csi(1, 'B')?;
+            }
+            self.csi(1, 'G')?;
+            self.csi(2, 'K')?;
+        }
+        if height > 0 {
+            self.cursor_up(height)?;
+        }
+        Ok(())
+    }
+
+    /// one candidate, with the `ranges` that matched highlighted and cut to `width` columns.
+    /// `marked` is `None` when the marker column is hidden
+    pub fn candidate<'a>(
+        &mut self,
+        path: impl Into<Colored<'a>>,
+        ranges: &[Range],
+        width: usize,
+        selected: bool,
+        marked: Option<bool>,
+        keep_right: bool,
+    ) -> io::Result<()> {
+        let mut width = width;
+        if let Some(marked) = marked {
+            self.writer.write_all(if marked { MARKER } else { b"  " })?;
+            width = width.saturating_sub(MARKER.len());
+        }
+
+        let Colored { text: path, colors } = path.into();
+        // the terminal would move tabs to its own tab stops, leaving the widths off
+        let expanded = path.contains('\t').then(|| expand_tabs(path, ranges, colors, self.tabstop));
+        let (path, ranges, colors) = match &expanded {
+            Some((path, ranges, colors)) => (path.as_str(), ranges.as_slice(), colors.as_slice()),
+            None => (path, ranges, colors),
+        };
+        let out: io::Result<()> = {
+            if selected { self.sgr(Attributes::Reverse)?; }
+            let (offset, path) = visible_path(path, ranges, width, keep_right);
+            if offset > 0 {
+                self.writer.write_all(ELLIPSIS.as_bytes())?;
+            }
+
+            // move the ranges into the visible part
+            let ranges = Vec::from_iter(ranges.iter().filter(|r| r.end > offset).map(|r| Range {
+                start: r.start.max(offset) - offset,
+                end: r.end - offset,
+            }));
+
+            if !colors.is_empty() {
+                self.colored(path, offset, colors, &ranges, selected)?;
+            } else if ranges.is_empty() {
+                self.writer.write_all(path.as_bytes())?;
+            } else {
+                // matches on the selected line need a color that stands out against reverse video
+                let highlight = if selected { Attributes::FgBrightYellow } else { Attributes::FgCyan };
+                for (flag, slice) in IterRanges::new(&ranges, path.len()) {
+                    let segment = &path[slice.start..slice.end];
+                    if flag {
+                        self.sgr(highlight)?;
+                    } else {
+                        self.sgr(Attributes::FgDefault)?;
+                        if selected { self.sgr(Attributes::Reverse)?; }
+                    }
+
+                    self.writer.write_all(segment.as_bytes())?;
+                }
+            }
+
+            Ok(())
+        };
+
+        self.sgr(Attributes::Reset)?;
+        out
+    }
+
+    // draw `path`, the part of a text from `offset` on, in its `colors` with the `ranges`
+    // highlighted over them. colors within a highlight wait for its end
+    fn colored(&mut self, path: &str, offset: usize, colors: &[Sgr], ranges: &[Range], selected: bool) -> io::Result<()> {
+        let highlight = if selected { Attributes::FgBrightYellow } else { Attributes::FgCyan };
+        // the colors since the last reset, to restore after a highlight
+        let mut active = vec![];
+        let mut colors = colors.iter().peekable();
+        for (flag, slice) in IterRanges::new(ranges, path.len()) {
+            if flag { self.sgr(highlight)?; }
+            let mut start = slice.start;
+            loop {
+                // the ones before the visible part all apply at its start
+                while let Some(color) = colors.next_if(|color| color.at <= offset + start) {
+                    if color.is_reset() { active.clear(); } else { active.push(color); }
+                    if !flag {
+                        self.sgr_params(&color.params)?;
+                        if selected { self.sgr(Attributes::Reverse)?; }
+                    }
+                }
+                let end = colors.peek().map_or(slice.end, |color| (color.at - offset).min(slice.end));
+                self.writer.write_all(&path.as_bytes()[start..end])?;
+                if end == slice.end { break; }
+                start = end;
+            }
+            if flag {
+                self.sgr(Attributes::Reset)?;
+                if selected { self.sgr(Attributes::Reverse)?; }
+                for color in &active {
+                    self.sgr_params(&color.params)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // draw `height` lines of candidates below the prompt, then the prompt with the query
+    // and the match count, leaving the cursor at the edit location
+    fn frame(
+        &mut self,
+        session: &Session,
+        height: usize,
+        width: usize,
+        prompt: &str,
+        keep_right: bool,
+        preview: Option<&[String]>,
+    ) -> io::Result<()> {
+        let (state, query) = (&session.state, session.query.as_str());
+        let (candidates, filtered) = (&session.candidates, session.filtered.as_slice());
+
+        // the preview takes the right half, after a separator
+        let list_width = if preview.is_some() { width / 2 } else { width };
+
+        // draw the candidates, starting below the prompt or else above it at the top. without
+        // any rows the cursor stays on the prompt
+        let layout = session.layout;
+        if height > 0 && !layout.prompt_on_top() {
+            self.cursor_up(height)?;
+        }
+        for row in 0..height {
+            if layout.prompt_on_top() || row > 0 {
+                self.csi(1, 'B')?;
+            }
+            self.csi(1, 'G')?;
+            self.csi(2, 'K')?;
+            // the first line of the list is the one next to the prompt
+            let line = match layout {
+                Layout::Reverse => height - 1 - row,
+                Layout::Default | Layout::ReverseList => row,
+            };
+            let index = state.offset + line;
+            if let Some(scored) = filtered.get(index) {
+                // `None` when nothing is marked and the marker column is hidden
+                let marked = (!session.marked.is_empty()).then(|| session.marked.contains(&scored.index));
+                let candidate = &candidates[scored.index];
+                let path = Colored { text: candidate.text(), colors: &candidate.colors };
+                self.candidate(path, &scored.ranges, list_width, index == state.selected, marked, keep_right)?;
+            }
+
+            if let Some(preview) = preview {
+                self.csi(list_width + 1, 'G')?;
+                self.writer.write_all(PREVIEW_SEPARATOR.as_bytes())?;
+                let text = preview.get(row).map_or("", String::as_str);
+                self.writer.write_all(shrink_str(text, width.saturating_sub(list_width + str_width(PREVIEW_SEPARATOR))).as_bytes())?;
+            }
+        }
+        self.sgr(Attributes::Reset)?;
+        if height == 0 {
+        } else if layout.prompt_on_top() {
+            self.cursor_up(height)?;
+        } else {
+            self.csi(1, 'B')?;
+        }
+
+        // draw the prompt
+        let prompt_width = str_width(prompt);
+        self.csi(1, 'G')?;
+        self.csi(2, 'K')?;
+        self.writer.write_all(shrink_str(prompt, width).as_bytes())?;
+        self.writer.write_all(shrink_str(query, width.saturating_sub(prompt_width)).as_bytes())?;
+
+        // draw info if there is room, the counts alone when the selected index doesn't fit
+        let selected = (!filtered.is_empty()).then_some(state.selected);
+        let spinner = session.spinner.map(|tick| SPINNER[tick % SPINNER.len()]);
+        let info = info_strings(selected, filtered.len(), candidates.len()).into_iter()
+            .map(|info_str| match spinner {
+                Some(glyph) => format!("{} {}", glyph, info_str),
+                None => info_str,
+            })
+            .map(|info_str| (info_spacing(width, prompt_width, query, &info_str), info_str))
+            .find(|(spacing, _)| *spacing >= 1);
+
+        if let Some((spacing, info_str)) = info {
+            self.csi(spacing, 'C')?;
+            self.writer.write_all(info_str.as_bytes())?;
+        }
+
+        // position the cursor at the edit location
+        self.csi(1, 'G')?;
+        self.csi(cursor_offset(width, prompt_width, query, state.cursor), 'C')
+    }
+}
+
+// wait up to `timeout` for `fd` to become readable, returns whether it did
+fn wait_for_input(fd: RawFd, timeout: Duration) -> io::Result<bool> {
+    let mut pollfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+    match unsafe { libc::poll(&mut pollfd, 1, timeout) } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+const PREVIEW_SEPARATOR: &str = "│ ";
+
+// the most output read from a preview command
+const PREVIEW_MAX_BYTES: u64 = 64 * 1024;
+
+// the first `lines` lines printed by the shell `command`, with `{}` replaced by the quoted
+// `path`. the command is stopped once that much was read. when it can't be started at
+// all the error is shown instead
+fn run_preview(command: &str, path: &str, lines: usize) -> Vec<String> {
+    let quoted = format!("'{}'", path.replace('\'', "'\\''"));
+    let child = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command.replace("{}", &quoted))
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => return vec![format!("preview failed: {}", e)],
+    };
+
+    let mut output = vec![];
+    if let Some(stdout) = child.stdout.take() {
+        // long lines are cut when drawn, but shouldn't be read without limit
+        let reader = io::BufReader::new(stdout.take(PREVIEW_MAX_BYTES));
+        for line in io::BufRead::split(reader, b'\n').take(lines) {
+            let Ok(line) = line else { break; };
+            let line = String::from_utf8_lossy(&line).replace('\t', "    ");
+            output.push(line.chars().filter(|c| !c.is_control()).collect());
+        }
+    }
+
+    // whatever is left is not needed
+    let _ = child.kill();
+    let _ = child.wait();
+    output
+}
+
+// the columns `s` takes up in the terminal. wide CJK chars take two and combining chars none
+fn str_width(s: &str) -> usize {
+    UnicodeWidthStr::width(s)
+}
+
+// the column after the prompt where the cursor at byte `cursor` of `query` is shown,
+// kept inside a window `width` columns wide
+fn cursor_offset(width: usize, prompt_width: usize, query: &str, cursor: usize) -> usize {
+    width.saturating_sub(1).min(prompt_width + str_width(&query[..cursor]))
+}
+
+// the info shown right of the query, from the most detailed to the shortest: the 1-based
+// `selected` index among the `matched` candidates out of all `total` candidates, then
+// the matched and total counts alone
+fn info_strings(selected: Option<usize>, matched: usize, total: usize) -> [String; 2] {
+    let selected = selected.map_or(0, |selected| selected + 1);
+    [format!("{}/{} (of {})", selected, matched, total), format!("{}/{}", matched, total)]
+}
+
+// the gap between the query and the right aligned info string
+fn info_spacing(width: usize, prompt_width: usize, query: &str, info: &str) -> usize {
+    width.saturating_sub(prompt_width + str_width(query) + str_width(info))
+}
+
+const ELLIPSIS: &str = "…";
+
+// the part of `path` drawn in `width` columns and the byte offset it starts at, which is
+// shown after an ellipsis. the start of the path is shown, or with `keep_right` the end,
+// but the path scrolls when that would hide the last match
+fn visible_path<'a>(path: &'a str, ranges: &[Range], width: usize, keep_right: bool) -> (usize, &'a str) {
+    // ranges past the end of the path end with it
+    let last_end = ranges.iter().map(|r| r.end.min(path.len())).max()
+        .map(|mut end| { while !path.is_char_boundary(end) { end += 1; } end });
+    if str_width(path) <= width || (!keep_right && str_width(&path[..last_end.unwrap_or(0)]) <= width) {
+        return (0, shrink_str(path, width));
+    }
+
+    let inner = width.saturating_sub(str_width(ELLIPSIS));
+    let tail = path.len() - shrink_str_left(path, inner).len();
+    let offset = match last_end {
+        // scroll until the last match ends on the right edge
+        Some(last_end) if !keep_right || last_end <= tail => {
+            last_end - shrink_str_left(&path[..last_end], inner).len()
+        },
+        _ => tail,
+    };
+
+    if offset == 0 {
+        (0, shrink_str(path, width))
+    } else {
+        (offset, shrink_str(&path[offset..], inner))
+    }
+}
+
+// `text` with each tab turned into the spaces up to the next multiple of `tabstop` columns,
+// along with the `ranges` and `colors` moved to where their bytes end up
+fn expand_tabs(text: &str, ranges: &[Range], colors: &[Sgr], tabstop: usize) -> (String, Vec<Range>, Vec<Sgr>) {
+    let mut expanded = String::with_capacity(text.len());
+    // where each byte of `text` and its end end up
+    let mut offsets = Vec::with_capacity(text.len() + 1);
+    let mut column = 0;
+    for chr in text.chars() {
+        offsets.extend(std::iter::repeat_n(expanded.len(), chr.len_utf8()));
+        if chr == '\t' {
+            let spaces = tabstop - column % tabstop;
+            expanded.extend(std::iter::repeat_n(' ', spaces));
+            column += spaces;
+        } else {
+            expanded.push(chr);
+            column += UnicodeWidthChar::width(chr).unwrap_or(0);
+        }
+    }
+    offsets.push(expanded.len());
+
+    let offset = |at: usize| offsets[at.min(text.len())];
+    let ranges = Vec::from_iter(ranges.iter().map(|r| Range { start: offset(r.start), end: offset(r.end) }));
+    let colors = Vec::from_iter(colors.iter().map(|color| Sgr { at: offset(color.at), ..color.clone() }));
+    (expanded, ranges, colors)
+}
+
+// the longest suffix of `s` that fits in `width` columns
+fn shrink_str_left(s: &str, width: usize) -> &str {
+    let mut columns = 0;
+    for (idx, chr) in s.char_indices().rev() {
+        columns += UnicodeWidthChar::width(chr).unwrap_or(0);
+        if columns > width {
+            return &s[idx + chr.len_utf8()..];
+        }
+    }
+    s
+}
+
+// the longest prefix of `s` that fits in `width` columns
+fn shrink_str(s: &str, width: usize) -> &str {
+    let mut columns = 0;
+    for (idx, chr) in s.char_indices() {
+        columns += UnicodeWidthChar::width(chr).unwrap_or(0);
+        if columns > width {
+            return &s[..idx];
+        }
+    }
+    s
+}
+
+// splits `0..stop` into the highlighted `ranges` and the parts between them, as
+// `(highlighted, range)`. ranges may come in any order, overlap, be empty or
+// reach past `stop`
+struct IterRanges {
+    // ascending and apart, starting before `stop`
+    ranges: std::vec::IntoIter<Range>,
+    stop: usize,
+
+    last: Option<Range>,
+    start: usize,
+}
+
+impl IterRanges {
+    fn new(ranges: &[Range], stop: usize) -> Self {
+        let mut ranges = Vec::from_iter(ranges.iter().filter(|r| r.start < r.end && r.start < stop).cloned());
+        filter::merge_ranges(&mut ranges);
+        let mut ranges = ranges.into_iter();
+        let last = ranges.next();
+        Self {
+            ranges,
+            stop,
+
+            last,
+            start: 0,
+        }
+    }
+}
+
+impl Iterator for IterRanges {
+    type Item = (bool, Range);
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.start >= self.stop { return None; }
+
+        let out = match self.last {
+            Some(Range { start, end }) if self.start == start => {
+                let end = end.min(self.stop);
+                self.last = self.ranges.next();
+                (true, Range { start, end })
+            },
+            Some(Range { start, .. }) => (false, Range { start: self.start, end: start }),
+            None => (false, Range { start: self.start, end: self.stop }),
+        };
+        self.start = out.1.end;
+        Some(out)
+    }
+}
+
+// the start of the word before `cursor`, skipping the whitespace right before it
+fn word_start_before(query: &str, cursor: usize) -> usize {
+    let trimmed = query[..cursor].trim_end();
+    trimmed.rfind(char::is_whitespace).map(|i| i + 1).unwrap_or(0)
+}
+
+// the end of the word after `cursor`, skipping the whitespace right after it
+fn word_end_after(query: &str, cursor: usize) -> usize {
+    let tail = &query[cursor..];
+    let start = tail.len() - tail.trim_start().len();
+    let len = tail[start..].find(char::is_whitespace).unwrap_or(tail.len() - start);
+    cursor + start + len
+}
+
+// delete the whitespace before the cursor and then the word before that
+fn action_delete_word(query: &mut String, cursor: &mut usize) {
+    let start = word_start_before(query, *cursor);
+
+    query.replace_range(start..*cursor, "");
+    *cursor = start;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use crate::other_error;
+    use std::os::unix::prelude::FromRawFd;
+
+    fn candidates(paths: &[&str]) -> Vec<Candidate> {
+        let content = paths.join("\n");
+        Candidate::collect(content.as_bytes(), b'\n', false, false).unwrap()
+    }
+
+    // feed keys into a session the same way `Terminal::run` does
+    fn drive(session: &mut Session, keys: &[Key]) -> Option<Exit> {
+        for &key in keys {
+            session.update_filter();
+            if let Some(exit) = session.handle_keys([key]) {
+                return Some(exit);
+            }
+        }
+        session.update_filter();
+        None
+    }
+
+    #[test]
+    fn select_returns_original_index() {
+        let candidates = candidates(&["src/ui.rs", "src/lib.rs", "README.md"]);
+
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 10);
+        assert_eq!(drive(&mut session, &[Key::Enter]), Some(Exit::Select(0)));
+
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 10);
+        let exit = drive(&mut session, &[Key::Character('r'), Key::Character('e'), Key::Character('a'), Key::Enter]);
+        assert_eq!(exit, Some(Exit::Select(2)));
+        assert_eq!(candidates[2].path, "README.md");
+    }
+
+    #[test]
+    fn history_cycles_at_the_top() {
+        let candidates = candidates(&["src/ui.rs", "src/lib.rs", "README.md"]);
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 10);
+        session.history.entries = vec!["lib".into(), "ui".into()];
+
+        drive(&mut session, &[Key::Character('r')]);
+        drive(&mut session, &[Key::Up]);
+        assert_eq!(session.query, "ui");
+        drive(&mut session, &[Key::Up, Key::Up]);
+        assert_eq!(session.query, "lib");
+        drive(&mut session, &[Key::Down]);
+        assert_eq!(session.query, "ui");
+        // back to what was typed
+        drive(&mut session, &[Key::Down]);
+        assert_eq!(session.query, "r");
+        assert_eq!(drive(&mut session, &[Key::Down]), None);
+        assert_eq!((session.query.as_str(), session.state.selected), ("r", 1));
+
+        // below the top, up moves the selection
+        drive(&mut session, &[Key::Up]);
+        assert_eq!((session.query.as_str(), session.state.selected), ("r", 0));
+
+        // editing a recalled query stops going through the history
+        drive(&mut session, &[Key::Up, Key::Character('s')]);
+        assert_eq!(session.query, "uis");
+        drive(&mut session, &[Key::Down]);
+        assert_eq!(session.query, "uis");
+
+        // without a history, up at the top does nothing
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 10);
+        drive(&mut session, &[Key::Up]);
+        assert_eq!((session.query.as_str(), session.state.selected), ("", 0));
+    }
+
+    #[test]
+    fn select_without_matches() {
+        let candidates = candidates(&["src/ui.rs", "src/lib.rs"]);
+
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 10);
+        let exit = drive(&mut session, &[Key::Character('x'), Key::Enter]);
+        assert_eq!(exit, None);
+        assert_eq!(drive(&mut session, &[Key::Esc]), Some(Exit::Close));
+    }
+
+    #[test]
+    fn move_selection_within_bounds() {
+        let candidates = candidates(&["a", "b", "c", "d"]);
+
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 10);
+        drive(&mut session, &[Key::Down; 20]);
+        assert_eq!(session.state.selected, 3);
+        drive(&mut session, &[Key::Up, Key::Control(ctrl('p'))]);
+        assert_eq!(session.state.selected, 1);
+        drive(&mut session, &[Key::Up; 20]);
+        assert_eq!(session.state.selected, 0);
+
+        // not limited by the visible rows
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 2);
+        drive(&mut session, &[Key::Down; 20]);
+        assert_eq!(session.state.selected, 3);
+        assert_eq!(drive(&mut session, &[Key::Enter]), Some(Exit::Select(3)));
+
+        // nothing to select
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 10);
+        drive(&mut session, &[Key::Character('x'), Key::Down, Key::Down, Key::Up]);
+        assert!(session.filtered.is_empty());
+        assert_eq!(session.state.selected, 0);
+    }
+
+    fn type_str(session: &mut Session, s: &str) {
+        let keys = Vec::from_iter(s.chars().map(Key::Character));
+        drive(session, &keys);
+    }
+
+    #[test]
+    fn move_cursor_and_insert() {
+        let candidates = candidates(&["a"]);
+
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 10);
+        type_str(&mut session, "ac");
+        drive(&mut session, &[Key::Left]);
+        assert_eq!(session.state.cursor, 1);
+        type_str(&mut session, "b");
+        assert_eq!(session.query, "abc");
+        assert_eq!(session.state.cursor, 2);
+
+        drive(&mut session, &[Key::Control(ctrl('a'))]);
+        assert_eq!(session.state.cursor, 0);
+        drive(&mut session, &[Key::Left, Key::Control(ctrl('b'))]);
+        assert_eq!(session.state.cursor, 0);
+        type_str(&mut session, ">");
+        assert_eq!(session.query, ">abc");
+
+        drive(&mut session, &[Key::Control(ctrl('e'))]);
+        assert_eq!(session.state.cursor, 4);
+        drive(&mut session, &[Key::Right, Key::Control(ctrl('f'))]);
+        assert_eq!(session.state.cursor, 4);
+        drive(&mut session, &[Key::Control(ctrl('a')), Key::Right, Key::Control(ctrl('f'))]);
+        assert_eq!(session.state.cursor, 2);
+        type_str(&mut session, "_");
+        assert_eq!(session.query, ">a_bc");
+    }
+
+    fn check_delete_word(query: &str, cursor: usize, expect: &str, expect_cursor: usize) {
+        let mut query = query.to_owned();
+        let mut cursor = cursor;
+        action_delete_word(&mut query, &mut cursor);
+        assert_eq!(query, expect);
+        assert_eq!(cursor, expect_cursor);
+    }
+
+    #[test]
+    fn delete_word() {
+        check_delete_word("foo bar", 7, "foo ", 4);
+        check_delete_word("foo bar ", 8, "foo ", 4);
+        check_delete_word("foo bar", 5, "foo ar", 4);
+        check_delete_word("foo bar", 4, "bar", 0);
+        check_delete_word("foo", 3, "", 0);
+        check_delete_word("foo", 0, "foo", 0);
+        check_delete_word("   ", 3, "", 0);
+        check_delete_word("", 0, "", 0);
+
+        let candidates = candidates(&["a"]);
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 10);
+        type_str(&mut session, "foo bar");
+        drive(&mut session, &[Key::Control(ctrl('w'))]);
+        assert_eq!(session.query, "foo ");
+        assert_eq!(session.state.cursor, 4);
+    }
+
+    #[test]
+    fn delete_line() {
+        let candidates = candidates(&["src/ui.rs", "src/lib.rs", "README.md"]);
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 10);
+        type_str(&mut session, "srclib");
+        assert_eq!(session.filtered.len(), 1);
+
+        drive(&mut session, &[Key::Left, Key::Left, Key::Left, Key::Control(ctrl('u'))]);
+        assert_eq!(session.query, "lib");
+        assert_eq!(session.state.cursor, 0);
+        assert_eq!(session.filtered.len(), 1);
+        assert_eq!(candidates[session.filtered[0].index].path, "src/lib.rs");
+
+        drive(&mut session, &[Key::Control(ctrl('e')), Key::Control(ctrl('u'))]);
+        assert_eq!(session.query, "");
+        assert_eq!(session.filtered.len(), 3);
+    }
+
+    #[test]
+    fn delete_forward() {
+        let candidates = candidates(&["a"]);
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 10);
+
+        drive(&mut session, &[Key::Delete, Key::Control(ctrl('d'))]);
+        assert_eq!(session.query, "");
+        assert_eq!(session.state.cursor, 0);
+
+        type_str(&mut session, "abc");
+        drive(&mut session, &[Key::Delete]);
+        assert_eq!(session.query, "abc");
+        assert_eq!(session.state.cursor, 3);
+
+        drive(&mut session, &[Key::Left, Key::Left, Key::Delete]);
+        assert_eq!(session.query, "ac");
+        assert_eq!(session.state.cursor, 1);
+
+        drive(&mut session, &[Key::Control(ctrl('a')), Key::Control(ctrl('d'))]);
+        assert_eq!(session.query, "c");
+        assert_eq!(session.state.cursor, 0);
+    }
+
+    #[test]
+    fn backspace_mid_string() {
+        let candidates = candidates(&["a"]);
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 10);
+
+        type_str(&mut session, "abc");
+        drive(&mut session, &[Key::Left, Key::Backspace]);
+        assert_eq!(session.query, "ac");
+        assert_eq!(session.state.cursor, 1);
+
+        drive(&mut session, &[Key::Control(ctrl('h')), Key::Backspace]);
+        assert_eq!(session.query, "c");
+        assert_eq!(session.state.cursor, 0);
+
+        drive(&mut session, &[Key::Right, Key::Backspace, Key::Backspace]);
+        assert_eq!(session.query, "");
+        assert_eq!(session.state.cursor, 0);
+    }
+
+    fn read_chars(mut bytes: &[u8]) -> Vec<Key> {
+        let mut keys = vec![];
+        let mut byte = 0;
+        while bytes.read_exact(std::slice::from_mut(&mut byte)).is_ok() {
+            keys.extend(read_utf8(byte, &mut bytes).map(Key::Character));
+        }
+        keys
+    }
+
+    #[test]
+    fn multibyte_input() {
+        assert_eq!(read_chars("café".as_bytes()), [Key::Character('c'), Key::Character('a'), Key::Character('f'), Key::Character('é')]);
+        assert_eq!(read_chars("日本".as_bytes()), [Key::Character('日'), Key::Character('本')]);
+        assert_eq!(read_chars(b"\xe6\x97"), []);
+        assert_eq!(read_chars(b"\xff"), []);
+
+        let candidates = candidates(&["a"]);
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 10);
+        drive(&mut session, &read_chars("café".as_bytes()));
+        assert_eq!(session.query, "café");
+        assert_eq!(session.state.cursor, 5);
+
+        drive(&mut session, &[Key::Left, Key::Left]);
+        assert_eq!(session.state.cursor, 2);
+        drive(&mut session, &read_chars("日本".as_bytes()));
+        assert_eq!(session.query, "ca日本fé");
+        drive(&mut session, &[Key::Right, Key::Right, Key::Backspace, Key::Left, Key::Delete]);
+        assert_eq!(session.query, "ca日本");
+        assert_eq!(session.state.cursor, "ca日本".len());
+    }
+
+    #[test]
+    fn select_maps_to_original_path() {
+        let candidates = candidates(&["src/ui.rs", "src/lib.rs", "lib/ui.zig", "src/ui.rs"]);
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 10);
+
+        type_str(&mut session, "ui");
+        let order = Vec::from_iter(session.filtered.iter().map(|s| candidates[s.index].path.as_str()));
+        assert_eq!(order, ["src/ui.rs", "src/ui.rs", "lib/ui.zig"]);
+
+        assert_eq!(drive(&mut session, &[Key::Down, Key::Down, Key::Enter]), Some(Exit::Select(2)));
+        assert_eq!(drive(&mut session, &[Key::Up, Key::Enter]), Some(Exit::Select(3)));
+    }
+
+    #[test]
+    fn scroll_with_selection() {
+        let paths = Vec::from_iter((0..20).map(|i| format!("file{}", i)));
+        let candidates = candidates(&Vec::from_iter(paths.iter().map(String::as_str)));
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 5);
+
+        drive(&mut session, &[Key::Down; 4]);
+        assert_eq!((session.state.selected, session.state.offset), (4, 0));
+        drive(&mut session, &[Key::Down]);
+        assert_eq!((session.state.selected, session.state.offset), (5, 1));
+        drive(&mut session, &[Key::Down; 30]);
+        assert_eq!((session.state.selected, session.state.offset), (19, 15));
+        assert_eq!(drive(&mut session, &[Key::Enter]), Some(Exit::Select(19)));
+
+        drive(&mut session, &[Key::Up; 4]);
+        assert_eq!((session.state.selected, session.state.offset), (15, 15));
+        drive(&mut session, &[Key::Up]);
+        assert_eq!((session.state.selected, session.state.offset), (14, 14));
+        drive(&mut session, &[Key::Up; 30]);
+        assert_eq!((session.state.selected, session.state.offset), (0, 0));
+
+        // a new query resets the view
+        drive(&mut session, &[Key::Down; 10]);
+        type_str(&mut session, "1");
+        assert_eq!((session.state.selected, session.state.offset), (0, 0));
+    }
+
+    #[test]
+    fn height_rows() {
+        assert_eq!(Height::Lines(10).rows(40), 10);
+        assert_eq!(Height::Lines(100).rows(40), 39);
+        assert_eq!(Height::Percent(40).rows(40), 16);
+        assert_eq!(Height::Percent(100).rows(40), 39);
+        assert_eq!(Height::Percent(1).rows(40), 1);
+        assert_eq!(Height::Percent(0).rows(40), 1);
+
+        // a window with no room next to the prompt, or none at all
+        for rows in [0, 1, 2] {
+            assert_eq!(Height::Lines(10).rows(rows), 1);
+            assert_eq!(Height::Percent(100).rows(rows), 1);
+            assert_eq!(Height::Percent(0).rows(rows), 1);
+        }
+    }
+
+    #[test]
+    fn display_width() {
+        assert_eq!(str_width("abc"), 3);
+        assert_eq!(str_width("日本語"), 6);
+        // e followed by a combining acute accent
+        assert_eq!(str_width("cafe\u{301}"), 4);
+
+        let query = "日本e\u{301}x";
+        assert_eq!(info_spacing(20, 2, query, "1/2"), 20 - 2 - 6 - 3);
+        assert_eq!(cursor_offset(20, 2, query, 0), 2);
+        assert_eq!(cursor_offset(20, 2, query, "日".len()), 4);
+        assert_eq!(cursor_offset(20, 2, query, "日本e\u{301}".len()), 7);
+        assert_eq!(cursor_offset(20, 2, query, query.len()), 8);
+        assert_eq!(cursor_offset(6, 2, query, query.len()), 5);
+    }
+
+    #[test]
+    fn shrink_to_width() {
+        assert_eq!(shrink_str("src/lib.rs", 20), "src/lib.rs");
+        assert_eq!(shrink_str("src/lib.rs", 10), "src/lib.rs");
+        assert_eq!(shrink_str("src/lib.rs", 5), "src/l");
+        assert_eq!(shrink_str("src/lib.rs", 0), "");
+        assert_eq!(shrink_str("", 3), "");
+
+        assert_eq!(shrink_str("éàü/ö", 3), "éàü");
+        assert_eq!(shrink_str("e\u{301}e\u{301}", 1), "e\u{301}");
+        // a wide char that doesn't fit is left out entirely
+        assert_eq!(shrink_str("日本語", 3), "日");
+        assert_eq!(shrink_str("日本語", 4), "日本");
+        assert_eq!(shrink_str("日本語", 1), "");
+    }
+
+    #[test]
+    fn scroll_long_paths() {
+        let path = "some/very/long/directory/name/file.rs";
+        let some = [Range::new(0, 4)];
+        let both = [Range::new(0, 4), Range::new(30, 34)];
+
+        // short enough, or the matches are already visible
+        assert_eq!(visible_path("src/lib.rs", &[], 20, true), (0, "src/lib.rs"));
+        assert_eq!(visible_path(path, &some, 10, false), (0, "some/very/"));
+
+        // the end of the path after an ellipsis
+        assert_eq!(visible_path(path, &[], 10, true), (28, "e/file.rs"));
+
+        // scrolled so the last match stays on screen
+        assert_eq!(visible_path(path, &some, 10, true), (0, "some/very/"));
+        assert_eq!(visible_path(path, &both, 10, false), (25, "name/file"));
+        assert_eq!(visible_path(path, &both, 10, true), (28, "e/file.rs"));
+
+        let mut out = vec![];
+        Renderer::new(&mut out).candidate(path, &both, 10, false, None, false).unwrap();
+        assert_eq!(out, "…\x1b[39mname/\x1b[36mfile\x1b[0m".as_bytes());
+
+        assert_eq!(shrink_str_left("日本語", 5), "本語");
+        assert_eq!(shrink_str_left("abc", 0), "");
+    }
+
+    #[test]
+    fn info_aligns_after_prompt() {
+        let prompt = "search❯ ";
+        assert_eq!(str_width(prompt), 8);
+
+        // the info string ends on the last column
+        let spacing = info_spacing(40, str_width(prompt), "abc", "3/10");
+        assert_eq!(spacing, 25);
+        assert_eq!(str_width(prompt) + 3 + spacing + 4, 40);
+
+        let spacing = info_spacing(40, str_width(prompt), "日本é", "3/10");
+        assert_eq!(str_width(prompt) + 5 + spacing + 4, 40);
+
+        // no room
+        assert_eq!(info_spacing(10, str_width(prompt), "abc", "3/10"), 0);
+    }
+
+    #[test]
+    fn info_selected_index() {
+        assert_eq!(info_strings(Some(2), 127, 900), ["3/127 (of 900)", "127/900"]);
+        assert_eq!(info_strings(None, 0, 900), ["0/0 (of 900)", "0/900"]);
+
+        let candidates = candidates(&["src/lib.rs", "src/ui.rs", "README.md", "Cargo.toml"]);
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 3);
+        drive(&mut session, &[Key::Character('s'), Key::Down]);
+        let frame = |width| {
+            let mut out = vec![];
+            Renderer::new(&mut out).frame(&session, 3, width, "> ", false, None).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert!(frame(40).contains("2/2 (of 4)"));
+        // too narrow for the index, but not for the counts
+        assert!(frame(12).contains("2/4") && !frame(12).contains("(of"));
+        assert!(!frame(6).contains("2/4"));
+    }
+
+    #[test]
+    fn literal_session() {
+        let candidates = candidates(&["bar/foo", "foo barista"]);
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 10);
+        session.literal = true;
+        type_str(&mut session, "foo bar");
+        session.update_filter();
+        assert_eq!(Vec::from_iter(session.filtered.iter().map(|s| s.index)), [1]);
+
+        // streamed candidates are matched the same way
+        session.extend(Vec::from_iter(candidates.iter().cloned()));
+        assert_eq!(Vec::from_iter(session.filtered.iter().map(|s| s.index)), [1, 3]);
+    }
+
+    #[test]
+    fn incremental_filter() {
+        let candidates = candidates(&["src/main.rs", "src/Main.zig", "README.md", "src/lib.rs", "lib/main.rs", "main"]);
+        for keep_order in [false, true] {
+            for query in ["src main", "'src ma", "src !li", "Ma"] {
+                let mut session = Session::new(&candidates, keep_order, Tiebreak::Length, 10);
+                for end in 1..=query.len() {
+                    type_str(&mut session, &query[end - 1..end]);
+                    let all = filter::score_candidates(&candidates, &query[..end], keep_order, Tiebreak::Length, CaseMode::Smart, None);
+                    assert_eq!(session.filtered, all, "{:?}", &query[..end]);
+                }
+                // and back
+                drive(&mut session, &[Key::Backspace, Key::Backspace]);
+                let all = filter::score_candidates(&candidates, &query[..query.len() - 2], keep_order, Tiebreak::Length, CaseMode::Smart, None);
+                assert_eq!(session.filtered, all);
+            }
+        }
+    }
+
+    #[test]
+    fn start_with_query() {
+        let candidates = candidates(&["src/ui.rs", "src/lib.rs", "README.md"]);
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 10);
+        session.set_query("lib");
+
+        drive(&mut session, &[]);
+        assert_eq!(session.filtered.len(), 1);
+        assert_eq!(session.state.cursor, 3);
+
+        type_str(&mut session, ".r");
+        assert_eq!(session.query, "lib.r");
+        assert_eq!(drive(&mut session, &[Key::Enter]), Some(Exit::Select(1)));
+    }
+
+    #[test]
+    fn erase_drawn_lines() {
+        let mut out = vec![];
+        Renderer::new(&mut out).erase_lines(2).unwrap();
+        assert_eq!(out, b"\x1b[1G\x1b[2K\x1b[1B\x1b[1G\x1b[2K\x1b[1B\x1b[1G\x1b[2K\x1b[2A");
+
+        let mut out = vec![];
+        Renderer::new(&mut out).erase_lines(0).unwrap();
+        assert_eq!(out, b"\x1b[1G\x1b[2K");
+    }
+
+    #[test]
+    fn finish_with_candidate() {
+        let candidates = candidates(&["src/ui.rs", "src/lib.rs", "README.md"]);
+
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 10);
+        type_str(&mut session, "lib");
+        let exit = drive(&mut session, &[Key::Enter]).unwrap();
+        let selected = session.finish(exit).unwrap().candidates;
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].path, "src/lib.rs");
+        assert_eq!(selected[0].ranges, [Range::new(4, 7)]);
+
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 10);
+        let exit = drive(&mut session, &[Key::Down, Key::Control(ctrl('c'))]).unwrap();
+        assert_eq!(session.finish(exit), None);
+    }
+
+    #[test]
+    fn expected_keys() {
+        let candidates = candidates(&["src/ui.rs", "src/lib.rs", "README.md"]);
+        let ctrl_x = Key::Control(ctrl('x'));
+
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 10);
+        session.expect = vec![ctrl_x, Key::Left];
+        type_str(&mut session, "lib");
+        let exit = drive(&mut session, &[ctrl_x]).unwrap();
+        assert_eq!(exit, Exit::Expect(ctrl_x));
+        let selection = session.finish(exit).unwrap();
+        assert_eq!(selection.query, "lib");
+        assert_eq!(selection.key, Some(ctrl_x));
+        assert_eq!(selection.candidates[0].path, "src/lib.rs");
+
+        // enter still selects, without a key
+        let exit = drive(&mut session, &[Key::Enter]).unwrap();
+        let selection = session.finish(exit).unwrap();
+        assert_eq!((selection.query.as_str(), selection.key), ("lib", None));
+
+        // an expected key ends the search even without matches
+        type_str(&mut session, "zzz");
+        let exit = drive(&mut session, &[Key::Left]).unwrap();
+        let selection = session.finish(exit).unwrap();
+        assert_eq!(selection.key, Some(Key::Left));
+        assert!(selection.candidates.is_empty());
+    }
+
+    #[test]
+    fn remapped_keys() {
+        let candidates = candidates(&["src/ui.rs", "src/lib.rs", "README.md"]);
+        let (ctrl_j, ctrl_k) = (Key::Control(ctrl('j')), Key::Control(ctrl('k')));
+
+        // by default ctrl-j moves down
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 10);
+        assert_eq!(drive(&mut session, &[ctrl_j]), None);
+        assert_eq!(session.state.selected, 1);
+
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 10);
+        session.bindings = HashMap::from([(ctrl_j, Action::Select), (Key::Up, Action::LineDown)]);
+        assert_eq!(drive(&mut session, &[Key::Up, Key::Up]), None);
+        assert_eq!(session.state.selected, 2);
+        // unbound keys keep their defaults
+        assert_eq!(drive(&mut session, &[ctrl_k]), None);
+        assert_eq!(session.state.selected, 1);
+        assert_eq!(drive(&mut session, &[ctrl_j]), Some(Exit::Select(1)));
+    }
+
+    #[test]
+    fn action_names() {
+        for &(name, action) in ACTION_NAMES {
+            assert_eq!(Action::from_name(name), Some(action));
+            assert_eq!(action.name(), Some(name));
+        }
+        assert_eq!(Action::from_name("select"), Some(Action::Select));
+        assert_eq!(Action::from_name("Select"), None);
+        assert_eq!(Action::Char('x').name(), None);
+    }
+
+    #[test]
+    fn escape_sequences() {
+        let key = |bytes: &[u8]| read_escape(bytes).key();
+        assert_eq!(key(b"b"), Key::Alt(b'b'));
+        assert_eq!(key(b"B"), Key::Alt(b'B'));
+        assert_eq!(key(b"\x7f"), Key::Alt(127));
+        assert_eq!(key(b""), Key::Esc);
+        assert_eq!(key(b"\x01"), Key::Esc);
+        assert_eq!(key(b"["), Key::Alt(b'['));
+        assert_eq!(key(b"O"), Key::Alt(b'O'));
+
+        // arrows and delete are kept
+        assert_eq!(key(b"[A"), Key::Up);
+        assert_eq!(key(b"OB"), Key::Down);
+        assert_eq!(key(b"[C"), Key::Right);
+        assert_eq!(key(b"[D"), Key::Left);
+        assert_eq!(key(b"[3~"), Key::Delete);
+        assert_eq!(key(b"[3"), Key::Esc);
+        assert_eq!(key(b"[Z"), Key::Esc);
+    }
+
+    #[test]
+    fn mouse_sequences() {
+        let read = |bytes: &[u8]| {
+            let mut bytes = bytes;
+            let escape = read_escape(&mut bytes);
+            (escape, bytes.to_vec())
+        };
+        let mouse = |button, x, y| Escape::Key(Key::Mouse { button, x, y });
+
+        assert_eq!(read(b"[<0;12;5Mx"), (mouse(MouseButton::Left, 12, 5), b"x".to_vec()));
+        assert_eq!(read(b"[<0;12;5m"), (mouse(MouseButton::Other, 12, 5), vec![]));
+        assert_eq!(read(b"[<2;1;130M"), (mouse(MouseButton::Right, 1, 130), vec![]));
+        assert_eq!(read(b"[<64;3;4M"), (mouse(MouseButton::WheelUp, 3, 4), vec![]));
+        assert_eq!(read(b"[<65;3;4M"), (mouse(MouseButton::WheelDown, 3, 4), vec![]));
+        // with ctrl held down, and dragging
+        assert_eq!(read(b"[<16;3;4M"), (mouse(MouseButton::Left, 3, 4), vec![]));
+        assert_eq!(read(b"[<32;3;4M"), (mouse(MouseButton::Other, 3, 4), vec![]));
+        assert_eq!(read(b"[<0;3M"), (Escape::Key(Key::Esc), vec![]));
+
+        // the cursor position report
+        assert_eq!(read(b"[17;1Ra"), (Escape::Position(17), b"a".to_vec()));
+        assert_eq!(read(b"[17R"), (Escape::Key(Key::Esc), vec![]));
+    }
+
+    #[test]
+    fn mouse_selects() {
+        let candidates = candidates(&["src/lib.rs", "src/ui.rs", "README.md"]);
+        let click = |y| Key::Mouse { button: MouseButton::Left, x: 4, y };
+        let mut session = Session::new(&candidates, true, Tiebreak::Length, 3);
+
+        // nowhere to click before the prompt's row is known
+        drive(&mut session, &[click(12)]);
+        assert_eq!(session.state.selected, 0);
+
+        session.prompt_row = Some(10);
+        drive(&mut session, &[click(12), click(10), click(14), click(9)]);
+        assert_eq!(session.state.selected, 1);
+        drive(&mut session, &[click(13)]);
+        assert_eq!(session.state.selected, 2);
+        assert_eq!(drive(&mut session, &[click(13)]), Some(Exit::Select(2)));
+
+        let wheel = |button| Key::Mouse { button, x: 1, y: 1 };
+        drive(&mut session, &[wheel(MouseButton::WheelUp), wheel(MouseButton::WheelUp)]);
+        assert_eq!(session.state.selected, 0);
+        drive(&mut session, &[wheel(MouseButton::WheelDown)]);
+        assert_eq!(session.state.selected, 1);
+
+        // the rows above the prompt, read from the bottom or the top
+        session.layout = Layout::Reverse;
+        drive(&mut session, &[click(7), click(10)]);
+        assert_eq!(session.state.selected, 2);
+        drive(&mut session, &[wheel(MouseButton::WheelDown)]);
+        assert_eq!(session.state.selected, 1);
+        session.layout = Layout::ReverseList;
+        drive(&mut session, &[click(7), click(6)]);
+        assert_eq!(session.state.selected, 0);
+    }
+
+    #[test]
+    fn extended_sequences() {
+        let key = |bytes: &[u8]| read_escape(bytes).key();
+        assert_eq!(key(b"[H"), Key::Home);
+        assert_eq!(key(b"OH"), Key::Home);
+        assert_eq!(key(b"[1~"), Key::Home);
+        assert_eq!(key(b"[7~"), Key::Home);
+        assert_eq!(key(b"[F"), Key::End);
+        assert_eq!(key(b"OF"), Key::End);
+        assert_eq!(key(b"[4~"), Key::End);
+        assert_eq!(key(b"[8~"), Key::End);
+        assert_eq!(key(b"[5~"), Key::PageUp);
+        assert_eq!(key(b"[6~"), Key::PageDown);
+
+        // multi-digit parameters are read to the end, even if unknown
+        assert_eq!(key(b"[15~"), Key::Esc);
+        assert_eq!(key(b"[99999999999999999999999~"), Key::Esc);
+        assert_eq!(key(b"[5"), Key::Esc);
+
+        // the rest of the input is left alone
+        let mut bytes = &b"[5;5~x"[..];
+        assert_eq!(read_escape(&mut bytes).key(), Key::PageUp);
+        assert_eq!(bytes, b"x");
+        let mut bytes = &b"[6~x"[..];
+        assert_eq!(read_escape(&mut bytes).key(), Key::PageDown);
+        assert_eq!(bytes, b"x");
+    }
+
+    #[test]
+    fn variable_length_sequences() {
+        // reads the whole sequence, and the key after it is read on its own
+        let read = |bytes: &[u8]| {
+            let mut bytes = bytes;
+            let key = read_escape(&mut bytes).key();
+            (key, bytes.to_vec())
+        };
+        assert_eq!(read(b"[1;5Cx"), (Key::Right, b"x".to_vec()));
+        assert_eq!(read(b"[1;5D\x1b"), (Key::Left, b"\x1b".to_vec()));
+        assert_eq!(read(b"[1;2A["), (Key::Up, b"[".to_vec()));
+        assert_eq!(read(b"[1;3B"), (Key::Down, vec![]));
+        assert_eq!(read(b"[1;5H"), (Key::Home, vec![]));
+        assert_eq!(read(b"[3;5~a"), (Key::Delete, b"a".to_vec()));
+        assert_eq!(read(b"[6;2~b"), (Key::PageDown, b"b".to_vec()));
+
+        // unknown sequences end at their final byte too
+        assert_eq!(read(b"[200~text"), (Key::Esc, b"text".to_vec()));
+        assert_eq!(read(b"[?1;2cz"), (Key::Esc, b"z".to_vec()));
+        assert_eq!(read(b"[ qz"), (Key::Esc, b"z".to_vec()));
+        assert_eq!(read(b"[1;"), (Key::Esc, vec![]));
+        // a byte that can't be in a sequence ends it
+        assert_eq!(read(b"[1\ny"), (Key::Esc, b"y".to_vec()));
+    }
+
+    #[test]
+    fn page_and_ends() {
+        let paths = Vec::from_iter((0..20).map(|i| format!("file{:02}", i)));
+        let candidates = candidates(&Vec::from_iter(paths.iter().map(String::as_str)));
+        let mut session = Session::new(&candidates, true, Tiebreak::Length, 5);
+
+        drive(&mut session, &[Key::PageDown]);
+        assert_eq!((session.state.selected, session.state.offset), (5, 1));
+        drive(&mut session, &[Key::PageDown, Key::PageDown, Key::PageDown]);
+        assert_eq!((session.state.selected, session.state.offset), (19, 15));
+        drive(&mut session, &[Key::PageUp]);
+        assert_eq!((session.state.selected, session.state.offset), (14, 14));
+        drive(&mut session, &[Key::Home]);
+        assert_eq!((session.state.selected, session.state.offset), (0, 0));
+        drive(&mut session, &[Key::PageUp]);
+        assert_eq!((session.state.selected, session.state.offset), (0, 0));
+        drive(&mut session, &[Key::End]);
+        assert_eq!((session.state.selected, session.state.offset), (19, 15));
+
+        // nothing to select
+        type_str(&mut session, "zzz");
+        drive(&mut session, &[Key::End, Key::PageDown]);
+        assert_eq!((session.state.selected, session.state.offset), (0, 0));
+    }
+
+    #[test]
+    fn alt_word_motion() {
+        let candidates = candidates(&["a"]);
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 10);
+        type_str(&mut session, "foo  bar baz");
+
+        drive(&mut session, &[Key::Alt(b'b')]);
+        assert_eq!(session.state.cursor, 9);
+        drive(&mut session, &[Key::Alt(b'b'), Key::Alt(b'b')]);
+        assert_eq!(session.state.cursor, 0);
+        drive(&mut session, &[Key::Alt(b'b')]);
+        assert_eq!(session.state.cursor, 0);
+
+        drive(&mut session, &[Key::Alt(b'f')]);
+        assert_eq!(session.state.cursor, 3);
+        drive(&mut session, &[Key::Alt(b'f')]);
+        assert_eq!(session.state.cursor, 8);
+
+        drive(&mut session, &[Key::Alt(127)]);
+        assert_eq!((session.query.as_str(), session.state.cursor), ("foo   baz", 5));
+
+        // unmapped alt keys do nothing
+        drive(&mut session, &[Key::Alt(b'x')]);
+        assert_eq!((session.query.as_str(), session.state.cursor), ("foo   baz", 5));
+        drive(&mut session, &[Key::Alt(b'f')]);
+        assert_eq!(session.state.cursor, 9);
+    }
+
+    #[test]
+    fn key_names() {
+        for name in ["ctrl-a", "ctrl-z", "alt-b", "alt-.", "alt-bspace", "home", "end", "pgup", "pgdn", "tab", "enter", "esc", "up", "down", "left", "right", "bspace", "del"] {
+            let key = Key::from_name(name).unwrap();
+            assert_eq!(key.to_string(), name);
+        }
+        assert_eq!(Key::from_name("ctrl-x"), Some(Key::Control(ctrl('x'))));
+        assert_eq!(Key::from_name("tab"), Some(Key::Control(ctrl('i'))));
+        for name in ["", "x", "ctrl-", "ctrl-ab", "ctrl-X", "ctrl-1", "space", "alt-", "alt-é", "alt- "] {
+            assert_eq!(Key::from_name(name), None);
+        }
+    }
+
+    #[test]
+    fn mark_several() {
+        let candidates = candidates(&["src/ui.rs", "src/lib.rs", "README.md", "Cargo.toml"]);
+        let tab = Key::Control(ctrl('i'));
+
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 10);
+        drive(&mut session, &[tab, Key::Down, tab]);
+        assert_eq!(session.marked, [0, 2]);
+        assert_eq!(session.state.selected, 3);
+
+        // marks survive a query change
+        type_str(&mut session, "lib");
+        drive(&mut session, &[tab]);
+        assert_eq!(session.marked, [0, 2, 1]);
+
+        // toggling again unmarks
+        drive(&mut session, &[Key::Control(ctrl('u'))]);
+        drive(&mut session, &[tab]);
+        assert_eq!(session.marked, [2, 1]);
+
+        let exit = drive(&mut session, &[Key::Enter]).unwrap();
+        let selected = session.finish(exit).unwrap().candidates;
+        assert_eq!(Vec::from_iter(selected.iter().map(|c| c.path.as_str())), ["README.md", "src/lib.rs"]);
+
+        // nothing to mark
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 10);
+        drive(&mut session, &[Key::Character('x'), tab]);
+        assert!(session.marked.is_empty());
+    }
+
+    #[test]
+    fn draw_selected_candidate() {
+        let ranges = [Range::new(4, 7)];
+
+        let mut out = vec![];
+        Renderer::new(&mut out).candidate("src/lib.rs", &ranges, 80, false, None, false).unwrap();
+        assert_eq!(out, b"\x1b[39msrc/\x1b[36mlib\x1b[39m.rs\x1b[0m");
+
+        let mut out = vec![];
+        Renderer::new(&mut out).candidate("src/lib.rs", &ranges, 80, true, None, false).unwrap();
+        assert_eq!(out, b"\x1b[7m\x1b[39m\x1b[7msrc/\x1b[93mlib\x1b[39m\x1b[7m.rs\x1b[0m");
+
+        let mut out = vec![];
+        Renderer::new(&mut out).candidate("src/lib.rs", &[], 80, true, Some(true), false).unwrap();
+        assert_eq!(out, b"* \x1b[7msrc/lib.rs\x1b[0m");
+    }
+
+    #[test]
+    fn draw_colored_candidate() {
+        let sgr = |at, params: &str| Sgr { at, params: params.to_owned() };
+        let draw = |colors: &[Sgr], ranges: &[Range], width, selected| {
+            let mut out = vec![];
+            let path = Colored { text: "src/lib.rs", colors };
+            Renderer::new(&mut out).candidate(path, ranges, width, selected, None, false).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        // a reset within the highlight waits for its end
+        let colors = [sgr(0, "34"), sgr(4, "0")];
+        assert_eq!(draw(&colors, &[], 80, false), "\x1b[34msrc/\x1b[0mlib.rs\x1b[0m");
+        assert_eq!(draw(&colors, &[Range::new(2, 6)], 80, false), "\x1b[34msr\x1b[36mc/li\x1b[0mb.rs\x1b[0m");
+
+        // the colors come back after the highlight, in reverse video when selected
+        let colors = [sgr(0, "1;31")];
+        assert_eq!(
+            draw(&colors, &[Range::new(8, 10)], 80, true),
+            "\x1b[7m\x1b[1;31m\x1b[7msrc/lib.\x1b[93mrs\x1b[0m\x1b[7m\x1b[1;31m\x1b[0m",
+        );
+
+        // the ones left of the visible part still apply
+        let colors = [sgr(0, "34"), sgr(8, "0")];
+        assert_eq!(draw(&colors, &[Range::new(9, 10)], 6, false), "…\x1b[34mib.\x1b[0mr\x1b[36ms\x1b[0m\x1b[0m");
+    }
+
+    #[test]
+    fn tabs_to_tab_stops() {
+        let (text, ranges, colors) = expand_tabs("a\tbc\t\té\td", &[Range::new(1, 3), Range::new(6, 9)], &[], 4);
+        assert_eq!(text, "a   bc      é   d");
+        assert_eq!(str_width(&text), 17);
+        assert_eq!(ranges, [Range::new(1, 5), Range::new(12, 17)]);
+        assert!(colors.is_empty());
+
+        let colors = [Sgr { at: 0, params: "31".into() }, Sgr { at: 2, params: "0".into() }];
+        let (text, ranges, colors) = expand_tabs("\tx", &[Range::new(2, 9)], &colors, 8);
+        assert_eq!(text, "        x");
+        assert_eq!(ranges, [Range::new(9, 9)]);
+        assert_eq!(Vec::from_iter(colors.iter().map(|c| c.at)), [0, 9]);
+
+        let mut out = vec![];
+        Renderer::new(&mut out).candidate("a\tb", &[Range::new(2, 3)], 80, false, None, false).unwrap();
+        assert_eq!(out, b"\x1b[39ma       \x1b[36mb\x1b[0m");
+
+        // shrunk to the width with the tabs expanded
+        let mut out = vec![];
+        let mut renderer = Renderer::new(&mut out);
+        renderer.set_tabstop(2);
+        renderer.candidate("a\tbcd\te", &[], 6, false, None, false).unwrap();
+        assert_eq!(out, b"a bcd \x1b[0m");
+    }
+
+    #[test]
+    fn render_frame() {
+        let candidates = candidates(&["src/lib.rs", "src/ui.rs", "README.md"]);
+        let mut session = Session::new(&candidates, true, Tiebreak::Length, 2);
+        type_str(&mut session, "s/");
+        session.update_filter();
+
+        let mut renderer = Renderer::new(vec![]);
+        renderer.frame(&session, 2, 30, "> ", false, None).unwrap();
+        assert_eq!(renderer.into_inner(), concat!(
+            // the selected line, with the match in a color that shows on reverse video
+            "\x1b[1B\x1b[1G\x1b[2K\x1b[7m\x1b[93msrc/\x1b[39m\x1b[7mlib.rs\x1b[0m",
+            "\x1b[1B\x1b[1G\x1b[2K\x1b[36msrc/\x1b[39mui.rs\x1b[0m",
+            // back up to the prompt, with the info on the last columns
+            "\x1b[0m\x1b[2A\x1b[1G\x1b[2K> s/\x1b[16C1/2 (of 3)",
+            "\x1b[1G\x1b[4C",
+        ).as_bytes());
+
+        let mut renderer = Renderer::new(vec![]);
+        renderer.bracketed_paste(true).unwrap();
+        renderer.scroll_down(2).unwrap();
+        renderer.cursor_up(2).unwrap();
+        assert_eq!(renderer.get_ref(), b"\x1b[?2004h\n\n\x1b[2A");
+    }
+
+    // the text written to each row of `frame`, counting from the one it starts on, and
+    // the row it leaves the cursor on. only cursor movement up and down is followed
+    fn frame_rows(frame: &[u8]) -> (BTreeMap<isize, String>, isize) {
+        let (mut rows, mut row) = (BTreeMap::new(), 0);
+        let mut bytes = frame.iter().copied();
+        while let Some(byte) = bytes.next() {
+            if byte != 0x1b {
+                rows.entry(row).or_insert_with(String::new).push(byte as char);
+                continue;
+            }
+            let mut num = 0;
+            for byte in bytes.by_ref().skip(1) {
+                match byte {
+                    b'0'..=b'9' => num = num * 10 + (byte - b'0') as isize,
+                    b'A' => { row -= num; break; },
+                    b'B' => { row += num; break; },
+                    b';' => {},
+                    _ => break,
+                }
+            }
+        }
+        (rows, row)
+    }
+
+    #[test]
+    fn layout_prompt_placement() {
+        let candidates = candidates(&["src/lib.rs", "src/ui.rs", "README.md"]);
+        let rows = |layout| {
+            let mut session = Session::new(&candidates, true, Tiebreak::Length, 3);
+            session.layout = layout;
+            type_str(&mut session, "s/");
+            session.update_filter();
+            let mut frame = vec![];
+            Renderer::new(&mut frame).frame(&session, 3, 30, "> ", false, None).unwrap();
+            let (rows, end) = frame_rows(&frame);
+            (Vec::from_iter(rows.into_iter().map(|(row, text)| (row, text.trim().to_string()))), end)
+        };
+        let prompt = "> s/1/2 (of 3)".to_string();
+        let (lib, ui) = ("src/lib.rs".to_string(), "src/ui.rs".to_string());
+
+        // the cursor is left on the prompt either way, the third row stays blank
+        assert_eq!(rows(Layout::Default), (vec![(0, prompt.clone()), (1, lib.clone()), (2, ui.clone())], 0));
+        assert_eq!(rows(Layout::Reverse), (vec![(-2, ui.clone()), (-1, lib.clone()), (0, prompt.clone())], 0));
+        assert_eq!(rows(Layout::ReverseList), (vec![(-3, lib), (-2, ui), (0, prompt)], 0));
+    }
+
+    #[test]
+    fn frame_without_rows() {
+        let candidates = candidates(&["src/lib.rs", "src/ui.rs"]);
+        for layout in [Layout::Default, Layout::Reverse, Layout::ReverseList] {
+            let mut session = Session::new(&candidates, true, Tiebreak::Length, Height::Lines(10).rows(1));
+            session.layout = layout;
+            let mut frame = vec![];
+            Renderer::new(&mut frame).frame(&session, 0, 30, "> ", false, None).unwrap();
+            let (rows, end) = frame_rows(&frame);
+            assert_eq!(Vec::from_iter(rows.into_iter().map(|(row, text)| (row, text.trim().to_string()))), [(0, "> 1/2 (of 2)".to_string())]);
+            assert_eq!(end, 0);
+        }
+    }
+
+    #[test]
+    fn reverse_layout_keys() {
+        let candidates = candidates(&["src/lib.rs", "src/ui.rs", "README.md"]);
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 3);
+        session.layout = Layout::Reverse;
+        session.history.entries = vec!["lib".into()];
+
+        // up goes along the list away from the prompt, down back towards it and then
+        // into the history
+        drive(&mut session, &[Key::Up, Key::Up]);
+        assert_eq!(session.state.selected, 2);
+        drive(&mut session, &[Key::Down, Key::Down]);
+        assert_eq!((session.query.as_str(), session.state.selected), ("", 0));
+        drive(&mut session, &[Key::Down]);
+        assert_eq!(session.query, "lib");
+
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 3);
+        session.layout = Layout::ReverseList;
+        drive(&mut session, &[Key::Down]);
+        assert_eq!(session.state.selected, 1);
+    }
+
+    #[test]
+    fn draw_overlapping_ranges() {
+        let mut out = vec![];
+        let ranges = [Range::new(5, 9), Range::new(4, 7), Range::new(0, 2)];
+        Renderer::new(&mut out).candidate("src/lib.rs", &ranges, 80, false, None, false).unwrap();
+        assert_eq!(out, b"\x1b[36msr\x1b[39mc/\x1b[36mlib.r\x1b[39ms\x1b[0m");
+    }
+
+    #[test]
+    fn ranges_past_the_end() {
+        let split = |ranges: &[Range], stop| Vec::from_iter(IterRanges::new(ranges, stop).map(|(flag, r)| (flag, r.into())));
+
+        assert_eq!(split(&[Range::new(2, 4)], 6), [(false, 0..2), (true, 2..4), (false, 4..6)]);
+        // unsorted, empty, inverted, and reaching past or starting after the end
+        let ranges = [Range::new(4, 9), Range::new(3, 1), Range::new(2, 2), Range::new(0, 1), Range::new(6, 7)];
+        assert_eq!(split(&ranges, 6), [(true, 0..1), (false, 1..4), (true, 4..6)]);
+        assert_eq!(split(&[Range::new(8, 9), Range::new(0, usize::MAX)], 3), [(true, 0..3)]);
+        assert_eq!(split(&[Range::new(8, 9)], 3), [(false, 0..3)]);
+        assert!(split(&[Range::new(0, 1)], 0).is_empty());
+        let converted = Vec::from_iter([2..4, 0..1].map(Range::from));
+        assert_eq!(split(&converted, 5), [(true, 0..1), (false, 1..2), (true, 2..4), (false, 4..5)]);
+
+        // matches cut off by the window width
+        let mut out = vec![];
+        let ranges = [Range::new(8, 30), Range::new(4, 6)];
+        Renderer::new(&mut out).candidate("src/lib.rs/and/more", &ranges, 9, false, None, false).unwrap();
+        assert_eq!(out, "…\x1b[36mand/more\x1b[0m".as_bytes());
+
+        let mut out = vec![];
+        Renderer::new(&mut out).candidate("src/lib.rs", &[Range::new(20, 25)], 6, false, None, false).unwrap();
+        assert_eq!(out, "…\x1b[39mib.rs\x1b[0m".as_bytes());
+    }
+
+    #[test]
+    fn sgr_sequences() {
+        let sgr = |code| {
+            let mut out = vec![];
+            Renderer::new(&mut out).sgr(code).unwrap();
+            out
+        };
+
+        assert_eq!(sgr(Attributes::Reset), b"\x1b[0m");
+        assert_eq!(sgr(Attributes::FgCyan), b"\x1b[36m");
+        assert_eq!(sgr(Attributes::Fg256(208)), b"\x1b[38;5;208m");
+        assert_eq!(sgr(Attributes::FgRgb(255, 0, 128)), b"\x1b[38;2;255;0;128m");
+    }
+
+    // counts the writes that reach it, like the syscalls on a tty
+    #[derive(Default)]
+    struct CountingWriter {
+        writes: usize,
+        bytes: Vec<u8>,
+    }
+
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            self.bytes.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn frame_written_at_once() {
+        let candidates = candidates(&["src/lib.rs", "src/ui.rs", "README.md"]);
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 3);
+        drive(&mut session, &[Key::Character('s')]);
+
+        let mut terminal = Terminal::headless(&b""[..], CountingWriter::default(), Height::Lines(3), "> ".into(), false, false).unwrap();
+        terminal.determine_height().unwrap();
+        let (writes, drawn) = (terminal.writer().writes, terminal.writer().bytes.len());
+        terminal.draw(&session).unwrap();
+
+        // the same frame the renderer assembles, in a single write
+        let mut frame = vec![];
+        Renderer::new(&mut frame).frame(&session, 3, HEADLESS_SIZE.x, "> ", false, None).unwrap();
+        assert_eq!(terminal.writer().writes, writes + 1);
+        assert_eq!(terminal.writer().bytes[drawn..], frame);
+
+        // writing each escape and segment on its own would take many more
+        let mut direct = CountingWriter::default();
+        Renderer::new(&mut direct).frame(&session, 3, HEADLESS_SIZE.x, "> ", false, None).unwrap();
+        assert!(direct.writes > 30, "{} writes", direct.writes);
+    }
+
+    #[test]
+    fn csi_numbers() {
+        for num in [0, 7, 10, 123, usize::MAX] {
+            let mut out = vec![];
+            Renderer::new(&mut out).csi(num, 'A').unwrap();
+            assert_eq!(out, format!("\x1b[{}A", num).as_bytes());
+        }
+    }
+
+    #[test]
+    fn alt_screen_sequences() {
+        assert_eq!(BRACKETED_PASTE_ON, b"\x1b[?2004h");
+        assert_eq!(BRACKETED_PASTE_OFF, b"\x1b[?2004l");
+
+        let mut out = vec![];
+        Renderer::new(&mut out).mouse(true).unwrap();
+        Renderer::new(&mut out).mouse(false).unwrap();
+        assert_eq!(out, b"\x1b[?1000h\x1b[?1006h\x1b[?1006l\x1b[?1000l");
+
+        let mut out = vec![];
+        Renderer::new(&mut out).enter_screen(false).unwrap();
+        Renderer::new(&mut out).leave_screen(false).unwrap();
+        assert!(out.is_empty());
+
+        Renderer::new(&mut out).enter_screen(true).unwrap();
+        assert_eq!(out, b"\x1b[?1049h\x1b[H");
+        out.clear();
+        Renderer::new(&mut out).leave_screen(true).unwrap();
+        assert_eq!(out, b"\x1b[?1049l");
+    }
+
+    #[test]
+    fn rank_once_per_burst() {
+        let candidates = candidates(&["src/lib.rs", "src/ui.rs", "README.md"]);
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 10);
+
+        // a cursor position reply in the middle doesn't end the burst
+        let (mut terminal, mut master) = pty_terminal();
+        master.write_all(b"src/u\x1b[5;1Ri\x7f\x7fl").unwrap();
+        let keys = terminal.read_burst().unwrap().unwrap();
+        assert_eq!(keys.len(), 9);
+        assert_eq!(terminal.prompt_row, Some(5));
+        drop(terminal);
+        drop(master);
+
+        // so the whole burst is ranked once
+        assert!(session.handle_keys(keys).is_none());
+        assert!(session.update_filter());
+        assert!(!session.update_filter());
+        assert_eq!(session.query, "src/l");
+        assert_eq!(session.filtered.len(), 1);
+
+        // keys after an exit are dropped
+        let exit = session.handle_keys([Key::Enter, Key::Character('x')]);
+        assert!(matches!(exit, Some(Exit::Select(0))));
+        assert_eq!(session.query, "src/l");
+    }
+
+    #[test]
+    fn resize_window() {
+        let paths = Vec::from_iter((0..10).map(|i| format!("file{}", i)));
+        let candidates = candidates(&Vec::from_iter(paths.iter().map(String::as_str)));
+        let max_height = Height::Lines(5);
+
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, max_height.rows(40));
+        assert_eq!(session.height, 5);
+        drive(&mut session, &[Key::Down; 4]);
+        assert_eq!((session.state.selected, session.state.offset), (4, 0));
+
+        // the window shrinks below the drawn region
+        session.set_height(max_height.rows(3));
+        assert_eq!(session.height, 2);
+        assert_eq!((session.state.selected, session.state.offset), (4, 3));
+        drive(&mut session, &[Key::Down]);
+        assert_eq!((session.state.selected, session.state.offset), (5, 4));
+
+        // and grows again
+        session.set_height(max_height.rows(40));
+        assert_eq!(session.height, 5);
+        assert_eq!((session.state.selected, session.state.offset), (5, 4));
+        drive(&mut session, &[Key::Up; 2]);
+        assert_eq!((session.state.selected, session.state.offset), (3, 3));
+    }
+
+    #[test]
+    fn input_timeout() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (reader, mut writer) = unsafe { (std::fs::File::from_raw_fd(fds[0]), std::fs::File::from_raw_fd(fds[1])) };
+
+        // nothing to read
+        let start = std::time::Instant::now();
+        assert!(!wait_for_input(reader.as_raw_fd(), Duration::from_millis(20)).unwrap());
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        writer.write_all(b"a").unwrap();
+        assert!(wait_for_input(reader.as_raw_fd(), Duration::from_secs(5)).unwrap());
+    }
+
+    // a terminal drawing on a new pseudo terminal, and the other end of it to type into
+    fn pty_terminal() -> (Terminal, std::fs::File) {
+        let (mut master, mut slave) = (0, 0);
+        let out = unsafe { libc::openpty(&mut master, &mut slave, std::ptr::null_mut(), std::ptr::null(), std::ptr::null()) };
+        assert_eq!(out, 0);
+        let (master, slave) = unsafe { (std::fs::File::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+        let terminal = Terminal::with_fd(slave, Height::Lines(3), "> ".into(), false, false).unwrap();
+        (terminal, master)
+    }
+
+    // a writer whose bytes can be looked at while a terminal owns it
+    #[derive(Clone, Default)]
+    struct SharedWriter(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(not(feature = "winsize"))]
+    #[test]
+    fn window_size_without_ioctl() {
+        let (terminal, master) = pty_terminal();
+        let (columns, lines) = (std::env::var("COLUMNS").ok(), std::env::var("LINES").ok());
+        let size = fallback_size(None, |name| if name == "COLUMNS" { columns.clone() } else { lines.clone() });
+        assert_eq!(terminal.window_size(), size);
+        assert!(size.x > 0 && size.y > 0);
+        drop(terminal);
+        drop(master);
+    }
+
+    #[test]
+    fn window_size_fallback() {
+        let env = |columns: Option<&'static str>, lines: Option<&'static str>| move |name: &str| match name {
+            "COLUMNS" => columns.map(String::from),
+            "LINES" => lines.map(String::from),
+            _ => None,
+        };
+        let size = |x, y| WinSize { x, y };
+
+        // the ioctl fails on anything but a tty
+        let (reader, _writer) = std::os::unix::net::UnixStream::pair().unwrap();
+        assert_eq!(tty_size(reader.as_raw_fd()), None);
+        assert_eq!(tty_size(-1), None);
+
+        assert_eq!(fallback_size(Some(size(120, 40)), env(Some("100"), Some("30"))), size(120, 40));
+        assert_eq!(fallback_size(None, env(Some("100"), Some("30"))), size(100, 30));
+        assert_eq!(fallback_size(None, env(Some("100"), None)), size(100, 24));
+        assert_eq!(fallback_size(None, env(Some("wide"), Some("0"))), size(80, 24));
+        assert_eq!(fallback_size(None, env(None, None)), size(80, 24));
+
+        // a pseudo-terminal that was never given a size
+        let (terminal, master) = pty_terminal();
+        assert_eq!(tty_size(terminal.rawfd().unwrap()), None);
+        assert!(terminal.window_size().x > 0);
+        drop(terminal);
+        drop(master);
+    }
+
+    #[test]
+    fn terminal_on_a_file() {
+        // opening worked, so this isn't reported as a missing terminal
+        let file = OwnedFd::from(std::fs::File::open("/dev/null").unwrap());
+        let error = Terminal::with_fd(file, Height::Lines(3), "> ".into(), false, false).err().unwrap();
+        assert_ne!(error.kind(), io::ErrorKind::NotConnected);
+    }
+
+    #[test]
+    fn headless_terminal() {
+        let candidates = candidates(&["src/ui.rs", "src/lib.rs", "README.md"]);
+        let output = SharedWriter::default();
+        let terminal = |keys: &'static [u8]| Terminal::headless(
+            Box::new(keys), Box::new(output.clone()), Height::Lines(3), "> ".into(), false, false,
+        ).unwrap();
+
+        let selection = terminal(b"lib\r").run(&candidates, false, Tiebreak::Length, "").unwrap().unwrap();
+        assert_eq!(selection.query, "lib");
+        assert_eq!(Vec::from_iter(selection.candidates.iter().map(|c| c.path.as_str())), ["src/lib.rs"]);
+        let drawn = String::from_utf8(output.0.take()).unwrap();
+        assert!(drawn.contains("> lib"));
+        assert!(drawn.contains("1/1 (of 3)"));
+
+        // the lines of a query are put on the one line being edited
+        let selection = terminal(b"\r").run(&candidates, false, Tiebreak::Length, "src\nlib\n").unwrap().unwrap();
+        assert_eq!(selection.query, "src lib ");
+        assert_eq!(Vec::from_iter(selection.candidates.iter().map(|c| c.path.as_str())), ["src/lib.rs"]);
+
+        // running out of keys aborts
+        assert_eq!(terminal(b"\x1b[Bui").run(&candidates, false, Tiebreak::Length, "").unwrap(), None);
+        assert_eq!(terminal(b"").run(&candidates, false, Tiebreak::Length, "src").unwrap(), None);
+    }
+
+    #[test]
+    fn injected_search() {
+        let candidates = candidates(&["a/one", "a/two", "b/three"]);
+        let input = io::Cursor::new(b"a/\x1b[B\r".to_vec());
+        let mut terminal = Terminal::headless(input, Vec::new(), Height::Lines(3), "> ".into(), false, false).unwrap();
+
+        let selection = terminal.run(&candidates, true, Tiebreak::Length, "").unwrap().unwrap();
+        assert_eq!(selection.query, "a/");
+        assert_eq!(Vec::from_iter(selection.candidates.iter().map(|c| c.path.as_str())), ["a/two"]);
+
+        let drawn = String::from_utf8(terminal.writer().clone()).unwrap();
+        assert!(drawn.starts_with("\x1b[?2004h"));
+        assert!(drawn.contains("> a/"));
+        assert!(drawn.contains("2/2 (of 3)"));
+        // the last frame, drawn after the first key's, highlights the second match
+        let last = &drawn[drawn.rfind("1/2 (of 3)").unwrap()..];
+        assert!(last.contains("\x1b[7m\x1b[93ma/\x1b[39m\x1b[7mtwo"));
+        assert!(last.ends_with("> a/\x1b[66C2/2 (of 3)\x1b[1G\x1b[4C"));
+        assert!(!last.contains("b/three"));
+    }
+
+    #[test]
+    fn lone_escape() {
+        let (mut terminal, mut master) = pty_terminal();
+        terminal.set_timeout(Some(Duration::from_millis(50)));
+
+        master.write_all(b"\x1b").unwrap();
+        assert_eq!(terminal.read_key(), Some(Key::Esc));
+        assert_eq!(terminal.read_key(), None);
+        assert!(!terminal.is_nodelay());
+
+        // sequences and alt keys are still read whole
+        master.write_all(b"\x1b[1;5Cx\x1bb").unwrap();
+        assert_eq!(terminal.read_key(), Some(Key::Right));
+        assert_eq!(terminal.read_key(), Some(Key::Character('x')));
+        assert_eq!(terminal.read_key(), Some(Key::Alt(b'b')));
+        assert_eq!(terminal.read_key(), None);
+
+        // restores the tty while the other end is still open
+        drop(terminal);
+    }
+
+    #[test]
+    fn bracketed_paste() {
+        let mut bytes = &b"[200~src/\n\x1b[A\tlib\x1b[201~x"[..];
+        assert_eq!(read_escape(&mut bytes), Escape::Paste);
+        assert_eq!(read_paste(&mut bytes), "src/[Alib");
+        assert_eq!(bytes, b"x");
+        // a paste that never ends takes what there is
+        assert_eq!(read_paste(&b"a\x1b[20"[..]), "a[20");
+
+        let (mut terminal, mut master) = pty_terminal();
+        terminal.set_timeout(Some(Duration::from_millis(50)));
+        master.write_all("l\x1b[200~ib\r\nér\x01\x1b[201~\x7f".as_bytes()).unwrap();
+        let keys = Vec::from_iter(std::iter::from_fn(|| terminal.read_key()));
+        drop(terminal);
+
+        let candidates = candidates(&["src/lib.rs"]);
+        let mut session = Session::new(&candidates, false, Tiebreak::Length, 10);
+        assert_eq!(session.handle_keys(keys), None);
+        assert_eq!(session.query, "libé");
+    }
+
+    #[test]
+    fn stream_candidates() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut session = Session::new(&[], false, Tiebreak::Length, 10);
+        assert!(session.filtered.is_empty());
+        assert!(drive(&mut session, &[Key::Enter]).is_none());
+
+        type_str(&mut session, "ui");
+        assert!(session.receive(&receiver).unwrap());
+        assert!(session.filtered.is_empty());
+
+        for candidate in candidates(&["src/ui.rs", "README.md", "ui.rs"]) {
+            sender.send(Ok(candidate)).unwrap();
+        }
+        assert!(session.receive(&receiver).unwrap());
+        assert_eq!(session.candidates.len(), 3);
+        let order = Vec::from_iter(session.filtered.iter().map(|s| session.candidates[s.index].path.as_str()));
+        assert_eq!(order, ["ui.rs", "src/ui.rs"]);
+
+        // the end of the input
+        sender.send(Ok(candidates(&["lib/ui.zig"]).remove(0))).unwrap();
+        drop(sender);
+        assert!(!session.receive(&receiver).unwrap());
+        assert_eq!(session.filtered.len(), 3);
+
+        let exit = drive(&mut session, &[Key::Enter]).unwrap();
+        assert_eq!(session.finish(exit).unwrap().candidates[0].path, "ui.rs");
+
+        // read errors end the search
+        let (sender, receiver) = std::sync::mpsc::channel();
+        sender.send(Err(other_error("bad input"))).unwrap();
+        assert!(session.receive(&receiver).is_err());
+    }
+
+    #[test]
+    fn spinner_while_streaming() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        sender.send(Ok(candidates(&["src/lib.rs"]).remove(0))).unwrap();
+        let output = SharedWriter::default();
+        let mut terminal = Terminal::headless(&b"lib"[..], output.clone(), Height::Lines(3), "> ".into(), false, false).unwrap();
+
+        // each key is another look at the input, which is still open
+        assert_eq!(terminal.run_streaming(receiver, false, Tiebreak::Length, "").unwrap(), None);
+        let drawn = String::from_utf8(output.0.take()).unwrap();
+        for glyph in &SPINNER[1..5] {
+            assert!(drawn.contains(&format!("{} 1/1 (of 1)", glyph)), "{:?}", drawn);
+        }
+        assert!(!drawn.contains(SPINNER[5]));
+        drop(sender);
+
+        // none once the input ended, or when it was read up front
+        let (sender, receiver) = std::sync::mpsc::channel();
+        sender.send(Ok(candidates(&["src/lib.rs"]).remove(0))).unwrap();
+        drop(sender);
+        let mut terminal = Terminal::headless(&b"lib"[..], output.clone(), Height::Lines(3), "> ".into(), false, false).unwrap();
+        terminal.run_streaming(receiver, false, Tiebreak::Length, "").unwrap();
+        let candidates = candidates(&["src/lib.rs"]);
+        let mut terminal = Terminal::headless(&b"lib"[..], output.clone(), Height::Lines(3), "> ".into(), false, false).unwrap();
+        terminal.run(&candidates, false, Tiebreak::Length, "").unwrap();
+        let drawn = String::from_utf8(output.0.take()).unwrap();
+        assert!(drawn.contains("1/1 (of 1)"));
+        assert!(!drawn.contains(SPINNER));
+    }
+
+    #[test]
+    fn preview_command() {
+        assert_eq!(run_preview("echo {}", "src/lib.rs", 10), ["src/lib.rs"]);
+        assert_eq!(run_preview("printf '%s\\n' {} {}", "it's a \"file\"", 10), ["it's a \"file\"", "it's a \"file\""]);
+        assert_eq!(run_preview("printf 'a\\tb\\033[1m\\n'", "x", 10), ["a    b[1m"]);
+
+        // endless output is cut off, and failures don't show anything
+        assert_eq!(run_preview("yes {}", "y", 3), ["y", "y", "y"]);
+        assert!(run_preview("exit 1", "x", 3).is_empty());
+
+        let candidates = candidates(&["src/lib.rs", "src/ui.rs"]);
+        let session = Session::new(&candidates, false, Tiebreak::Length, 2);
+        let mut frame = vec![];
+        let preview = ["fn main() {}".to_string()];
+        Renderer::new(&mut frame).frame(&session, 2, 20, "> ", false, Some(&preview)).unwrap();
+        let frame = String::from_utf8(frame).unwrap();
+        assert!(frame.contains("src/lib.rs\x1b[0m\x1b[11G│ fn main(\x1b"), "{:?}", frame);
+        assert!(frame.contains("src/ui.rs\x1b[0m\x1b[11G│ \x1b"), "{:?}", frame);
+    }
 }
@@ -0,0 +1,85 @@
+use std::io::Write;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Output, Stdio};
+
+// run the zf binary with `args`, feeding it `input`
+fn zf(args: &[&str], input: &[u8]) -> Output {
+    run(Command::new(env!("CARGO_BIN_EXE_zf")).args(args), input)
+}
+
+// same as `zf`, in a new session without a controlling terminal
+fn zf_without_tty(args: &[&str], input: &[u8]) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_zf"));
+    // SAFETY: setsid is async-signal-safe
+    unsafe { command.pre_exec(|| { libc::setsid(); Ok(()) }); }
+    run(command.args(args), input)
+}
+
+fn run(command: &mut Command, input: &[u8]) -> Output {
+    let mut child = command
+        .env_remove("ZF_DEFAULT_OPTS")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn no_matches_exit_code() {
+    let input = b"src/main.rs\nsrc/lib.rs\n";
+
+    let out = zf(&["-f", "lib"], input);
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(out.stdout, b"src/lib.rs\n");
+
+    // nothing matched is still a success by default
+    let out = zf(&["-f", "xyz"], input);
+    assert_eq!(out.status.code(), Some(0));
+    assert!(out.stdout.is_empty());
+
+    let out = zf(&["--no-matches-error", "-f", "xyz"], input);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(out.stdout.is_empty());
+
+    let out = zf(&["--no-matches-error", "--print-query", "-f", "xyz"], input);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(out.stdout, b"xyz\n");
+
+    let out = zf(&["--no-matches-error", "-f", "lib"], input);
+    assert_eq!(out.status.code(), Some(0));
+}
+
+#[test]
+fn without_tty() {
+    // the candidates already took stdin, so there is nowhere to read keys from
+    for args in [&[][..], &["--stream"][..]] {
+        let out = zf_without_tty(args, b"a\nb\n");
+        assert_eq!(out.status.code(), Some(1));
+        assert!(out.stdout.is_empty());
+        assert!(String::from_utf8_lossy(&out.stderr).starts_with("no controlling terminal; use --filter\n"));
+    }
+
+    // with --input the keys come from stdin and drawing goes to stderr
+    let path = std::env::temp_dir().join(format!("zf-without-tty-{}", std::process::id()));
+    std::fs::write(&path, "a\nb\n").unwrap();
+    let out = zf_without_tty(&["--input", path.to_str().unwrap()], b"b\r");
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(out.stdout, b"b\n");
+}
+
+#[test]
+fn builds_on_stable() {
+    // the crate is meant to build on a stable toolchain, so no nightly feature gates
+    for (name, source) in [
+        ("src/lib.rs", include_str!("../src/lib.rs")),
+        ("src/main.rs", include_str!("../src/main.rs")),
+        ("src/filter.rs", include_str!("../src/filter.rs")),
+        ("src/ui.rs", include_str!("../src/ui.rs")),
+    ] {
+        assert!(!source.contains("#![feature("), "{name} uses a feature gate");
+    }
+}